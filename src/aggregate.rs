@@ -0,0 +1,556 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::{collections::BTreeMap, marker::PhantomData, ops::Add, sync::Arc};
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    hook::{Hook, Write},
+    progress::{Claimed, Deferral, Progress, CHUNK},
+    transaction::atomically,
+};
+
+/// Aggregates over the values in a range of a structured tree
+///
+/// Counting only reads keys, every other aggregate decodes each value once.
+pub struct Aggregate<V, E>(sled::Iter, Arc<str>, PhantomData<V>, PhantomData<E>);
+
+/// Aggregates over the values in a range of a structured tree, grouped by key prefix
+///
+/// Keys shorter than the prefix length form a group of their own.
+pub struct GroupBy<V, E> {
+    iter: sled::Iter,
+    tree: Arc<str>,
+    prefix_len: usize,
+    value: PhantomData<V>,
+    encoding: PhantomData<E>,
+}
+
+/// A count of the values in a structured tree for each key prefix of a fixed length
+///
+/// Counts are stored in a companion tree, keyed by prefix, and updated in the same transaction as
+/// every insert and removal. The prefix length and state of each counter is kept in a metadata
+/// tree shared by every counter on the same tree.
+///
+/// A counter that is being recounted only applies writes to keys it has already counted. The
+/// recount claims a chunk of keys, counts them outside of a transaction, and then adds the
+/// counts in a transaction that fails if a write to the claimed chunk was seen in the meantime,
+/// in which case the chunk is counted again.
+pub(crate) struct Counter {
+    prefix_len: usize,
+    progress: Progress,
+    trees: [sled::Tree; 2],
+}
+
+impl<V, E> Aggregate<V, E>
+where
+    E: Encoding<V> + 'static,
+{
+    pub(crate) fn new(iter: sled::Iter, tree: &Arc<str>) -> Self {
+        Aggregate(iter, tree.clone(), PhantomData, PhantomData)
+    }
+
+    /// Group the values by the first `prefix_len` bytes of their keys
+    pub fn group_by(self, prefix_len: usize) -> GroupBy<V, E> {
+        GroupBy {
+            iter: self.0,
+            tree: self.1,
+            prefix_len,
+            value: PhantomData,
+            encoding: PhantomData,
+        }
+    }
+
+    /// Count the values, without decoding them
+    pub fn count(self) -> Result<usize> {
+        self.0.keys().try_fold(0, |count, res| {
+            res?;
+            Ok(count + 1)
+        })
+    }
+
+    /// Add up a field extracted from each value
+    pub fn sum<N, F>(self, f: F) -> Result<N>
+    where
+        N: Add<Output = N> + Default,
+        F: Fn(&V) -> N,
+    {
+        self.fold(N::default(), |sum, _, value| sum + (f)(&value))
+    }
+
+    /// Find the smallest field extracted from a value
+    pub fn min<N, F>(self, f: F) -> Result<Option<N>>
+    where
+        N: PartialOrd,
+        F: Fn(&V) -> N,
+    {
+        self.fold(None, |min, _, value| smallest(min, (f)(&value)))
+    }
+
+    /// Find the largest field extracted from a value
+    pub fn max<N, F>(self, f: F) -> Result<Option<N>>
+    where
+        N: PartialOrd,
+        F: Fn(&V) -> N,
+    {
+        self.fold(None, |max, _, value| largest(max, (f)(&value)))
+    }
+
+    /// Combine every key and value into a single result
+    pub fn fold<A, F>(mut self, init: A, mut f: F) -> Result<A>
+    where
+        F: FnMut(A, IVec, V) -> A,
+    {
+        let tree = self.1;
+
+        self.0.try_fold(init, |acc, res| {
+            let (key, v) = res?;
+            let value = E::decode_at(&tree, &key, &v)?;
+            Ok((f)(acc, key, value))
+        })
+    }
+}
+
+impl<V, E> GroupBy<V, E>
+where
+    E: Encoding<V> + 'static,
+{
+    /// Count the values in each group, without decoding them
+    pub fn count(self) -> Result<BTreeMap<IVec, usize>> {
+        let mut groups = BTreeMap::new();
+
+        for key in self.iter.keys() {
+            let key = key?;
+            *groups.entry(group(&key, self.prefix_len)).or_insert(0) += 1;
+        }
+
+        Ok(groups)
+    }
+
+    /// Add up a field extracted from each value in each group
+    pub fn sum<N, F>(self, f: F) -> Result<BTreeMap<IVec, N>>
+    where
+        N: Add<Output = N> + Default,
+        F: Fn(&V) -> N,
+    {
+        self.fold(N::default, |sum, _, value| sum + (f)(&value))
+    }
+
+    /// Find the smallest field extracted from a value in each group
+    pub fn min<N, F>(self, f: F) -> Result<BTreeMap<IVec, N>>
+    where
+        N: PartialOrd,
+        F: Fn(&V) -> N,
+    {
+        let groups = self.fold(|| None, |min, _, value| smallest(min, (f)(&value)))?;
+        Ok(flatten(groups))
+    }
+
+    /// Find the largest field extracted from a value in each group
+    pub fn max<N, F>(self, f: F) -> Result<BTreeMap<IVec, N>>
+    where
+        N: PartialOrd,
+        F: Fn(&V) -> N,
+    {
+        let groups = self.fold(|| None, |max, _, value| largest(max, (f)(&value)))?;
+        Ok(flatten(groups))
+    }
+
+    /// Combine the keys and values in each group into a result per group
+    pub fn fold<A, I, F>(self, init: I, mut f: F) -> Result<BTreeMap<IVec, A>>
+    where
+        I: Fn() -> A,
+        F: FnMut(A, IVec, V) -> A,
+    {
+        let mut groups = BTreeMap::new();
+
+        for res in self.iter {
+            let (key, v) = res?;
+            let value = E::decode_at(&self.tree, &key, &v)?;
+
+            let group = group(&key, self.prefix_len);
+            let acc = groups.remove(&group).unwrap_or_else(&init);
+
+            groups.insert(group, (f)(acc, key, value));
+        }
+
+        Ok(groups)
+    }
+}
+
+impl Counter {
+    /// Open a counter over the data tree
+    ///
+    /// Counters are recounted from the data tree the first time they are registered, and whenever
+    /// they are registered with a different prefix length. An interrupted recount resumes where
+    /// it left off the next time the counter is registered.
+    pub(crate) fn new(
+        db: &sled::Db,
+        data: &sled::Tree,
+        tree: &str,
+        name: &str,
+        prefix_len: usize,
+    ) -> Result<Self> {
+        let counter = Counter {
+            prefix_len,
+            progress: Progress::new("counter", name, &(prefix_len as u64).to_be_bytes()),
+            trees: [
+                db.open_tree(format!("{}-counter-{}", tree, name))?,
+                db.open_tree(format!("{}-counter-meta", tree))?,
+            ],
+        };
+
+        if counter.progress.state(&counter.trees[1])?.is_none() {
+            // Writes stop updating the counts before they are cleared
+            counter.progress.restart(&counter.trees)?;
+            counter.trees[0].clear()?;
+        }
+
+        counter.recount(data)?;
+        Ok(counter)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.progress.name()
+    }
+
+    /// The number of values whose keys start with the prefix
+    ///
+    /// Returns `None` when the prefix is longer than the prefix length of this counter, since
+    /// those values are counted together with their siblings.
+    pub(crate) fn count(&self, prefix: &[u8]) -> Result<Option<usize>> {
+        if prefix.len() > self.prefix_len {
+            return Ok(None);
+        }
+
+        if prefix.len() == self.prefix_len {
+            return match self.trees[0].get(prefix)? {
+                Some(v) => Ok(Some(decode_count(&v)? as usize)),
+                None => Ok(Some(0)),
+            };
+        }
+
+        self.trees[0]
+            .scan_prefix(prefix)
+            .values()
+            .try_fold(0, |count, res| Ok(count + decode_count(&res?)? as usize))
+            .map(Some)
+    }
+
+    /// Count the values that were in the data tree before the counter was registered
+    fn recount(&self, data: &sled::Tree) -> Result<()> {
+        while let Some(claimed) = self.progress.claim(&self.trees, data, CHUNK)? {
+            self.count_claimed(data, &claimed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Count the keys of a claimed chunk, unless a write marked the claim dirty meanwhile
+    fn count_claimed(&self, data: &sled::Tree, claimed: &Claimed) -> Result<()> {
+        let mut counts = BTreeMap::new();
+
+        for key in data
+            .range::<IVec, _>((claimed.after.clone(), claimed.upper.clone()))
+            .keys()
+        {
+            *counts.entry(group(&key?, self.prefix_len)).or_insert(0u64) += 1;
+        }
+
+        atomically(&self.trees, |views| {
+            if !self.progress.commit(&views[1], claimed)? {
+                return Ok(Ok(()));
+            }
+
+            for (group, added) in &counts {
+                let count = match views[0].get(group)? {
+                    Some(v) => match decode_count(&v) {
+                        Ok(count) => count,
+                        Err(e) => return Ok(Err(e)),
+                    },
+                    None => 0,
+                };
+
+                views[0].insert(group, &(count + added).to_be_bytes()[..])?;
+            }
+
+            Ok(Ok(()))
+        })
+    }
+}
+
+impl<V> Hook<V> for Counter {
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        let added = match (write.old.is_some(), write.new.is_some()) {
+            (false, true) => true,
+            (true, false) => false,
+            _ => return Ok(Ok(())),
+        };
+
+        let state = match self.progress.state_in(&trees[1])? {
+            Ok(Some(state)) => state,
+            Ok(None) => return Ok(Ok(())),
+            Err(e) => return Ok(Err(e)),
+        };
+
+        match state.defers(write.key) {
+            Deferral::Apply => (),
+            Deferral::Defer => return Ok(Ok(())),
+            Deferral::Dirty(state) => {
+                self.progress.store(&trees[1], &state)?;
+                return Ok(Ok(()));
+            }
+        }
+
+        let group = group(write.key, self.prefix_len);
+
+        let count = match trees[0].get(&group)? {
+            Some(v) => match decode_count(&v) {
+                Ok(count) => count,
+                Err(e) => return Ok(Err(e)),
+            },
+            None => 0,
+        };
+
+        match (added, count) {
+            (true, count) => {
+                trees[0].insert(group, &(count + 1).to_be_bytes()[..])?;
+            }
+            (false, 0) | (false, 1) => {
+                trees[0].remove(group)?;
+            }
+            (false, count) => {
+                trees[0].insert(group, &(count - 1).to_be_bytes()[..])?;
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+/// The group a key belongs to
+fn group(key: &[u8], prefix_len: usize) -> IVec {
+    IVec::from(&key[..prefix_len.min(key.len())])
+}
+
+fn smallest<N: PartialOrd>(current: Option<N>, candidate: N) -> Option<N> {
+    match current {
+        Some(current) if current <= candidate => Some(current),
+        _ => Some(candidate),
+    }
+}
+
+fn largest<N: PartialOrd>(current: Option<N>, candidate: N) -> Option<N> {
+    match current {
+        Some(current) if current >= candidate => Some(current),
+        _ => Some(candidate),
+    }
+}
+
+fn flatten<N>(groups: BTreeMap<IVec, Option<N>>) -> BTreeMap<IVec, N> {
+    groups
+        .into_iter()
+        .filter_map(|(group, n)| n.map(|n| (group, n)))
+        .collect()
+}
+
+fn decode_count(v: &[u8]) -> Result<u64> {
+    let mut count = [0; 8];
+
+    if v.len() != count.len() {
+        return Err(Error::Corrupted("count of values".to_owned()));
+    }
+
+    count.copy_from_slice(v);
+    Ok(u64::from_be_bytes(count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoding::PlainEncoding,
+        progress::{Claim, State},
+        structured_tree::StructuredTree,
+        Config,
+    };
+    use std::ops::Bound;
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn fill(tree: &Tree, groups: &[u8], per_group: u16) {
+        for group in groups {
+            for i in 0..per_group {
+                let mut key = vec![*group];
+                key.extend_from_slice(&i.to_be_bytes());
+                tree.insert(key, IVec::from(&[*group][..])).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn aggregates_decode_each_value() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "numbers").unwrap();
+        fill(&tree, &[1, 2, 3], 2);
+
+        let value = |v: &IVec| u64::from(v[0]);
+
+        assert_eq!(tree.aggregate::<&[u8], _>(..).count().unwrap(), 6);
+        assert_eq!(tree.aggregate::<&[u8], _>(..).sum(value).unwrap(), 12);
+        assert_eq!(tree.aggregate_prefix([2]).max(value).unwrap(), Some(2));
+
+        let sums = tree
+            .aggregate::<&[u8], _>(..)
+            .group_by(1)
+            .sum(value)
+            .unwrap();
+        assert_eq!(sums.get(&[3][..]), Some(&6));
+        assert_eq!(
+            tree.aggregate(&[2][..]..)
+                .group_by(1)
+                .count()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn counters_recount_existing_values_in_chunks() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "numbers").unwrap();
+        fill(&tree, &[1, 2, 3], 1000);
+
+        let tree = tree.with_counter("groups", 1).unwrap();
+        assert_eq!(tree.count_by_counter("groups", [2]).unwrap(), 1000);
+        assert_eq!(tree.count_by_counter("groups", []).unwrap(), 3000);
+
+        tree.remove(&[1, 0, 0][..]).unwrap();
+        tree.insert(&[4][..], IVec::from(&[4][..])).unwrap();
+        assert_eq!(tree.count_by_counter("groups", [1]).unwrap(), 999);
+        assert_eq!(tree.count_by_counter("groups", [4]).unwrap(), 1);
+
+        // Registering with another prefix length counts everything again
+        let tree = Tree::new(&db, "numbers")
+            .unwrap()
+            .with_counter("groups", 0)
+            .unwrap();
+        assert_eq!(tree.count_by_counter("groups", []).unwrap(), 3000);
+    }
+
+    #[test]
+    fn writes_during_a_recount_are_counted_once() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "numbers")
+            .unwrap()
+            .with_counter("groups", 1)
+            .unwrap();
+        fill(&tree, &[1, 2, 3], 3);
+
+        // Another handle is part way through recounting, having counted group 1 and claimed
+        // group 2
+        let meta = db.open_tree("numbers-counter-meta").unwrap();
+        let counter = Counter::new(&db, tree.sled_tree(), "numbers", "groups", 1).unwrap();
+        let counts = db.open_tree("numbers-counter-groups").unwrap();
+        counts.remove([2]).unwrap();
+        counts.remove([3]).unwrap();
+
+        let building = |dirty| State::Building {
+            counted: Some(IVec::from(&[1, 0, 2][..])),
+            claim: Claim::UpTo(IVec::from(&[2, 0, 2][..])),
+            dirty,
+        };
+        meta.insert("groups", counter.progress.encode(&building(false)))
+            .unwrap();
+
+        // Keys that were already counted are applied straight away, later ones are left to the
+        // recount
+        tree.insert(&[1, 0, 0, 5][..], IVec::from(&[1][..]))
+            .unwrap();
+        tree.insert(&[2, 9][..], IVec::from(&[2][..])).unwrap();
+        assert_eq!(tree.count_by_counter("groups", [1]).unwrap(), 4);
+        assert_eq!(tree.count_by_counter("groups", [2]).unwrap(), 0);
+        assert_eq!(
+            counter
+                .progress
+                .decode(meta.get("groups").unwrap().as_deref())
+                .unwrap(),
+            Some(building(false))
+        );
+
+        tree.remove(&[2, 0, 1][..]).unwrap();
+        let state = meta.get("groups").unwrap();
+        assert_eq!(
+            counter.progress.decode(state.as_deref()).unwrap(),
+            Some(building(true))
+        );
+
+        tree.insert(&[3, 9][..], IVec::from(&[3][..])).unwrap();
+        assert_eq!(
+            meta.get("groups").unwrap(),
+            Some(IVec::from(counter.progress.encode(&building(true))))
+        );
+
+        counter.recount(tree.sled_tree()).unwrap();
+        assert_eq!(tree.count_by_counter("groups", [1]).unwrap(), 4);
+        assert_eq!(tree.count_by_counter("groups", [2]).unwrap(), 3);
+        assert_eq!(tree.count_by_counter("groups", [3]).unwrap(), 4);
+    }
+
+    #[test]
+    fn writes_between_a_claim_and_its_scan_restart_the_chunk() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "numbers")
+            .unwrap()
+            .with_counter("groups", 1)
+            .unwrap();
+        fill(&tree, &[1, 2], 2);
+
+        // Another handle registered the counter and has not started recounting
+        let meta = db.open_tree("numbers-counter-meta").unwrap();
+        let counter = Counter::new(&db, tree.sled_tree(), "numbers", "groups", 1).unwrap();
+        db.open_tree("numbers-counter-groups")
+            .unwrap()
+            .clear()
+            .unwrap();
+        meta.insert("groups", counter.progress.encode(&State::restart()))
+            .unwrap();
+
+        let claimed = counter
+            .progress
+            .claim(&counter.trees, tree.sled_tree(), CHUNK)
+            .unwrap()
+            .unwrap();
+        assert_eq!(claimed.upper, Bound::Unbounded);
+
+        // The write sees the claim, so it marks it dirty instead of being lost
+        tree.insert(&[2, 9][..], IVec::from(&[2][..])).unwrap();
+        assert_eq!(
+            counter
+                .progress
+                .decode(meta.get("groups").unwrap().as_deref())
+                .unwrap(),
+            Some(State::Building {
+                counted: None,
+                claim: Claim::Rest,
+                dirty: true,
+            })
+        );
+
+        // The chunk is not committed, and is counted again with the new key
+        counter.count_claimed(tree.sled_tree(), &claimed).unwrap();
+        assert_eq!(tree.count_by_counter("groups", [2]).unwrap(), 0);
+
+        counter.recount(tree.sled_tree()).unwrap();
+        assert_eq!(tree.count_by_counter("groups", [1]).unwrap(), 2);
+        assert_eq!(tree.count_by_counter("groups", [2]).unwrap(), 3);
+    }
+}
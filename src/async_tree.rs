@@ -0,0 +1,870 @@
+use chrono::{DateTime, Utc};
+use futures_core::Stream;
+use sled::IVec;
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    ops::{Bound, RangeBounds},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    expiring_tree::{ExpiringBatch, ExpiringTree},
+    structured_tree::{
+        CompareAndSwapError, StructuredBatch, StructuredEvent, StructuredSubscriber, StructuredTree,
+    },
+};
+
+const CHUNK_SIZE: usize = 64;
+
+type Chunk<V> = JoinHandle<Result<Vec<(IVec, V)>>>;
+type NextEvent<V, E> = JoinHandle<(
+    StructuredSubscriber<V, E>,
+    Option<Result<StructuredEvent<V>>>,
+)>;
+
+#[derive(Clone)]
+/// An async wrapper around a structured tree
+///
+/// Every operation is offloaded to tokio's blocking thread pool, so none of the calls here will
+/// block the executor.
+pub struct AsyncStructuredTree<V, E>(StructuredTree<V, E>);
+
+#[derive(Clone)]
+/// An async wrapper around an expiring tree
+///
+/// Every operation is offloaded to tokio's blocking thread pool, so none of the calls here will
+/// block the executor.
+pub struct AsyncExpiringTree<V, E, F>(ExpiringTree<V, E, F>);
+
+/// A stream over keys and values in a `Tree`.
+///
+/// Records are fetched from the blocking thread pool in small chunks.
+pub struct AsyncIter<T, V>
+where
+    T: Scan<V>,
+{
+    tree: T,
+    lower: Bound<IVec>,
+    upper: Bound<IVec>,
+    prefix: Option<IVec>,
+    buffer: VecDeque<(IVec, V)>,
+    pending: Option<Chunk<V>>,
+    done: bool,
+}
+
+/// A stream of decoded `Event`s for keys that have the specified prefix
+///
+/// Waiting for the next event occupies a thread in tokio's blocking thread pool. sled's
+/// subscribers can't be woken without an event, so a wait that is in progress when the stream is
+/// dropped keeps its thread until the next event for the prefix arrives or the tree is dropped.
+/// Runtimes that may have a wait in progress should be shut down with `shutdown_timeout` or
+/// `shutdown_background`, since dropping them waits for every blocking thread to finish.
+pub struct AsyncSubscriber<V, E> {
+    subscriber: Option<StructuredSubscriber<V, E>>,
+    pending: Option<NextEvent<V, E>>,
+}
+
+/// Trees that can be scanned in chunks by an `AsyncIter`
+pub trait Scan<V>: Send + 'static {
+    /// Clone the tree, regardless of whether V is Clone
+    fn cloned(&self) -> Self;
+
+    /// Fetch up to `limit` records that fall within the provided bounds
+    fn scan(&self, lower: Bound<IVec>, upper: Bound<IVec>, limit: usize) -> Result<Vec<(IVec, V)>>;
+}
+
+async fn blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(Error::custom(e)),
+    }
+}
+
+fn to_ivec_bound<K>(bound: Bound<&K>) -> Bound<IVec>
+where
+    K: AsRef<[u8]>,
+{
+    match bound {
+        Bound::Included(k) => Bound::Included(IVec::from(k.as_ref())),
+        Bound::Excluded(k) => Bound::Excluded(IVec::from(k.as_ref())),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+impl<V, E> AsyncStructuredTree<V, E>
+where
+    E: Encoding<V> + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    /// Wrap a structured tree for use from async code
+    pub fn new(tree: StructuredTree<V, E>) -> Self {
+        AsyncStructuredTree(tree)
+    }
+
+    /// Borrow the blocking tree this wraps
+    pub fn inner(&self) -> &StructuredTree<V, E> {
+        &self.0
+    }
+
+    /// Unwrap the blocking tree
+    pub fn into_inner(self) -> StructuredTree<V, E> {
+        self.0
+    }
+
+    /// Create a new batched update that can be atomically applied.
+    pub async fn apply_batch(&self, batch: StructuredBatch<V, E>) -> Result<()> {
+        let tree = self.0.cloned();
+        blocking(move || tree.apply_batch(batch)).await
+    }
+
+    /// Compare and swap. Capable of unique creation, conditional modification, or deletion.
+    ///
+    /// See `Tree::compare_and_swap` for details.
+    pub async fn compare_and_swap<K>(
+        &self,
+        key: K,
+        old: Option<V>,
+        new: Option<V>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError<V>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.compare_and_swap(key, old, new)).await
+    }
+
+    /// Retrieve a value from the Tree if it exists.
+    pub async fn get<K>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.get(key)).await
+    }
+
+    /// Retrieve the values for many keys at once, in the order the keys were given.
+    pub async fn get_many<K, I>(&self, keys: I) -> Result<Vec<Option<V>>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let tree = self.0.cloned();
+        let keys: Vec<IVec> = keys.into_iter().map(|key| key.as_ref().into()).collect();
+        blocking(move || tree.get_many(keys)).await
+    }
+
+    /// Insert a key to a new value, returning the last value if it was set.
+    pub async fn insert<K>(&self, key: K, value: V) -> Result<Option<V>>
+    where
+        IVec: From<K>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key);
+        blocking(move || tree.insert::<IVec>(key, value)).await
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    pub async fn remove<K>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.remove(key)).await
+    }
+
+    /// Fetch the value, apply a function to it and return the result.
+    ///
+    /// ### Note
+    /// This may call the function multiple times if the value has been changed from other threads
+    /// in the meantime.
+    pub async fn update_and_fetch<K, G>(&self, key: K, f: G) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        G: Fn(Option<V>) -> Option<V> + Send + 'static,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.update_and_fetch(key, f)).await
+    }
+
+    /// Fetch the value, apply a function to it and return the previous value.
+    ///
+    /// ### Note
+    /// This may call the function multiple times if the value has been changed from other threads
+    /// in the meantime.
+    pub async fn fetch_and_update<K, G>(&self, key: K, f: G) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        G: Fn(Option<V>) -> Option<V> + Send + 'static,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.fetch_and_update(key, f)).await
+    }
+
+    /// Subscribe to `Event`s that happen to keys that have the specified prefix.
+    ///
+    /// See `Tree::subscribe` for details.
+    pub fn subscribe(&self, prefix: Vec<u8>) -> AsyncSubscriber<V, E> {
+        AsyncSubscriber {
+            subscriber: Some(self.0.subscribe(prefix)),
+            pending: None,
+        }
+    }
+
+    /// Flushes all dirty IO buffers and calls fsync.
+    pub async fn flush(&self) -> Result<()> {
+        self.0.flush_async().await
+    }
+
+    /// Returns `true` if the `Tree` contains a value for the specified key.
+    pub async fn contains_key<K>(&self, key: K) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.contains_key(key)).await
+    }
+
+    /// Create a stream over the tuples of keys and values in this tree.
+    pub fn iter(&self) -> AsyncIter<StructuredTree<V, E>, V> {
+        AsyncIter::new(self.0.cloned(), Bound::Unbounded, Bound::Unbounded, None)
+    }
+
+    /// Create a stream over tuples of keys and values, where the keys fall within the specified
+    /// range.
+    pub fn range<K, R>(&self, range: R) -> AsyncIter<StructuredTree<V, E>, V>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        AsyncIter::new(
+            self.0.cloned(),
+            to_ivec_bound(range.start_bound()),
+            to_ivec_bound(range.end_bound()),
+            None,
+        )
+    }
+
+    /// Create a stream over tuples of keys and values, where the all the keys starts with the
+    /// given prefix.
+    pub fn scan_prefix<P>(&self, prefix: P) -> AsyncIter<StructuredTree<V, E>, V>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = IVec::from(prefix.as_ref());
+
+        AsyncIter::new(
+            self.0.cloned(),
+            Bound::Included(prefix.clone()),
+            Bound::Unbounded,
+            Some(prefix),
+        )
+    }
+
+    /// Retrieve the key and value before the provided key, if one exists.
+    pub async fn get_lt<K>(&self, key: K) -> Result<Option<(IVec, V)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.get_lt(key)).await
+    }
+
+    /// Retrieve the next key and value from the Tree after the provided key.
+    pub async fn get_gt<K>(&self, key: K) -> Result<Option<(IVec, V)>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.get_gt(key)).await
+    }
+
+    /// Atomically removes the maximum item in the `Tree` instance.
+    pub async fn pop_max(&self) -> Result<Option<(IVec, V)>> {
+        let tree = self.0.cloned();
+        blocking(move || tree.pop_max()).await
+    }
+
+    /// Atomically removes the minimum item in the `Tree` instance.
+    pub async fn pop_min(&self) -> Result<Option<(IVec, V)>> {
+        let tree = self.0.cloned();
+        blocking(move || tree.pop_min()).await
+    }
+
+    /// Returns the number of elements in this tree.
+    ///
+    /// Beware: performs a full O(n) scan under the hood.
+    pub async fn len(&self) -> Result<usize> {
+        let tree = self.0.cloned();
+        blocking(move || Ok(tree.len())).await
+    }
+
+    /// Returns `true` if the `Tree` contains no elements.
+    pub async fn is_empty(&self) -> Result<bool> {
+        let tree = self.0.cloned();
+        blocking(move || Ok(tree.is_empty())).await
+    }
+
+    /// Clears the `Tree`, removing all values.
+    ///
+    /// Note that this is not atomic.
+    pub async fn clear(&self) -> Result<()> {
+        let tree = self.0.cloned();
+        blocking(move || tree.clear()).await
+    }
+
+    /// Returns the name of the tree.
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+}
+
+impl<V, E, F> AsyncExpiringTree<V, E, F>
+where
+    E: Encoding<HashSet<IVec>> + Encoding<DateTime<Utc>> + Send + Sync + 'static,
+    F: Encoding<V> + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    /// Wrap an expiring tree for use from async code
+    pub fn new(tree: ExpiringTree<V, E, F>) -> Self {
+        AsyncExpiringTree(tree)
+    }
+
+    /// Borrow the blocking tree this wraps
+    pub fn inner(&self) -> &ExpiringTree<V, E, F> {
+        &self.0
+    }
+
+    /// Unwrap the blocking tree
+    pub fn into_inner(self) -> ExpiringTree<V, E, F> {
+        self.0
+    }
+
+    /// Create a new batched update that can be atomically applied.
+    pub async fn apply_batch(&self, batch: ExpiringBatch<V, F>) -> Result<()> {
+        let tree = self.0.cloned();
+        blocking(move || tree.apply_batch(batch)).await
+    }
+
+    /// Compare and swap. Capable of unique creation, conditional modification, or deletion.
+    ///
+    /// See `Tree::compare_and_swap` for details.
+    pub async fn compare_and_swap<K>(
+        &self,
+        key: K,
+        old: Option<V>,
+        new: Option<V>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError<V>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.compare_and_swap(key, old, new)).await
+    }
+
+    /// Retrieve a value from the Tree if it exists.
+    pub async fn get<K>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.get(key)).await
+    }
+
+    /// Retrieve the values for many keys at once, in the order the keys were given.
+    pub async fn get_many<K, I>(&self, keys: I) -> Result<Vec<Option<V>>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let tree = self.0.cloned();
+        let keys: Vec<IVec> = keys.into_iter().map(|key| key.as_ref().into()).collect();
+        blocking(move || tree.get_many(keys)).await
+    }
+
+    /// Insert a key to a new value, returning the last value if it was set.
+    pub async fn insert<K>(&self, key: K, value: V) -> Result<Option<V>>
+    where
+        IVec: From<K>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key);
+        blocking(move || tree.insert::<IVec>(key, value)).await
+    }
+
+    /// Delete a value, returning the old value if it existed.
+    pub async fn remove<K>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.remove(key)).await
+    }
+
+    /// Fetch the value, apply a function to it and return the result.
+    ///
+    /// ### Note
+    /// This may call the function multiple times if the value has been changed from other threads
+    /// in the meantime.
+    pub async fn update_and_fetch<K, G>(&self, key: K, f: G) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        G: Fn(Option<V>) -> Option<V> + Send + 'static,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.update_and_fetch(key, f)).await
+    }
+
+    /// Fetch the value, apply a function to it and return the previous value.
+    ///
+    /// ### Note
+    /// This may call the function multiple times if the value has been changed from other threads
+    /// in the meantime.
+    pub async fn fetch_and_update<K, G>(&self, key: K, f: G) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        G: Fn(Option<V>) -> Option<V> + Send + 'static,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.fetch_and_update(key, f)).await
+    }
+
+    /// Flushes all dirty IO buffers and calls fsync.
+    pub async fn flush(&self) -> Result<()> {
+        let tree = self.0.cloned();
+        blocking(move || tree.flush()).await
+    }
+
+    /// Returns `true` if the `Tree` contains a value for the specified key.
+    pub async fn contains_key<K>(&self, key: K) -> Result<bool>
+    where
+        K: AsRef<[u8]>,
+    {
+        let tree = self.0.cloned();
+        let key = IVec::from(key.as_ref());
+        blocking(move || tree.contains_key(key)).await
+    }
+
+    /// Create a stream over the tuples of keys and values in this tree.
+    pub fn iter(&self) -> AsyncIter<ExpiringTree<V, E, F>, V> {
+        AsyncIter::new(self.0.cloned(), Bound::Unbounded, Bound::Unbounded, None)
+    }
+
+    /// Create a stream over tuples of keys and values, where the keys fall within the specified
+    /// range.
+    pub fn range<K, R>(&self, range: R) -> AsyncIter<ExpiringTree<V, E, F>, V>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        AsyncIter::new(
+            self.0.cloned(),
+            to_ivec_bound(range.start_bound()),
+            to_ivec_bound(range.end_bound()),
+            None,
+        )
+    }
+
+    /// Create a stream over tuples of keys and values, where the all the keys starts with the
+    /// given prefix.
+    pub fn scan_prefix<P>(&self, prefix: P) -> AsyncIter<ExpiringTree<V, E, F>, V>
+    where
+        P: AsRef<[u8]>,
+    {
+        let prefix = IVec::from(prefix.as_ref());
+
+        AsyncIter::new(
+            self.0.cloned(),
+            Bound::Included(prefix.clone()),
+            Bound::Unbounded,
+            Some(prefix),
+        )
+    }
+
+    /// Atomically removes the maximum item in the `Tree` instance.
+    pub async fn pop_max(&self) -> Result<Option<(IVec, V)>> {
+        let tree = self.0.cloned();
+        blocking(move || tree.pop_max()).await
+    }
+
+    /// Atomically removes the minimum item in the `Tree` instance.
+    pub async fn pop_min(&self) -> Result<Option<(IVec, V)>> {
+        let tree = self.0.cloned();
+        blocking(move || tree.pop_min()).await
+    }
+
+    /// Clears the `Tree`, removing all values.
+    ///
+    /// Note that this is not atomic.
+    pub async fn clear(&self) -> Result<()> {
+        let tree = self.0.cloned();
+        blocking(move || tree.clear()).await
+    }
+
+    /// Collect the keys of expired records
+    pub async fn expired(&self) -> Result<Vec<IVec>> {
+        let tree = self.0.cloned();
+        blocking(move || Ok(tree.expired().collect())).await
+    }
+
+    /// Returns the name of the tree.
+    pub fn name(&self) -> String {
+        self.0.name()
+    }
+}
+
+impl<T, V> AsyncIter<T, V>
+where
+    T: Scan<V>,
+    V: Send + 'static,
+{
+    /// Fetch the next record from the stream
+    pub async fn next(&mut self) -> Option<Result<(IVec, V)>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    fn new(tree: T, lower: Bound<IVec>, upper: Bound<IVec>, prefix: Option<IVec>) -> Self {
+        AsyncIter {
+            tree,
+            lower,
+            upper,
+            prefix,
+            buffer: VecDeque::new(),
+            pending: None,
+            done: false,
+        }
+    }
+}
+
+impl<V, E> AsyncSubscriber<V, E>
+where
+    E: Encoding<V> + Send + 'static,
+    V: Send + 'static,
+{
+    /// Wait for the next event
+    pub async fn next(&mut self) -> Option<Result<StructuredEvent<V>>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<V, E> Scan<V> for StructuredTree<V, E>
+where
+    E: Encoding<V> + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    fn cloned(&self) -> Self {
+        self.cloned()
+    }
+
+    fn scan(&self, lower: Bound<IVec>, upper: Bound<IVec>, limit: usize) -> Result<Vec<(IVec, V)>> {
+        self.range((lower, upper)).take(limit).collect()
+    }
+}
+
+impl<V, E, F> Scan<V> for ExpiringTree<V, E, F>
+where
+    E: Encoding<HashSet<IVec>> + Encoding<DateTime<Utc>> + Send + Sync + 'static,
+    F: Encoding<V> + Send + Sync + 'static,
+    V: Send + 'static,
+{
+    fn cloned(&self) -> Self {
+        self.cloned()
+    }
+
+    fn scan(&self, lower: Bound<IVec>, upper: Bound<IVec>, limit: usize) -> Result<Vec<(IVec, V)>> {
+        self.range((lower, upper)).take(limit).collect()
+    }
+}
+
+impl<V, E> From<StructuredTree<V, E>> for AsyncStructuredTree<V, E> {
+    fn from(tree: StructuredTree<V, E>) -> Self {
+        AsyncStructuredTree(tree)
+    }
+}
+
+impl<V, E, F> From<ExpiringTree<V, E, F>> for AsyncExpiringTree<V, E, F> {
+    fn from(tree: ExpiringTree<V, E, F>) -> Self {
+        AsyncExpiringTree(tree)
+    }
+}
+
+impl<T, V> Unpin for AsyncIter<T, V> where T: Scan<V> {}
+
+impl<V, E> Unpin for AsyncSubscriber<V, E> {}
+
+impl<T, V> Stream for AsyncIter<T, V>
+where
+    T: Scan<V>,
+    V: Send + 'static,
+{
+    type Item = Result<(IVec, V)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            if let Some((key, value)) = this.buffer.pop_front() {
+                if let Some(ref prefix) = this.prefix {
+                    if !key.starts_with(prefix) {
+                        this.buffer.clear();
+                        this.done = true;
+                        return Poll::Ready(None);
+                    }
+                }
+
+                return Poll::Ready(Some(Ok((key, value))));
+            }
+
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let handle = match this.pending {
+                Some(ref mut handle) => handle,
+                None => {
+                    let tree = this.tree.cloned();
+                    let lower = this.lower.clone();
+                    let upper = this.upper.clone();
+
+                    this.pending
+                        .get_or_insert(tokio::task::spawn_blocking(move || {
+                            tree.scan(lower, upper, CHUNK_SIZE)
+                        }))
+                }
+            };
+
+            let res = match Pin::new(handle).poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            };
+            this.pending = None;
+
+            let chunk = match res {
+                Ok(Ok(chunk)) => chunk,
+                Ok(Err(e)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+                Err(e) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(Error::custom(e))));
+                }
+            };
+
+            if chunk.len() < CHUNK_SIZE {
+                this.done = true;
+            }
+
+            if let Some((key, _)) = chunk.last() {
+                this.lower = Bound::Excluded(key.clone());
+            }
+
+            this.buffer.extend(chunk);
+        }
+    }
+}
+
+impl<V, E> Stream for AsyncSubscriber<V, E>
+where
+    E: Encoding<V> + Send + 'static,
+    V: Send + 'static,
+{
+    type Item = Result<StructuredEvent<V>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        if this.pending.is_none() {
+            let mut subscriber = match this.subscriber.take() {
+                Some(subscriber) => subscriber,
+                None => return Poll::Ready(None),
+            };
+
+            this.pending = Some(tokio::task::spawn_blocking(move || {
+                let event = subscriber.next();
+                (subscriber, event)
+            }));
+        }
+
+        let res = match this.pending {
+            Some(ref mut handle) => match Pin::new(handle).poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            },
+            None => return Poll::Ready(None),
+        };
+        this.pending = None;
+
+        match res {
+            Ok((subscriber, event)) => {
+                if event.is_some() {
+                    this.subscriber = Some(subscriber);
+                }
+                Poll::Ready(event)
+            }
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Poll::Ready(Some(Err(Error::custom(e)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, Config};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .expect("Failed to build runtime")
+    }
+
+    fn tree(db: &sled::Db) -> AsyncStructuredTree<IVec, PlainEncoding> {
+        AsyncStructuredTree::new(StructuredTree::new(db, "async").unwrap())
+    }
+
+    #[test]
+    fn structured_tree_crud() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = tree(&db);
+
+        runtime().block_on(async {
+            assert_eq!(tree.insert(b"a", IVec::from(b"1")).await.unwrap(), None);
+            assert_eq!(tree.get(b"a").await.unwrap(), Some(IVec::from(b"1")));
+            assert!(tree.contains_key(b"a").await.unwrap());
+
+            let swapped = tree
+                .compare_and_swap(b"a", Some(IVec::from(b"1")), Some(IVec::from(b"2")))
+                .await
+                .unwrap();
+            assert!(swapped.is_ok());
+
+            let updated = tree
+                .update_and_fetch(b"a", |v| v.map(|v| IVec::from([&*v, b"3"].concat())))
+                .await
+                .unwrap();
+            assert_eq!(updated, Some(IVec::from(b"23")));
+
+            let many = tree.get_many(vec![b"a", b"b"]).await.unwrap();
+            assert_eq!(many, vec![Some(IVec::from(b"23")), None]);
+
+            assert_eq!(tree.len().await.unwrap(), 1);
+            assert_eq!(tree.remove(b"a").await.unwrap(), Some(IVec::from(b"23")));
+            assert!(tree.is_empty().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn iter_spans_chunks() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = tree(&db);
+        let count = CHUNK_SIZE * 2 + 7;
+
+        runtime().block_on(async {
+            for i in 0..count as u32 {
+                let key = [&b"k"[..], &i.to_be_bytes()].concat();
+                tree.insert(key, IVec::from(&i.to_be_bytes()))
+                    .await
+                    .unwrap();
+            }
+            tree.insert(b"z", IVec::from(b"outside")).await.unwrap();
+
+            let mut iter = tree.scan_prefix(b"k");
+            let mut seen = 0u32;
+
+            while let Some(res) = iter.next().await {
+                let (_, value) = res.unwrap();
+                assert_eq!(value, IVec::from(&seen.to_be_bytes()));
+                seen += 1;
+            }
+
+            assert_eq!(seen as usize, count);
+
+            let mut all = tree.iter();
+            let mut total = 0;
+            while let Some(res) = all.next().await {
+                res.unwrap();
+                total += 1;
+            }
+            assert_eq!(total, count + 1);
+        });
+    }
+
+    #[test]
+    fn subscriber_sees_writes() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = tree(&db);
+        let rt = runtime();
+
+        rt.block_on(async {
+            let mut subscriber = tree.subscribe(b"watched".to_vec());
+
+            tree.insert(b"ignored", IVec::from(b"0")).await.unwrap();
+            tree.insert(b"watched", IVec::from(b"1")).await.unwrap();
+            tree.remove(b"watched").await.unwrap();
+
+            match subscriber.next().await {
+                Some(Ok(StructuredEvent::Insert(key, value))) => {
+                    assert_eq!(key, IVec::from(b"watched"));
+                    assert_eq!(value, IVec::from(b"1"));
+                }
+                _ => panic!("Expected an insert"),
+            }
+
+            match subscriber.next().await {
+                Some(Ok(StructuredEvent::Remove(key))) => assert_eq!(key, IVec::from(b"watched")),
+                _ => panic!("Expected a removal"),
+            }
+        });
+
+        rt.shutdown_timeout(std::time::Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn expiring_tree() {
+        use crate::DbExt;
+
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree: AsyncExpiringTree<String, _, _> = db
+            .open_expiring_json_tree::<String>("async-expiring")
+            .extend_on_update()
+            .expiration_length(chrono::Duration::milliseconds(-1))
+            .build()
+            .unwrap()
+            .into();
+
+        runtime().block_on(async {
+            tree.insert(b"a", "one".to_owned()).await.unwrap();
+            assert_eq!(tree.get(b"a").await.unwrap(), Some("one".to_owned()));
+            assert!(tree.contains_key(b"a").await.unwrap());
+            assert_eq!(tree.expired().await.unwrap(), vec![IVec::from(b"a")]);
+
+            assert_eq!(tree.remove(b"a").await.unwrap(), Some("one".to_owned()));
+            assert!(tree.expired().await.unwrap().is_empty());
+        });
+    }
+}
@@ -0,0 +1,239 @@
+use sled::IVec;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{encoding::Encoding, error::Result, structured_tree::StructuredTree};
+
+const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+/// Loads large numbers of values into a structured tree
+///
+/// Values are pulled from the source in batches, encoded in parallel, and written with a single
+/// `sled::Batch` per batch. Trees with indexes or other companion trees are written in a single
+/// transaction per batch instead, so their companions stay consistent.
+pub struct BulkLoader<'a, V, E> {
+    tree: &'a StructuredTree<V, E>,
+    threads: usize,
+    batch_size: usize,
+    progress: Option<Box<OnProgress<'a>>>,
+    after_batch: Option<Box<AfterBatch<'a>>>,
+}
+
+type OnProgress<'a> = dyn FnMut(&Progress) + 'a;
+
+type AfterBatch<'a> = dyn Fn(&[IVec]) -> Result<()> + 'a;
+
+/// How far a bulk load has progressed
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    /// The number of values written so far
+    pub loaded: usize,
+
+    /// The time spent loading so far
+    pub elapsed: Duration,
+}
+
+impl<'a, V, E> BulkLoader<'a, V, E>
+where
+    E: Encoding<V> + 'static,
+{
+    pub(crate) fn new(tree: &'a StructuredTree<V, E>) -> Self {
+        BulkLoader {
+            tree,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            batch_size: DEFAULT_BATCH_SIZE,
+            progress: None,
+            after_batch: None,
+        }
+    }
+
+    /// Set the number of threads values are encoded on, defaulting to the available parallelism
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Set the number of values written at once, defaulting to 10,000
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Call a function after each batch has been written
+    pub fn on_progress<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Progress) + 'a,
+    {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Run a function with the keys of each batch once it has been written
+    pub(crate) fn after_batch<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&[IVec]) -> Result<()> + 'a,
+    {
+        self.after_batch = Some(Box::new(f));
+        self
+    }
+
+    /// Load every key and value from the source, in any order
+    ///
+    /// When a key appears more than once, the last value for it wins. Returns the progress once
+    /// every value has been written.
+    pub fn load<K, I>(mut self, records: I) -> Result<Progress>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+        V: Sync,
+    {
+        let start = Instant::now();
+        let mut records = records.into_iter();
+        let mut loaded = 0;
+
+        loop {
+            let batch: Vec<(IVec, V)> = records
+                .by_ref()
+                .take(self.batch_size)
+                .map(|(key, value)| (IVec::from(key.as_ref()), value))
+                .collect();
+
+            if batch.is_empty() {
+                break;
+            }
+
+            let encoded = encode_all::<V, E>(&self.tree.name(), &batch, self.threads)?;
+
+            let mut entries: Vec<_> = batch
+                .into_iter()
+                .zip(encoded)
+                .map(|((key, value), v)| (key, value, v))
+                .collect();
+
+            // Writing in key order keeps each batch local in the tree. The sort is stable, so
+            // later values for a key still win.
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+            self.tree.write_encoded(&entries)?;
+            loaded += entries.len();
+
+            if let Some(after_batch) = &self.after_batch {
+                let keys: Vec<IVec> = entries.into_iter().map(|(key, _, _)| key).collect();
+                (after_batch)(&keys)?;
+            }
+
+            if let Some(progress) = &mut self.progress {
+                (progress)(&Progress {
+                    loaded,
+                    elapsed: start.elapsed(),
+                });
+            }
+        }
+
+        Ok(Progress {
+            loaded,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+impl Progress {
+    /// The average number of values written per second
+    pub fn per_second(&self) -> f64 {
+        self.loaded as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Encode every value for the named tree, split evenly across the threads
+fn encode_all<V, E>(tree: &str, batch: &[(IVec, V)], threads: usize) -> Result<Vec<Vec<u8>>>
+where
+    V: Sync,
+    E: Encoding<V>,
+{
+    let chunk_size = batch.len().div_ceil(threads);
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = batch
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(key, value)| E::encode_at(tree, key, value))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect();
+
+        let mut encoded = Vec::with_capacity(batch.len());
+
+        for handle in handles {
+            let chunk = handle
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+
+            encoded.extend(chunk);
+        }
+
+        Ok(encoded)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        encoding::PlainEncoding, index::IndexKey, structured_tree::StructuredTree, Config,
+    };
+    use sled::IVec;
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn records(n: u16) -> impl Iterator<Item = (Vec<u8>, IVec)> {
+        (0..n).rev().map(|i| {
+            let value = IVec::from(&[(i % 3) as u8][..]);
+            (i.to_be_bytes().to_vec(), value)
+        })
+    }
+
+    #[test]
+    fn batches_are_written_in_any_order() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "bulk").unwrap();
+        let mut batches = Vec::new();
+
+        let progress = tree
+            .bulk_loader()
+            .threads(3)
+            .batch_size(40)
+            .on_progress(|progress| batches.push(progress.loaded))
+            .load(records(100).chain(vec![(vec![0, 0], IVec::from(b"last"))]))
+            .unwrap();
+
+        assert_eq!(progress.loaded, 101);
+        assert_eq!(batches, vec![40, 80, 101]);
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.get([0, 0]).unwrap(), Some(IVec::from(b"last")));
+        assert_eq!(tree.get([0, 99]).unwrap(), Some(IVec::from(&[0][..])));
+    }
+
+    #[test]
+    fn companion_trees_are_kept_up_to_date() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "bulk")
+            .unwrap()
+            .with_index("value", |value: &IVec| vec![IndexKey::from(value.clone())])
+            .unwrap();
+
+        tree.bulk_loader().batch_size(7).load(records(30)).unwrap();
+        tree.bulk_loader().load(records(3)).unwrap();
+
+        for (value, count) in &[(0u8, 10), (1, 10), (2, 10)] {
+            assert_eq!(
+                tree.get_by_index("value", &[*value][..]).unwrap().count(),
+                *count
+            );
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::{marker::PhantomData, sync::Arc};
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    hook::{Hook, Write},
+    structured_tree::StructuredEvent,
+};
+
+const SEQUENCE: &[u8] = b"sequence";
+const CURSOR_PREFIX: &[u8] = b"cursor/";
+
+const REMOVE: u8 = 0;
+const INSERT: u8 = 1;
+
+/// A durable, append-only log of the changes made to a structured tree
+///
+/// Every change is assigned a sequence number in the same transaction that writes it, so the log
+/// is ordered by commit and never misses a write.
+pub struct Changelog<V, E> {
+    tree: Arc<str>,
+    log: sled::Tree,
+    meta: sled::Tree,
+    value: PhantomData<V>,
+    encoding: PhantomData<E>,
+}
+
+/// A named reader of a changelog that remembers how far it has read
+///
+/// The position is stored in the database, so consumers resume where they left off after a
+/// restart.
+pub struct Consumer<V, E> {
+    changelog: Changelog<V, E>,
+    cursor: Vec<u8>,
+}
+
+/// An iterator over the changes in a changelog, paired with their sequence numbers
+pub struct ChangelogIter<V, E>(sled::Iter, Arc<str>, PhantomData<V>, PhantomData<E>);
+
+pub(crate) struct ChangelogHook {
+    trees: [sled::Tree; 2],
+}
+
+impl<V, E> Changelog<V, E>
+where
+    E: Encoding<V> + 'static,
+{
+    pub(crate) fn new(db: &sled::Db, name: &str) -> Result<Self> {
+        Ok(Changelog {
+            tree: name.into(),
+            log: db.open_tree(format!("{}-changelog", name))?,
+            meta: db.open_tree(format!("{}-changelog-meta", name))?,
+            value: PhantomData,
+            encoding: PhantomData,
+        })
+    }
+
+    pub(crate) fn hook(&self) -> ChangelogHook {
+        ChangelogHook {
+            trees: [self.log.clone(), self.meta.clone()],
+        }
+    }
+
+    /// Clone for structures where V and E aren't Clone
+    pub fn cloned(&self) -> Self {
+        Changelog {
+            tree: self.tree.clone(),
+            log: self.log.clone(),
+            meta: self.meta.clone(),
+            value: PhantomData,
+            encoding: PhantomData,
+        }
+    }
+
+    /// Iterate over every change still present in the log
+    pub fn iter(&self) -> ChangelogIter<V, E> {
+        ChangelogIter(self.log.iter(), self.tree.clone(), PhantomData, PhantomData)
+    }
+
+    /// Iterate over the changes starting at the provided sequence number
+    pub fn since(&self, sequence: u64) -> ChangelogIter<V, E> {
+        ChangelogIter(
+            self.log.range(sequence.to_be_bytes()..),
+            self.tree.clone(),
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    /// The sequence number that will be assigned to the next change
+    pub fn next_sequence(&self) -> Result<u64> {
+        match self.meta.get(SEQUENCE)? {
+            Some(v) => decode_sequence(&v),
+            None => Ok(0),
+        }
+    }
+
+    /// Get a named consumer of this changelog
+    pub fn consumer(&self, name: &str) -> Consumer<V, E> {
+        let mut cursor = CURSOR_PREFIX.to_vec();
+        cursor.extend_from_slice(name.as_bytes());
+
+        Consumer {
+            changelog: self.cloned(),
+            cursor,
+        }
+    }
+
+    /// Remove every change with a sequence number lower than the one provided
+    ///
+    /// Consumers that have not yet read those changes will never see them.
+    pub fn truncate(&self, before: u64) -> Result<()> {
+        for key in self.log.range(..before.to_be_bytes()).keys() {
+            self.log.remove(key?)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<V, E> Consumer<V, E>
+where
+    E: Encoding<V> + 'static,
+{
+    /// The sequence number of the next change this consumer will read
+    pub fn position(&self) -> Result<u64> {
+        match self.changelog.meta.get(&self.cursor)? {
+            Some(v) => decode_sequence(&v),
+            None => Ok(0),
+        }
+    }
+
+    /// Iterate over the changes this consumer has not yet committed
+    pub fn iter(&self) -> Result<ChangelogIter<V, E>> {
+        Ok(self.changelog.since(self.position()?))
+    }
+
+    /// Fetch up to `limit` changes this consumer has not yet committed
+    ///
+    /// This does not move the consumer's position, so the same changes will be returned until
+    /// they are committed.
+    pub fn poll(&self, limit: usize) -> Result<Vec<(u64, StructuredEvent<V>)>> {
+        self.iter()?.take(limit).collect()
+    }
+
+    /// Record that every change up to and including the provided sequence number has been
+    /// processed
+    ///
+    /// A stored position that can't be read is reported rather than overwritten.
+    pub fn commit(&self, sequence: u64) -> Result<()> {
+        self.position()?;
+
+        let next = next_sequence(sequence)?;
+
+        self.changelog
+            .meta
+            .insert(self.cursor.as_slice(), &next.to_be_bytes()[..])?;
+        Ok(())
+    }
+}
+
+impl<V> Hook<V> for ChangelogHook {
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        if write.old.is_none() && write.new.is_none() {
+            return Ok(Ok(()));
+        }
+
+        let (log, meta) = (&trees[0], &trees[1]);
+
+        let sequence = match meta.get(SEQUENCE)? {
+            Some(v) => match decode_sequence(&v) {
+                Ok(sequence) => sequence,
+                Err(e) => return Ok(Err(e)),
+            },
+            None => 0,
+        };
+
+        let mut record = Vec::new();
+
+        match write.new {
+            Some((_, v)) => {
+                record.push(INSERT);
+                record.extend_from_slice(&(write.key.len() as u32).to_be_bytes());
+                record.extend_from_slice(write.key);
+                record.extend_from_slice(v);
+            }
+            None => {
+                record.push(REMOVE);
+                record.extend_from_slice(write.key);
+            }
+        }
+
+        let next = match next_sequence(sequence) {
+            Ok(next) => next,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        meta.insert(SEQUENCE, &next.to_be_bytes()[..])?;
+        log.insert(&sequence.to_be_bytes()[..], record)?;
+
+        Ok(Ok(()))
+    }
+}
+
+fn decode_sequence(v: &[u8]) -> Result<u64> {
+    let mut buf = [0; 8];
+
+    if v.len() != buf.len() {
+        return Err(Error::Corrupted("changelog sequence number".to_owned()));
+    }
+
+    buf.copy_from_slice(v);
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn next_sequence(sequence: u64) -> Result<u64> {
+    sequence
+        .checked_add(1)
+        .ok_or_else(|| Error::Corrupted(format!("changelog sequence number {}", sequence)))
+}
+
+fn decode_record<V, E>(tree: &str, key: &[u8], record: &[u8]) -> Result<(u64, StructuredEvent<V>)>
+where
+    E: Encoding<V>,
+{
+    let sequence = decode_sequence(key)?;
+    let corrupted = || Error::Corrupted(format!("changelog record {}", sequence));
+
+    match record.split_first() {
+        Some((&INSERT, rest)) if rest.len() >= 4 => {
+            let mut len = [0; 4];
+            len.copy_from_slice(&rest[..4]);
+            let len = u32::from_be_bytes(len) as usize;
+
+            if rest.len() < 4 + len {
+                return Err(corrupted());
+            }
+
+            let (key, value) = rest[4..].split_at(len);
+
+            Ok((
+                sequence,
+                StructuredEvent::Insert(IVec::from(key), E::decode_at(tree, key, value)?),
+            ))
+        }
+        Some((&REMOVE, key)) => Ok((sequence, StructuredEvent::Remove(IVec::from(key)))),
+        _ => Err(corrupted()),
+    }
+}
+
+impl<V, E> Iterator for ChangelogIter<V, E>
+where
+    E: Encoding<V>,
+{
+    type Item = Result<(u64, StructuredEvent<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.next()? {
+            Ok((key, record)) => Some(decode_record::<V, E>(&self.1, &key, &record)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl<V, E> DoubleEndedIterator for ChangelogIter<V, E>
+where
+    E: Encoding<V>,
+{
+    fn next_back(&mut self) -> Option<<Self as Iterator>::Item> {
+        match self.0.next_back()? {
+            Ok((key, record)) => Some(decode_record::<V, E>(&self.1, &key, &record)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, structured_tree::StructuredTree, Config};
+
+    fn tree(db: &sled::Db) -> StructuredTree<IVec, PlainEncoding> {
+        StructuredTree::new(db, "logged")
+            .unwrap()
+            .with_changelog()
+            .unwrap()
+    }
+
+    #[test]
+    fn records_changes_in_order() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = tree(&db);
+
+        tree.insert(b"a", IVec::from(b"1")).unwrap();
+        tree.insert(b"b", IVec::from(b"2")).unwrap();
+        tree.remove(b"a").unwrap();
+        tree.remove(b"missing").unwrap();
+
+        let changes: Vec<_> = tree
+            .changelog()
+            .unwrap()
+            .iter()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        assert_eq!(changes.len(), 3);
+        assert_eq!(
+            changes.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+
+        match &changes[1].1 {
+            StructuredEvent::Insert(key, value) => {
+                assert_eq!(key, &IVec::from(b"b"));
+                assert_eq!(value, &IVec::from(b"2"));
+            }
+            _ => panic!("Expected an insert"),
+        }
+
+        match &changes[2].1 {
+            StructuredEvent::Remove(key) => assert_eq!(key, &IVec::from(b"a")),
+            _ => panic!("Expected a removal"),
+        }
+    }
+
+    #[test]
+    fn consumers_resume_after_commit() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = tree(&db);
+
+        for i in 0..5u8 {
+            tree.insert(&[i][..], IVec::from(&[i])).unwrap();
+        }
+
+        let changelog = tree.changelog().unwrap();
+        let consumer = changelog.consumer("reader");
+
+        let first = consumer.poll(2).unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(consumer.poll(2).unwrap().len(), 2);
+
+        consumer.commit(first[1].0).unwrap();
+        assert_eq!(consumer.position().unwrap(), 2);
+        assert_eq!(changelog.consumer("reader").poll(10).unwrap().len(), 3);
+
+        changelog.truncate(2).unwrap();
+        assert_eq!(changelog.iter().count(), 3);
+        assert_eq!(changelog.next_sequence().unwrap(), 5);
+    }
+
+    #[test]
+    fn commit_rejects_overflow_and_corrupt_positions() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let changelog = tree(&db).changelog().unwrap();
+        let consumer = changelog.consumer("reader");
+
+        match consumer.commit(u64::MAX) {
+            Err(Error::Corrupted(_)) => (),
+            _ => panic!("Expected the overflow to be reported"),
+        }
+
+        changelog
+            .meta
+            .insert(b"cursor/reader", &b"bad"[..])
+            .unwrap();
+
+        match consumer.commit(1) {
+            Err(Error::Corrupted(_)) => (),
+            _ => panic!("Expected the corrupt position to be reported"),
+        }
+        assert!(consumer.position().is_err());
+    }
+}
@@ -0,0 +1,848 @@
+use std::{borrow::Cow, io, marker::PhantomData};
+
+#[cfg(feature = "zstd")]
+use sled::IVec;
+#[cfg(feature = "zstd")]
+use std::{
+    any::TypeId,
+    collections::{hash_map::RandomState, BTreeMap},
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::{Arc, RwLock, Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(feature = "zstd")]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+use crate::{
+    encoding::{Encoding, TreeState},
+    error::{Error, Result},
+};
+
+// Header bytes are chosen from those that can't start a UTF-8 string or a CBOR value, so values
+// written before compression was enabled are read back as they were stored. MessagePack negative
+// fixints (0xE0 to 0xFF) do overlap them.
+const UNCOMPRESSED: u8 = 0xFF;
+const ZSTD: u8 = 0xFE;
+const LZ4: u8 = 0xFD;
+const SNAPPY: u8 = 0xFC;
+const DICTIONARY: u8 = 0xBE;
+
+// Values with the header of a built-in algorithm other than the one in use were compressed with
+// it, rather than written before compression was enabled
+const BUILT_IN: [u8; 4] = [ZSTD, LZ4, SNAPPY, DICTIONARY];
+
+// The key of the token identifying the database a tree's dictionaries are stored in. Dictionary
+// ids start at 1, so it sorts ahead of them.
+#[cfg(feature = "zstd")]
+const OWNER: &[u8] = b"";
+
+#[cfg(feature = "zstd")]
+const FIRST_ID: [u8; 4] = 1u32.to_be_bytes();
+
+// Trained dictionaries, grouped by the type naming the set they belong to and the tree they were
+// trained for. Encodings can't carry state, so dictionaries are kept here much like merge
+// operators are.
+#[cfg(feature = "zstd")]
+static DICTIONARIES: RwLock<BTreeMap<TypeId, BTreeMap<String, Dictionaries>>> =
+    RwLock::new(BTreeMap::new());
+
+#[cfg(feature = "zstd")]
+struct Dictionaries {
+    owner: IVec,
+    loaded: Weak<LoadedDictionaries>,
+    current: Option<(u32, Arc<[u8]>)>,
+    encoders: BTreeMap<i32, Arc<EncoderDictionary<'static>>>,
+    decoders: BTreeMap<u32, Arc<DecoderDictionary<'static>>>,
+}
+
+#[cfg(feature = "zstd")]
+/// Held by the trees using a set of dictionaries, so it isn't replaced by another database's
+struct LoadedDictionaries;
+
+/// A compression algorithm that can be used with `Compressed`
+///
+/// By implementing this trait, a custom compression algorithm can be used to store values.
+pub trait Compression {
+    /// The header byte marking values compressed with this algorithm
+    ///
+    /// This must not be `0xFF`, which marks values stored uncompressed. Values with the header of
+    /// a built-in algorithm other than this one fail to decode.
+    const HEADER: u8;
+
+    /// Compress bytes
+    fn compress(data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Decompress bytes produced by `compress`
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Compress bytes that will be stored in the named tree
+    ///
+    /// Algorithms that keep state for each tree, such as `ZstdDictionary`, override this. By
+    /// default the tree is ignored.
+    fn compress_at(_tree: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        Self::compress(data)
+    }
+
+    /// Decompress bytes stored in the named tree
+    fn decompress_at(_tree: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        Self::decompress(data)
+    }
+
+    /// Load what is needed to compress values in the named tree, whenever it is opened
+    fn open(_db: &sled::Db, _tree: &str) -> Result<Vec<TreeState>> {
+        Ok(Vec::new())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+/// An Encoding that compresses the output of another Encoding
+///
+/// Each value is prefixed with a header byte recording whether it was compressed. Values smaller
+/// than `THRESHOLD` bytes, and values that don't shrink when compressed, are stored uncompressed.
+///
+/// Values without a header, such as those written before a tree's encoding was wrapped, are
+/// passed straight to the inner Encoding, while values with the header of another built-in
+/// algorithm fail with `Error::Decompress`. The header bytes can't start Json or Cbor values, but
+/// binary encodings such as bincode can produce values that look like they have a header, and
+/// MessagePack values starting with a negative fixint (`0xE0` to `0xFF`) do too, so existing
+/// bincode and MessagePack trees should be migrated rather than wrapped in place.
+///
+/// ```rust
+/// use sled_extensions::{compression::{Compressed, Zstd}, json::JsonEncoding, structured, Config, DbExt};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let db = Config::default().temporary(true).open()?;
+/// let tree: structured::Tree<Vec<String>, Compressed<JsonEncoding, Zstd>> =
+///     db.open_structured_tree("documents")?;
+///
+/// tree.insert(b"doc", vec!["a repeated string".to_owned(); 100])?;
+/// assert_eq!(tree.get(b"doc")?.map(|doc| doc.len()), Some(100));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Compressed<E, C, const THRESHOLD: usize = 64>(PhantomData<(E, C)>);
+
+#[cfg(feature = "zstd")]
+#[derive(Clone, Debug, Default)]
+/// Compression backed by zstd, at the given compression level
+pub struct Zstd<const LEVEL: i32 = 3>;
+
+#[cfg(feature = "zstd")]
+#[derive(Clone, Debug, Default)]
+/// Compression backed by zstd, using dictionaries trained from a tree's values
+///
+/// `D` is any type naming the set of dictionaries to use. Each tree has its own dictionaries
+/// within the set, trained with `train_dictionary` and loaded whenever the tree is opened. Values
+/// record the id of the dictionary they were compressed with, so training a new dictionary
+/// doesn't affect values that are already stored. Values written before any dictionary is
+/// trained are compressed without one.
+///
+/// Dictionaries are found by the name of the tree, so while a tree is open, opening a tree of the
+/// same name with the same `D` in another database fails with `Error::DictionariesInUse`.
+pub struct ZstdDictionary<D, const LEVEL: i32 = 3>(PhantomData<D>);
+
+#[cfg(feature = "lz4")]
+#[derive(Clone, Debug, Default)]
+/// Compression backed by lz4, which is very fast but compresses less than zstd
+pub struct Lz4;
+
+#[cfg(feature = "snappy")]
+#[derive(Clone, Debug, Default)]
+/// Compression backed by snappy
+pub struct Snappy;
+
+impl<T, E, C, const THRESHOLD: usize> Encoding<T> for Compressed<E, C, THRESHOLD>
+where
+    E: Encoding<T>,
+    C: Compression,
+{
+    fn encode(t: &T) -> Result<Vec<u8>> {
+        compress::<C, _, THRESHOLD>(E::encode(t)?, C::compress)
+    }
+
+    fn decode(slice: &[u8]) -> Result<T> {
+        E::decode(&decompress::<C, _>(slice, C::decompress)?)
+    }
+
+    fn encode_at(tree: &str, key: &[u8], t: &T) -> Result<Vec<u8>> {
+        compress::<C, _, THRESHOLD>(E::encode_at(tree, key, t)?, |data| {
+            C::compress_at(tree, data)
+        })
+    }
+
+    fn decode_at(tree: &str, key: &[u8], slice: &[u8]) -> Result<T> {
+        let decompressed = decompress::<C, _>(slice, |data| C::decompress_at(tree, data))?;
+        E::decode_at(tree, key, &decompressed)
+    }
+
+    fn open(db: &sled::Db, tree: &str) -> Result<Vec<TreeState>> {
+        let mut state = E::open(db, tree)?;
+        state.extend(C::open(db, tree)?);
+        Ok(state)
+    }
+}
+
+/// Compress an encoded value if it is large enough and the compression pays off
+fn compress<C, F, const THRESHOLD: usize>(encoded: Vec<u8>, compress: F) -> Result<Vec<u8>>
+where
+    C: Compression,
+    F: FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+{
+    if encoded.len() >= THRESHOLD {
+        let compressed = (compress)(&encoded).map_err(Error::Compress)?;
+
+        if compressed.len() < encoded.len() {
+            return Ok(with_header(C::HEADER, &compressed));
+        }
+    }
+
+    Ok(with_header(UNCOMPRESSED, &encoded))
+}
+
+/// The encoded value held by a stored value, which predates compression without a header
+///
+/// Values compressed by another built-in algorithm are an error rather than a value.
+fn decompress<C, F>(slice: &[u8], decompress: F) -> Result<Cow<'_, [u8]>>
+where
+    C: Compression,
+    F: FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+{
+    match slice.split_first() {
+        Some((&UNCOMPRESSED, rest)) => Ok(Cow::Borrowed(rest)),
+        Some((&header, rest)) if header == C::HEADER => {
+            Ok(Cow::Owned((decompress)(rest).map_err(Error::Decompress)?))
+        }
+        Some((header, _)) if BUILT_IN.contains(header) => Err(Error::Decompress(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "value was compressed by another algorithm, with header {:#04X}",
+                header
+            ),
+        ))),
+        _ => Ok(Cow::Borrowed(slice)),
+    }
+}
+
+fn with_header(header: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 1);
+    out.push(header);
+    out.extend_from_slice(data);
+    out
+}
+
+#[cfg(feature = "zstd")]
+impl<const LEVEL: i32> Compression for Zstd<LEVEL> {
+    const HEADER: u8 = ZSTD;
+
+    fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::encode_all(data, LEVEL)
+    }
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        zstd::decode_all(data)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl<D, const LEVEL: i32> Compression for ZstdDictionary<D, LEVEL>
+where
+    D: 'static,
+{
+    const HEADER: u8 = DICTIONARY;
+
+    fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+        Self::compress_at("", data)
+    }
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        Self::decompress_at("", data)
+    }
+
+    fn open(db: &sled::Db, tree: &str) -> Result<Vec<TreeState>> {
+        let loaded: TreeState = load_dictionaries::<D>(db, tree)?;
+        Ok(vec![loaded])
+    }
+
+    fn compress_at(tree: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (id, encoder) = encoder::<D>(tree, LEVEL);
+
+        let compressed = match encoder {
+            // The frame records the dictionary's own id too, so zstd refuses to decompress it with
+            // any other dictionary
+            Some(encoder) => {
+                zstd::bulk::Compressor::with_prepared_dictionary(&encoder)?.compress(data)?
+            }
+            None => zstd::bulk::compress(data, LEVEL)?,
+        };
+
+        let mut out = id.to_be_bytes().to_vec();
+        out.extend(compressed);
+        Ok(out)
+    }
+
+    fn decompress_at(tree: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Missing dictionary id",
+            ));
+        }
+
+        let (id, frame) = data.split_at(4);
+        let id = u32::from_be_bytes([id[0], id[1], id[2], id[3]]);
+
+        if id == 0 {
+            return zstd::decode_all(frame);
+        }
+
+        let decoder = decoder::<D>(tree, id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Dictionary {} has not been loaded for {}", id, tree),
+            )
+        })?;
+
+        let mut out = Vec::new();
+        zstd::stream::read::Decoder::with_prepared_dictionary(frame, &decoder)?
+            .read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+/// The id of a tree's newest dictionary in a set, along with its encoder at the given level
+///
+/// Only sets held by an open tree are used. A set that no tree holds may have been loaded from a
+/// database that has since closed, so values are compressed without a dictionary instead.
+#[cfg(feature = "zstd")]
+fn encoder<D>(tree: &str, level: i32) -> (u32, Option<Arc<EncoderDictionary<'static>>>)
+where
+    D: 'static,
+{
+    if let Ok(dictionaries) = DICTIONARIES.read() {
+        match dictionaries
+            .get(&TypeId::of::<D>())
+            .and_then(|trees| trees.get(tree))
+        {
+            Some(set) if set.loaded.strong_count() > 0 => {
+                match (&set.current, set.encoders.get(&level)) {
+                    (None, _) => return (0, None),
+                    (Some((id, _)), Some(encoder)) => return (*id, Some(encoder.clone())),
+                    (Some(_), None) => (),
+                }
+            }
+            _ => return (0, None),
+        }
+    }
+
+    let mut dictionaries = match DICTIONARIES.write() {
+        Ok(dictionaries) => dictionaries,
+        Err(_) => return (0, None),
+    };
+
+    match dictionaries
+        .get_mut(&TypeId::of::<D>())
+        .and_then(|trees| trees.get_mut(tree))
+    {
+        Some(Dictionaries {
+            current: Some((id, dictionary)),
+            encoders,
+            loaded,
+            ..
+        }) if loaded.strong_count() > 0 => {
+            let encoder = encoders
+                .entry(level)
+                .or_insert_with(|| Arc::new(EncoderDictionary::copy(dictionary, level)));
+
+            (*id, Some(encoder.clone()))
+        }
+        _ => (0, None),
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn decoder<D>(tree: &str, id: u32) -> Option<Arc<DecoderDictionary<'static>>>
+where
+    D: 'static,
+{
+    DICTIONARIES
+        .read()
+        .ok()?
+        .get(&TypeId::of::<D>())?
+        .get(tree)?
+        .decoders
+        .get(&id)
+        .cloned()
+}
+
+/// Make a tree's dictionaries available to a set, using the newest for new values
+///
+/// A set that was loaded from another database is replaced once no tree of that database is
+/// using it any more.
+#[cfg(feature = "zstd")]
+fn register<D>(tree: &str, owner: IVec, loaded: &[(u32, IVec)]) -> Result<Arc<LoadedDictionaries>>
+where
+    D: 'static,
+{
+    let mut dictionaries = DICTIONARIES
+        .write()
+        .map_err(|_| Error::Corrupted("Dictionaries were poisoned".to_owned()))?;
+
+    let trees = dictionaries.entry(TypeId::of::<D>()).or_default();
+    let in_use = trees.get(tree).and_then(|set| {
+        set.loaded
+            .upgrade()
+            .map(|handle| (set.owner == owner, handle))
+    });
+
+    let handle = match in_use {
+        Some((true, handle)) => handle,
+        Some((false, _)) => return Err(Error::DictionariesInUse(tree.to_owned())),
+        None => {
+            let handle = Arc::new(LoadedDictionaries);
+            let fresh = Dictionaries {
+                owner: owner.clone(),
+                loaded: Arc::downgrade(&handle),
+                current: None,
+                encoders: BTreeMap::new(),
+                decoders: BTreeMap::new(),
+            };
+
+            // Dictionaries that are no longer in use are kept if they came from this database
+            match trees.get_mut(tree) {
+                Some(set) if set.owner == owner => set.loaded = fresh.loaded,
+                _ => {
+                    trees.insert(tree.to_owned(), fresh);
+                }
+            }
+
+            handle
+        }
+    };
+
+    if let Some(set) = trees.get_mut(tree) {
+        for (id, dictionary) in loaded {
+            set.decoders
+                .insert(*id, Arc::new(DecoderDictionary::copy(dictionary)));
+
+            if !matches!(set.current, Some((current, _)) if current >= *id) {
+                set.current = Some((*id, Arc::from(&dictionary[..])));
+                set.encoders.clear();
+            }
+        }
+    }
+
+    Ok(handle)
+}
+
+/// The name of the companion tree holding a tree's dictionaries
+#[cfg(feature = "zstd")]
+fn dictionary_tree(tree: &str) -> String {
+    format!("{}-dictionaries", tree)
+}
+
+/// The token identifying the database a tree's dictionaries are stored in, created on first use
+#[cfg(feature = "zstd")]
+fn owner(dictionaries: &sled::Tree) -> Result<IVec> {
+    if let Some(owner) = dictionaries.get(OWNER)? {
+        return Ok(owner);
+    }
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(nanos);
+
+    let mut token = hasher.finish().to_be_bytes().to_vec();
+    token.extend_from_slice(&nanos.to_be_bytes());
+
+    match dictionaries.compare_and_swap(OWNER, None as Option<&[u8]>, Some(token.as_slice()))? {
+        Ok(()) => Ok(IVec::from(token)),
+        Err(e) => e
+            .current
+            .ok_or_else(|| Error::Corrupted("Missing dictionary owner".to_owned())),
+    }
+}
+
+/// Load every dictionary trained for a tree into a set
+#[cfg(feature = "zstd")]
+fn load_dictionaries<D>(db: &sled::Db, tree: &str) -> Result<Arc<LoadedDictionaries>>
+where
+    D: 'static,
+{
+    let dictionaries = db.open_tree(dictionary_tree(tree))?;
+    let owner = owner(&dictionaries)?;
+
+    let loaded = dictionaries
+        .range(FIRST_ID..)
+        .map(|res| {
+            let (id, dictionary) = res?;
+            Ok((dictionary_id(&id)?, dictionary))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    register::<D>(tree, owner, &loaded)
+}
+
+/// Train a dictionary from a sample of a tree's values, store it, and add it to a set
+///
+/// Values are sampled evenly across the tree, and are decompressed first if they were compressed
+/// with a dictionary from the same set.
+#[cfg(feature = "zstd")]
+pub(crate) fn train_dictionary<D>(
+    db: &sled::Db,
+    tree: &sled::Tree,
+    name: &str,
+    samples: usize,
+    max_size: usize,
+) -> Result<u32>
+where
+    D: 'static,
+{
+    let samples = samples.max(1);
+    let step = (tree.len() / samples).max(1);
+    let mut sample = Vec::with_capacity(samples);
+
+    for v in tree.iter().values().step_by(step).take(samples) {
+        let v = v?;
+
+        let raw = match v.split_first() {
+            Some((&UNCOMPRESSED, rest)) => rest.to_vec(),
+            Some((&DICTIONARY, rest)) => {
+                <ZstdDictionary<D> as Compression>::decompress_at(name, rest)
+                    .map_err(Error::Decompress)?
+            }
+            _ => v.to_vec(),
+        };
+
+        sample.push(raw);
+    }
+
+    let dictionary = zstd::dict::from_samples(&sample, max_size).map_err(Error::Compress)?;
+
+    let dictionaries = db.open_tree(dictionary_tree(name))?;
+    let owner = owner(&dictionaries)?;
+
+    // Another handle may be training at the same time, so the next id is claimed rather than
+    // overwritten
+    let id = loop {
+        let id = match dictionaries.range(FIRST_ID..).keys().next_back() {
+            Some(last) => dictionary_id(&last?)?
+                .checked_add(1)
+                .ok_or_else(|| Error::Corrupted(format!("Too many dictionaries for {}", name)))?,
+            None => 1,
+        };
+
+        let claimed = dictionaries.compare_and_swap(
+            id.to_be_bytes(),
+            None as Option<&[u8]>,
+            Some(dictionary.as_slice()),
+        )?;
+
+        if claimed.is_ok() {
+            break id;
+        }
+    };
+
+    register::<D>(name, owner, &[(id, IVec::from(dictionary))])?;
+
+    Ok(id)
+}
+
+#[cfg(feature = "zstd")]
+fn dictionary_id(key: &[u8]) -> Result<u32> {
+    match *key {
+        [a, b, c, d] => Ok(u32::from_be_bytes([a, b, c, d])),
+        _ => Err(Error::Corrupted(format!("Invalid dictionary id {:?}", key))),
+    }
+}
+
+#[cfg(feature = "lz4")]
+impl Compression for Lz4 {
+    const HEADER: u8 = LZ4;
+
+    fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(lz4_flex::compress_prepend_size(data))
+    }
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        lz4_flex::decompress_size_prepended(data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(feature = "snappy")]
+impl Compression for Snappy {
+    const HEADER: u8 = SNAPPY;
+
+    fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(snap::raw::Encoder::new().compress_vec(data)?)
+    }
+
+    fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(snap::raw::Decoder::new().decompress_vec(data)?)
+    }
+}
+
+#[cfg(all(test, any(feature = "lz4", feature = "snappy", feature = "zstd")))]
+mod tests {
+    use super::*;
+    use crate::encoding::PlainEncoding;
+
+    type Small<C> = Compressed<PlainEncoding, C, 16>;
+
+    fn encode<C>(value: &Vec<u8>) -> Result<Vec<u8>>
+    where
+        C: Compression,
+    {
+        Small::<C>::encode(value)
+    }
+
+    fn decode<C>(slice: &[u8]) -> Result<Vec<u8>>
+    where
+        C: Compression,
+    {
+        Small::<C>::decode(slice)
+    }
+
+    /// Check that values are compressed only when they're large enough and shrink
+    fn compresses_when_it_pays_off<C>()
+    where
+        C: Compression,
+    {
+        let short = b"short".to_vec();
+        let encoded = encode::<C>(&short).unwrap();
+        assert_eq!(encoded, with_header(UNCOMPRESSED, &short));
+        assert_eq!(decode::<C>(&encoded).unwrap(), short);
+
+        let repetitive = b"repeated ".repeat(100);
+        let encoded = encode::<C>(&repetitive).unwrap();
+        assert_eq!(encoded[0], C::HEADER);
+        assert!(encoded.len() < repetitive.len());
+        assert_eq!(decode::<C>(&encoded).unwrap(), repetitive);
+
+        // Bytes that don't repeat don't shrink, so they're kept as they are
+        let noise: Vec<u8> = (0..64u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let encoded = encode::<C>(&noise).unwrap();
+        assert_eq!(encoded, with_header(UNCOMPRESSED, &noise));
+
+        // Values written before the encoding was wrapped have no header
+        let legacy = b"{\"written\":\"before\"}".to_vec();
+        assert_eq!(decode::<C>(&legacy).unwrap(), legacy);
+
+        // A corrupt frame is an error rather than a value
+        let mut corrupt = encode::<C>(&repetitive).unwrap();
+        corrupt.truncate(corrupt.len() / 2);
+        match decode::<C>(&corrupt) {
+            Err(Error::Decompress(_)) => (),
+            _ => panic!("Expected a decompression error"),
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_compresses_when_it_pays_off() {
+        compresses_when_it_pays_off::<Lz4>();
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn snappy_compresses_when_it_pays_off() {
+        compresses_when_it_pays_off::<Snappy>();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_compresses_when_it_pays_off() {
+        compresses_when_it_pays_off::<Zstd>();
+        compresses_when_it_pays_off::<Zstd<19>>();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn each_algorithm_only_reads_its_own_header() {
+        let repetitive = b"repeated ".repeat(100);
+        let encoded = encode::<Zstd>(&repetitive).unwrap();
+
+        // Another algorithm knows the header isn't its own, so the value isn't mistaken for one
+        // written before compression was enabled
+        #[cfg(feature = "lz4")]
+        match decode::<Lz4>(&encoded) {
+            Err(Error::Decompress(_)) => (),
+            _ => panic!("Expected a decompression error"),
+        }
+
+        assert_eq!(decode::<Zstd<1>>(&encoded).unwrap(), repetitive);
+
+        for header in [LZ4, SNAPPY, DICTIONARY] {
+            match decode::<Zstd>(&with_header(header, &repetitive)) {
+                Err(Error::Decompress(_)) => (),
+                _ => panic!("Expected a decompression error"),
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    mod dictionaries {
+        use super::*;
+        use crate::{structured_tree::StructuredTree, Config};
+
+        type Tree<D> = StructuredTree<IVec, Compressed<PlainEncoding, ZstdDictionary<D>>>;
+
+        fn open<D>(db: &sled::Db, name: &str) -> Tree<D>
+        where
+            D: 'static,
+        {
+            Tree::<D>::new(db, name).unwrap()
+        }
+
+        fn fill<D>(tree: &Tree<D>, word: &str)
+        where
+            D: 'static,
+        {
+            for i in 0..200u32 {
+                let value = format!("{{\"{}\":{},\"{}s\":[{}]}}", word, i, word, i % 7).repeat(4);
+                tree.insert(&i.to_be_bytes()[..], IVec::from(value.as_bytes()))
+                    .unwrap();
+            }
+        }
+
+        /// The id of the dictionary a stored value was compressed with
+        fn stored_id<D>(tree: &Tree<D>, key: &[u8]) -> u32
+        where
+            D: 'static,
+        {
+            let stored = tree.sled_tree().get(key).unwrap().unwrap();
+            assert_eq!(stored[0], DICTIONARY);
+            dictionary_id(&stored[1..5]).unwrap()
+        }
+
+        #[test]
+        fn each_tree_has_its_own_dictionaries() {
+            struct Shared;
+
+            let db = Config::default().temporary(true).open().unwrap();
+            let users = open::<Shared>(&db, "users");
+            let posts = open::<Shared>(&db, "posts");
+            fill(&users, "user");
+            fill(&posts, "post");
+
+            assert_eq!(users.train_dictionary::<Shared>(100, 1024).unwrap(), 1);
+
+            let value = IVec::from(&b"{\"user\":1000,\"users\":[1]}".repeat(4)[..]);
+            users.insert(b"new", value.clone()).unwrap();
+            posts.insert(b"new", value.clone()).unwrap();
+
+            // Training one tree doesn't change how the other is compressed
+            assert_eq!(stored_id(&users, b"new"), 1);
+            assert_eq!(stored_id(&posts, b"new"), 0);
+
+            assert_eq!(posts.train_dictionary::<Shared>(100, 1024).unwrap(), 1);
+            posts.insert(b"newer", value.clone()).unwrap();
+            assert_eq!(stored_id(&posts, b"newer"), 1);
+
+            // Both trees read back values compressed with their own dictionary 1
+            assert_eq!(users.get(b"new").unwrap(), Some(value.clone()));
+            assert_eq!(posts.get(b"newer").unwrap(), Some(value));
+            assert!(users.get(&5u32.to_be_bytes()[..]).unwrap().is_some());
+        }
+
+        #[test]
+        fn concurrent_training_claims_distinct_ids() {
+            struct Concurrent;
+
+            let db = Config::default().temporary(true).open().unwrap();
+            fill(&open::<Concurrent>(&db, "docs"), "doc");
+
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    let tree = open::<Concurrent>(&db, "docs");
+                    std::thread::spawn(move || tree.train_dictionary::<Concurrent>(50, 1024))
+                })
+                .collect();
+
+            let mut ids: Vec<u32> = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap().unwrap())
+                .collect();
+            ids.sort();
+
+            assert_eq!(ids, vec![1, 2, 3, 4]);
+        }
+
+        #[test]
+        fn dictionaries_in_use_by_another_database_are_refused() {
+            struct Owned;
+
+            let first = Config::default().temporary(true).open().unwrap();
+            let second = Config::default().temporary(true).open().unwrap();
+
+            let tree = open::<Owned>(&first, "owned");
+            fill(&tree, "owned");
+            tree.train_dictionary::<Owned>(100, 1024).unwrap();
+
+            match Tree::<Owned>::new(&second, "owned") {
+                Err(Error::DictionariesInUse(ref name)) if name == "owned" => (),
+                _ => panic!("Expected the dictionaries to be in use"),
+            }
+
+            // Another handle on the same database shares them
+            let reopened = open::<Owned>(&first, "owned");
+            assert!(reopened.get(&5u32.to_be_bytes()[..]).unwrap().is_some());
+
+            // Once the first database's trees are closed, the second can load its own
+            drop(tree);
+            drop(reopened);
+
+            let tree = open::<Owned>(&second, "owned");
+            tree.insert(b"new", IVec::from(&b"owned ".repeat(20)[..]))
+                .unwrap();
+            assert_eq!(stored_id(&tree, b"new"), 0);
+        }
+
+        #[test]
+        fn closed_databases_dictionaries_are_never_used() {
+            struct Docs;
+
+            let first = Config::default().temporary(true).open().unwrap();
+            let tree = open::<Docs>(&first, "docs");
+            fill(&tree, "doc");
+            tree.train_dictionary::<Docs>(100, 1024).unwrap();
+
+            let value = IVec::from(&b"{\"doc\":1000,\"docs\":[1]}".repeat(4)[..]);
+            tree.insert(b"trained", value.clone()).unwrap();
+            assert_eq!(stored_id(&tree, b"trained"), 1);
+            let trained = tree.sled_tree().get(b"trained").unwrap().unwrap();
+            drop(tree);
+            drop(first);
+
+            // Nothing holds the first database's dictionaries, so they aren't written with
+            let encoded =
+                Compressed::<PlainEncoding, ZstdDictionary<Docs>>::encode_at("docs", b"k", &value)
+                    .unwrap();
+            assert_eq!(dictionary_id(&encoded[1..5]).unwrap(), 0);
+
+            let second = Config::default().temporary(true).open().unwrap();
+            let tree = open::<Docs>(&second, "docs");
+            fill(&tree, "note");
+            tree.insert(b"new", value.clone()).unwrap();
+            assert_eq!(stored_id(&tree, b"new"), 0);
+
+            // The second database's dictionary 1 can't decompress the first's
+            assert_eq!(tree.train_dictionary::<Docs>(100, 1024).unwrap(), 1);
+            tree.sled_tree().insert(b"copied", trained).unwrap();
+            match tree.get(b"copied") {
+                Err(Error::Decompress(_)) => (),
+                _ => panic!("Expected the other database's dictionary to be refused"),
+            }
+
+            drop(tree);
+            let tree = open::<Docs>(&second, "docs");
+            assert_eq!(tree.get(b"new").unwrap(), Some(value));
+        }
+    }
+}
@@ -114,6 +114,36 @@ pub trait DbExt {
         self.open_expiring_tree(name)
     }
 
+    #[cfg(feature = "postcard")]
+    /// Open a tree that stores it's values as postcard
+    fn open_postcard_tree<V>(&self, name: &str) -> Result<crate::postcard::Tree<V>>
+    where
+        V: DeserializeOwned + Serialize + 'static,
+    {
+        self.open_structured_tree(name)
+    }
+
+    #[cfg(feature = "postcard")]
+    /// Open an expiring tree that stores it's values as postcard
+    fn open_expiring_postcard_tree<V>(
+        &self,
+        name: &str,
+    ) -> crate::postcard::expiring::TreeBuilder<V>
+    where
+        V: DeserializeOwned + Serialize + 'static,
+    {
+        self.open_expiring_tree(name)
+    }
+
+    #[cfg(feature = "postcard")]
+    /// Open an expiring tree that stores it's metadata as postcard
+    fn open_expiring_plain_postcard_tree(
+        &self,
+        name: &str,
+    ) -> expiring::plain::TreeBuilder<crate::postcard::PostcardEncoding> {
+        self.open_expiring_tree(name)
+    }
+
     #[cfg(feature = "cbor")]
     /// Open a tree that stores it's values as cbor
     fn open_cbor_tree<V>(&self, name: &str) -> Result<crate::cbor::Tree<V>>
@@ -167,6 +197,33 @@ pub trait DbExt {
     ) -> expiring::plain::TreeBuilder<crate::json::JsonEncoding> {
         self.open_expiring_tree(name)
     }
+
+    #[cfg(feature = "msgpack")]
+    /// Open a tree that stores it's values as MessagePack
+    fn open_msgpack_tree<V>(&self, name: &str) -> Result<crate::msgpack::Tree<V>>
+    where
+        V: DeserializeOwned + Serialize + 'static,
+    {
+        self.open_structured_tree(name)
+    }
+
+    #[cfg(feature = "msgpack")]
+    /// Open an expiring tree that stores it's values as MessagePack
+    fn open_expiring_msgpack_tree<V>(&self, name: &str) -> crate::msgpack::expiring::TreeBuilder<V>
+    where
+        V: DeserializeOwned + Serialize + 'static,
+    {
+        self.open_expiring_tree(name)
+    }
+
+    #[cfg(feature = "msgpack")]
+    /// Open an expiring tree that stores it's metadata as MessagePack
+    fn open_expiring_plain_msgpack_tree(
+        &self,
+        name: &str,
+    ) -> expiring::plain::TreeBuilder<crate::msgpack::MsgPackEncoding> {
+        self.open_expiring_tree(name)
+    }
 }
 
 impl DbExt for sled::Db {
@@ -1,11 +1,43 @@
-#[cfg(any(feature = "bincode", feature = "cbor", feature = "json"))]
-use serde::{de::DeserializeOwned, ser::Serialize};
+#[cfg(any(
+    feature = "bincode",
+    feature = "cbor",
+    feature = "json",
+    feature = "msgpack",
+    feature = "postcard"
+))]
+use serde::{
+    de::{Deserialize, DeserializeOwned},
+    ser::Serialize,
+};
 
-#[cfg(any(feature = "bincode", feature = "cbor", feature = "json"))]
+#[cfg(any(
+    feature = "bincode",
+    feature = "cbor",
+    feature = "json",
+    feature = "msgpack",
+    feature = "postcard"
+))]
 use crate::error::Error;
 
+#[cfg(feature = "bincode")]
+use bincode::{
+    config::{
+        self, AllowTrailing, Bounded, FixintEncoding, Infinite, VarintEncoding, WithOtherEndian,
+        WithOtherIntEncoding, WithOtherLimit, WithOtherTrailing,
+    },
+    DefaultOptions, Options,
+};
+
+#[cfg(any(feature = "bincode", feature = "msgpack"))]
+use std::marker::PhantomData;
+
+use std::{any::Any, sync::Arc};
+
 use crate::error::Result;
 
+/// State an Encoding keeps loaded for a tree while handles to it are open
+pub type TreeState = Arc<dyn Any + Send + Sync>;
+
 /// The Encoding trait
 ///
 /// By implementing this trait, a custom data format can be used to store information in Sled
@@ -15,6 +47,38 @@ pub trait Encoding<T> {
 
     /// Decoding data from bytes
     fn decode(slice: &[u8]) -> Result<T>;
+
+    /// Encoding data to bytes that will be stored under the key in the named tree
+    ///
+    /// Encodings that bind data to where it is stored, such as `Encrypted`, override this. By
+    /// default the location is ignored.
+    fn encode_at(_tree: &str, _key: &[u8], t: &T) -> Result<Vec<u8>> {
+        Self::encode(t)
+    }
+
+    /// Decoding data from bytes stored under the key in the named tree
+    fn decode_at(_tree: &str, _key: &[u8], slice: &[u8]) -> Result<T> {
+        Self::decode(slice)
+    }
+
+    /// Load what is needed to store data in the named tree, whenever a handle to it is opened
+    ///
+    /// Encodings that keep state for each tree, such as `ZstdDictionary`, load it here. The state
+    /// is kept until every handle to the tree is dropped. By default nothing is loaded.
+    fn open(_db: &sled::Db, _tree: &str) -> Result<Vec<TreeState>> {
+        Ok(Vec::new())
+    }
+}
+
+/// An Encoding that can decode views borrowing from the stored bytes
+///
+/// Decoding a view avoids copying large strings and byte buffers out of the database. Serde-based
+/// encodings can borrow `&str` and `&[u8]` fields, though some formats can't borrow every value,
+/// such as Json strings containing escapes. `Cow` fields marked `#[serde(borrow)]` borrow when
+/// they can and copy when they can't.
+pub trait BorrowedEncoding<'a, T> {
+    /// Decoding a view of the data from bytes
+    fn decode_borrowed(slice: &'a [u8]) -> Result<T>;
 }
 
 #[derive(Clone, Debug, Default)]
@@ -26,7 +90,111 @@ pub struct PlainEncoding;
 /// An Encoding backed by bincode to store serde-compatible types
 ///
 /// Note that Bincode cannot store certain kinds of types, such as untagged enums
-pub struct BincodeEncoding;
+///
+/// By default values are stored the way `bincode::serialize` stores them, with fixed-width
+/// little-endian integers and no size limit. The type parameters choose how integers are stored,
+/// their byte order, and the largest value that will be encoded or decoded. Setting a limit guards
+/// against corrupt or hostile data claiming to hold huge collections.
+///
+/// Changing any of these for a tree that already holds data makes that data unreadable.
+///
+/// ```rust
+/// use sled_extensions::{
+///     bincode::{BigEndian, BincodeWith, Limit, Varint},
+///     structured, Config, DbExt,
+/// };
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let db = Config::default().temporary(true).open()?;
+/// type Compact = BincodeWith<Varint, BigEndian, Limit<1024>>;
+///
+/// let tree: structured::Tree<Vec<u64>, Compact> = db.open_structured_tree("compact")?;
+///
+/// tree.insert(b"small", vec![1, 2, 3])?;
+/// assert!(tree.insert(b"large", vec![u64::MAX; 1024]).is_err());
+/// # Ok(())
+/// # }
+/// ```
+pub struct BincodeWith<I = Fixint, B = LittleEndian, L = NoLimit>(PhantomData<(I, B, L)>);
+
+#[cfg(feature = "bincode")]
+/// An Encoding backed by bincode, storing values the way `bincode::serialize` does
+pub type BincodeEncoding = BincodeWith;
+
+#[cfg(feature = "bincode")]
+/// The bincode options every encoding starts from
+type BaseOptions = WithOtherTrailing<DefaultOptions, AllowTrailing>;
+
+#[cfg(feature = "bincode")]
+/// How a bincode encoding stores integers
+pub trait BincodeIntEncoding<O> {
+    /// The options with this integer encoding applied
+    type Options: Options;
+
+    /// Apply this integer encoding to a set of bincode options
+    fn apply(options: O) -> Self::Options;
+}
+
+#[cfg(feature = "bincode")]
+/// The byte order of a bincode encoding
+pub trait BincodeEndian<O> {
+    /// The options with this byte order applied
+    type Options: Options;
+
+    /// Apply this byte order to a set of bincode options
+    fn apply(options: O) -> Self::Options;
+}
+
+#[cfg(feature = "bincode")]
+/// The size limit of a bincode encoding
+pub trait BincodeLimit<O> {
+    /// The largest number of bytes a value may take, if any
+    const BYTES: Option<u64>;
+
+    /// The options with this limit applied
+    type Options: Options;
+
+    /// Apply this limit to a set of bincode options
+    fn apply(options: O) -> Self::Options;
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, Default)]
+/// Store integers with a fixed width
+pub struct Fixint;
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, Default)]
+/// Store integers with a variable width, so small integers take fewer bytes
+pub struct Varint;
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, Default)]
+/// Store integers in little-endian byte order
+pub struct LittleEndian;
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, Default)]
+/// Store integers in big-endian byte order
+pub struct BigEndian;
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, Default)]
+/// Encode and decode values of any size
+pub struct NoLimit;
+
+#[cfg(feature = "bincode")]
+#[derive(Clone, Debug, Default)]
+/// Refuse to encode or decode values larger than `BYTES` bytes
+pub struct Limit<const BYTES: u64>;
+
+#[cfg(feature = "postcard")]
+#[derive(Clone, Debug, Default)]
+/// An Encoding backed by postcard to store serde-compatible types
+///
+/// Postcard produces the smallest output of the available encodings, at the cost of not being
+/// self-describing. Like Bincode, it cannot store certain kinds of types, such as untagged enums
+pub struct PostcardEncoding;
 
 #[cfg(feature = "cbor")]
 #[derive(Clone, Debug, Default)]
@@ -38,6 +206,53 @@ pub struct CborEncoding;
 /// An Encoding backed by json to store serde-compatible types
 pub struct JsonEncoding;
 
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Debug, Default)]
+/// An Encoding backed by MessagePack to store serde-compatible types
+///
+/// By default structs are stored as maps keyed by field name, which other MessagePack readers can
+/// make sense of. `MsgPackEncoding<StructArray>` stores them as arrays of fields instead, which is
+/// more compact but relies on readers knowing the field order.
+///
+/// ```rust
+/// use sled_extensions::{msgpack::{MsgPackEncoding, StructArray}, structured, Config, DbExt};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let db = Config::default().temporary(true).open()?;
+/// let named = db.open_msgpack_tree::<(u32, String)>("named")?;
+/// let compact: structured::Tree<(u32, String), MsgPackEncoding<StructArray>> =
+///     db.open_structured_tree("compact")?;
+///
+/// compact.insert(b"key", (1, "one".to_owned()))?;
+/// assert_eq!(compact.get(b"key")?, Some((1, "one".to_owned())));
+/// # let _ = named;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MsgPackEncoding<M = StructMap>(PhantomData<M>);
+
+#[cfg(feature = "msgpack")]
+/// How a MessagePack encoding lays out structs
+pub trait MsgPackMode {
+    /// Serialize a value with this layout
+    fn serialize<T>(t: &T) -> std::result::Result<Vec<u8>, rmp_serde::encode::Error>
+    where
+        T: Serialize + ?Sized;
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Debug, Default)]
+/// Store structs as MessagePack maps keyed by field name
+pub struct StructMap;
+
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Debug, Default)]
+/// Store structs as MessagePack arrays of their fields, in declaration order
+///
+/// Views decoded with `BorrowedEncoding` have to declare every field, since fields are matched
+/// by position.
+pub struct StructArray;
+
 impl<T> Encoding<T> for PlainEncoding
 where
     T: AsRef<[u8]>,
@@ -52,17 +267,178 @@ where
     }
 }
 
+impl<'a> BorrowedEncoding<'a, &'a [u8]> for PlainEncoding {
+    fn decode_borrowed(slice: &'a [u8]) -> Result<&'a [u8]> {
+        Ok(slice)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<O> BincodeIntEncoding<O> for Fixint
+where
+    O: Options,
+{
+    type Options = WithOtherIntEncoding<O, FixintEncoding>;
+
+    fn apply(options: O) -> Self::Options {
+        options.with_fixint_encoding()
+    }
+}
+
 #[cfg(feature = "bincode")]
-impl<T> Encoding<T> for BincodeEncoding
+impl<O> BincodeIntEncoding<O> for Varint
+where
+    O: Options,
+{
+    type Options = WithOtherIntEncoding<O, VarintEncoding>;
+
+    fn apply(options: O) -> Self::Options {
+        options.with_varint_encoding()
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<O> BincodeEndian<O> for LittleEndian
+where
+    O: Options,
+{
+    type Options = WithOtherEndian<O, config::LittleEndian>;
+
+    fn apply(options: O) -> Self::Options {
+        options.with_little_endian()
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<O> BincodeEndian<O> for BigEndian
+where
+    O: Options,
+{
+    type Options = WithOtherEndian<O, config::BigEndian>;
+
+    fn apply(options: O) -> Self::Options {
+        options.with_big_endian()
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<O> BincodeLimit<O> for NoLimit
+where
+    O: Options,
+{
+    const BYTES: Option<u64> = None;
+
+    type Options = WithOtherLimit<O, Infinite>;
+
+    fn apply(options: O) -> Self::Options {
+        options.with_no_limit()
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<O, const BYTES: u64> BincodeLimit<O> for Limit<BYTES>
+where
+    O: Options,
+{
+    const BYTES: Option<u64> = Some(BYTES);
+
+    type Options = WithOtherLimit<O, Bounded>;
+
+    fn apply(options: O) -> Self::Options {
+        options.with_limit(BYTES)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<I, B, L> BincodeWith<I, B, L>
+where
+    I: BincodeIntEncoding<BaseOptions>,
+    B: BincodeEndian<I::Options>,
+    L: BincodeLimit<B::Options>,
+{
+    /// The bincode options for this encoding
+    ///
+    /// Trailing bytes are allowed, as they are by `bincode::serialize`, so the defaults read data
+    /// written before encodings were configurable.
+    fn options() -> L::Options {
+        L::apply(B::apply(I::apply(
+            DefaultOptions::new().allow_trailing_bytes(),
+        )))
+    }
+
+    /// Deserialize a value, refusing values larger than the limit
+    ///
+    /// bincode replaces the limit with `Infinite` when deserializing from a slice, so the slice's
+    /// length is checked against it here.
+    fn deserialize<'a, T>(slice: &'a [u8]) -> Result<T>
+    where
+        T: Deserialize<'a>,
+    {
+        if matches!(L::BYTES, Some(limit) if slice.len() as u64 > limit) {
+            return Err(Error::BincodeDeserialize(Box::new(
+                bincode::ErrorKind::SizeLimit,
+            )));
+        }
+
+        Self::options()
+            .deserialize(slice)
+            .map_err(Error::BincodeDeserialize)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T, I, B, L> Encoding<T> for BincodeWith<I, B, L>
+where
+    T: DeserializeOwned + Serialize + 'static,
+    I: BincodeIntEncoding<BaseOptions>,
+    B: BincodeEndian<I::Options>,
+    L: BincodeLimit<B::Options>,
+{
+    fn encode(t: &T) -> Result<Vec<u8>> {
+        Self::options()
+            .serialize(t)
+            .map_err(Error::BincodeSerialize)
+    }
+
+    fn decode(slice: &[u8]) -> Result<T> {
+        Self::deserialize(slice)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<'a, T, I, B, L> BorrowedEncoding<'a, T> for BincodeWith<I, B, L>
+where
+    T: Deserialize<'a>,
+    I: BincodeIntEncoding<BaseOptions>,
+    B: BincodeEndian<I::Options>,
+    L: BincodeLimit<B::Options>,
+{
+    fn decode_borrowed(slice: &'a [u8]) -> Result<T> {
+        Self::deserialize(slice)
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<T> Encoding<T> for PostcardEncoding
 where
     T: DeserializeOwned + Serialize + 'static,
 {
     fn encode(t: &T) -> Result<Vec<u8>> {
-        bincode::serialize(t).map_err(Error::BincodeSerialize)
+        postcard::to_stdvec(t).map_err(Error::PostcardSerialize)
     }
 
     fn decode(slice: &[u8]) -> Result<T> {
-        bincode::deserialize(slice).map_err(Error::BincodeDeserialize)
+        postcard::from_bytes(slice).map_err(Error::PostcardDeserialize)
+    }
+}
+
+#[cfg(feature = "postcard")]
+impl<'a, T> BorrowedEncoding<'a, T> for PostcardEncoding
+where
+    T: Deserialize<'a>,
+{
+    fn decode_borrowed(slice: &'a [u8]) -> Result<T> {
+        postcard::from_bytes(slice).map_err(Error::PostcardDeserialize)
     }
 }
 
@@ -80,6 +456,16 @@ where
     }
 }
 
+#[cfg(feature = "cbor")]
+impl<'a, T> BorrowedEncoding<'a, T> for CborEncoding
+where
+    T: Deserialize<'a>,
+{
+    fn decode_borrowed(slice: &'a [u8]) -> Result<T> {
+        serde_cbor::from_slice(slice).map_err(Error::CborDeserialize)
+    }
+}
+
 #[cfg(feature = "json")]
 impl<T> Encoding<T> for JsonEncoding
 where
@@ -93,3 +479,227 @@ where
         serde_json::from_slice(slice).map_err(Error::JsonDeserialize)
     }
 }
+
+#[cfg(feature = "json")]
+impl<'a, T> BorrowedEncoding<'a, T> for JsonEncoding
+where
+    T: Deserialize<'a>,
+{
+    fn decode_borrowed(slice: &'a [u8]) -> Result<T> {
+        serde_json::from_slice(slice).map_err(Error::JsonDeserialize)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl MsgPackMode for StructMap {
+    fn serialize<T>(t: &T) -> std::result::Result<Vec<u8>, rmp_serde::encode::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        rmp_serde::to_vec_named(t)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl MsgPackMode for StructArray {
+    fn serialize<T>(t: &T) -> std::result::Result<Vec<u8>, rmp_serde::encode::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        rmp_serde::to_vec(t)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<T, M> Encoding<T> for MsgPackEncoding<M>
+where
+    T: DeserializeOwned + Serialize + 'static,
+    M: MsgPackMode,
+{
+    fn encode(t: &T) -> Result<Vec<u8>> {
+        M::serialize(t).map_err(Error::MsgPackSerialize)
+    }
+
+    fn decode(slice: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(slice).map_err(Error::MsgPackDeserialize)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl<'a, T, M> BorrowedEncoding<'a, T> for MsgPackEncoding<M>
+where
+    T: Deserialize<'a>,
+{
+    fn decode_borrowed(slice: &'a [u8]) -> Result<T> {
+        rmp_serde::from_slice(slice).map_err(Error::MsgPackDeserialize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_views_are_the_stored_bytes() {
+        let stored = b"stored".to_vec();
+        let view: &[u8] = PlainEncoding::decode_borrowed(&stored).unwrap();
+
+        assert_eq!(view.as_ptr(), stored.as_ptr());
+        assert_eq!(view.len(), stored.len());
+    }
+
+    #[cfg(any(
+        feature = "bincode",
+        feature = "cbor",
+        feature = "json",
+        feature = "msgpack",
+        feature = "postcard"
+    ))]
+    mod serde_formats {
+        use super::*;
+        use serde_derive::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Post {
+            title: String,
+            body: Vec<u8>,
+        }
+
+        #[derive(Deserialize)]
+        struct PostTitle<'a> {
+            title: &'a str,
+        }
+
+        /// Round trip a post, and check that its title is borrowed from the encoded bytes
+        fn borrows_title<E>() -> Vec<u8>
+        where
+            E: Encoding<Post> + for<'a> BorrowedEncoding<'a, PostTitle<'a>>,
+        {
+            let post = Post {
+                title: "Hello".to_owned(),
+                body: vec![1, 2, 3],
+            };
+
+            let encoded = E::encode(&post).unwrap();
+            assert_eq!(E::decode(&encoded).unwrap(), post);
+
+            let view: PostTitle = E::decode_borrowed(&encoded).unwrap();
+            assert_eq!(view.title, "Hello");
+            assert!(encoded.as_ptr_range().contains(&view.title.as_ptr()));
+
+            encoded
+        }
+
+        #[cfg(feature = "bincode")]
+        #[test]
+        fn bincode_borrows() {
+            borrows_title::<BincodeEncoding>();
+        }
+
+        #[cfg(feature = "bincode")]
+        #[test]
+        fn bincode_defaults_read_serialized_values() {
+            let post = Post {
+                title: "Hello".to_owned(),
+                body: vec![1, 2, 3],
+            };
+            let serialized = bincode::serialize(&post).unwrap();
+
+            assert_eq!(BincodeEncoding::encode(&post).unwrap(), serialized);
+            assert_eq!(BincodeWith::<Fixint>::encode(&post).unwrap(), serialized);
+            let decoded: Post = BincodeEncoding::decode(&serialized).unwrap();
+            assert_eq!(decoded, post);
+        }
+
+        #[cfg(feature = "bincode")]
+        #[test]
+        fn bincode_options_change_the_layout() {
+            let value = 1u32;
+
+            assert_eq!(BincodeEncoding::encode(&value).unwrap(), vec![1, 0, 0, 0]);
+            assert_eq!(
+                BincodeWith::<Fixint, BigEndian>::encode(&value).unwrap(),
+                vec![0, 0, 0, 1]
+            );
+            assert_eq!(BincodeWith::<Varint>::encode(&value).unwrap(), vec![1]);
+        }
+
+        #[cfg(feature = "bincode")]
+        #[test]
+        fn bincode_limits_apply_when_encoding_and_decoding() {
+            type Limited = BincodeWith<Fixint, LittleEndian, Limit<64>>;
+
+            let small = vec![0u64; 4];
+            let large = vec![0u64; 64];
+
+            let encoded = Limited::encode(&small).unwrap();
+            let decoded: Vec<u64> = Limited::decode(&encoded).unwrap();
+            assert_eq!(decoded, small);
+            assert!(Limited::encode(&large).is_err());
+
+            // A stored value over the limit is refused before any of it is read
+            let stored = BincodeEncoding::encode(&large).unwrap();
+            let decoded: Result<Vec<u64>> = Limited::decode(&stored);
+            assert!(decoded.is_err());
+
+            // As is one whose length is corrupt, without allocating for the length it claims
+            let mut hostile = encoded;
+            hostile[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+            let decoded: Result<Vec<u64>> = Limited::decode(&hostile);
+            assert!(decoded.is_err());
+        }
+
+        #[cfg(feature = "cbor")]
+        #[test]
+        fn cbor_borrows() {
+            borrows_title::<CborEncoding>();
+        }
+
+        #[cfg(feature = "json")]
+        #[test]
+        fn json_borrows_strings_without_escapes() {
+            borrows_title::<JsonEncoding>();
+
+            let escaped = br#"{"title":"Hello\nthere","body":[]}"#;
+            let view: Result<PostTitle> = JsonEncoding::decode_borrowed(&escaped[..]);
+            assert!(view.is_err());
+        }
+
+        #[cfg(feature = "postcard")]
+        #[test]
+        fn postcard_is_the_most_compact() {
+            let compact = borrows_title::<PostcardEncoding>();
+            assert_eq!(compact, vec![5, b'H', b'e', b'l', b'l', b'o', 3, 1, 2, 3]);
+
+            // Lengths are varints, so a truncated value is rejected rather than read short
+            let truncated: Result<Post> = PostcardEncoding::decode(&compact[..8]);
+            assert!(truncated.is_err());
+        }
+
+        #[cfg(feature = "msgpack")]
+        #[test]
+        fn msgpack_maps_and_arrays_both_round_trip() {
+            let named = borrows_title::<MsgPackEncoding>();
+            let compact = MsgPackEncoding::<StructArray>::encode(&Post {
+                title: "Hello".to_owned(),
+                body: vec![1, 2, 3],
+            })
+            .unwrap();
+
+            // Views of arrays have to list every field, so this one can't skip the body
+            let view: Result<PostTitle> = MsgPackEncoding::<StructArray>::decode_borrowed(&compact);
+            assert!(view.is_err());
+
+            // Maps carry the field names, arrays only the values
+            assert!(named.windows(5).any(|w| w == b"title"));
+            assert!(!compact.windows(5).any(|w| w == b"title"));
+            assert!(compact.len() < named.len());
+
+            // Either layout can be read back by the other
+            let post: Post = MsgPackEncoding::<StructArray>::decode(&named).unwrap();
+            assert_eq!(post.title, "Hello");
+            let post: Post = MsgPackEncoding::<StructMap>::decode(&compact).unwrap();
+            assert_eq!(post.body, vec![1, 2, 3]);
+        }
+    }
+}
@@ -0,0 +1,663 @@
+use sled::IVec;
+use std::{marker::PhantomData, ops::Bound};
+
+#[cfg(feature = "chacha20poly1305")]
+use chacha20poly1305::aead;
+
+#[cfg(all(feature = "aes-gcm", not(feature = "chacha20poly1305")))]
+use aes_gcm::aead;
+
+use crate::{
+    encoding::{Encoding, TreeState},
+    error::{Error, Result},
+};
+
+const POSITION: &[u8] = b"position";
+
+/// Supplies the keys values are encrypted with
+///
+/// Encodings can't carry state, so keys are supplied through a type, usually one that reads them
+/// from the environment or a secret store. Each key has an id, which is stored with every value
+/// encrypted with it. Rotating keys means making a new key current while keeping the old ones
+/// available until nothing stored is encrypted with them, including covering index projections,
+/// view outputs and changelog records, which re-encryption doesn't rewrite.
+pub trait KeyProvider {
+    /// The id of the 256 bit key new values are encrypted with, and the key itself
+    fn current() -> Result<(u32, [u8; 32])>;
+
+    /// The 256 bit key with the given id, or `None` if it isn't known
+    fn key(id: u32) -> Result<Option<[u8; 32]>>;
+}
+
+/// An Encoding whose stored values can be moved to the current key without being decoded
+pub trait Rekey {
+    /// The id of the key values are currently encrypted with
+    fn current_key_id() -> Result<u32>;
+
+    /// Re-encrypt a value stored under the key in the named tree with the current key
+    ///
+    /// Returns `None` if the value is already encrypted with the current key.
+    fn rekey(tree: &str, key: &[u8], slice: &[u8]) -> Result<Option<Vec<u8>>>;
+}
+
+/// An authenticated cipher that can be used with `Encrypted`
+///
+/// By implementing this trait, a custom cipher can be used to store values.
+pub trait Cipher {
+    /// The header byte marking values encrypted with this cipher
+    const HEADER: u8;
+
+    /// Encrypt bytes with a fresh nonce, authenticating the associated data along with them
+    ///
+    /// The output must hold everything `decrypt` needs other than the key and associated data.
+    fn encrypt(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decrypt bytes produced by `encrypt`, failing if they or the associated data were altered
+    fn decrypt(key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[derive(Clone, Debug, Default)]
+/// An Encoding that encrypts the output of another Encoding
+///
+/// Each value is prefixed with a header byte naming the cipher and the id of the key it was
+/// encrypted with, followed by the nonce and the ciphertext. Values are always written with the
+/// current key, and can be read with any key the `KeyProvider` still knows. The name of the tree
+/// and the key a value is stored under are authenticated along with it, so a value copied to
+/// another key or tree can't be decrypted. Expiry metadata is bound to the metadata tree it is
+/// stored in, under the key it expires or the timestamp its keys expire at. Merge operands are
+/// encoded before they reach a tree, so they are bound to an empty location.
+///
+/// Only values are encrypted. Keys, and anything the companion trees derive from values, are
+/// stored in the clear: secondary index keys, the terms, positions and lengths of full-text
+/// indexes, the keys of views, counter prefixes and their counts, reference entries, and expiry
+/// timestamps. Covering projections and view outputs are encrypted, since they go through the
+/// tree's encoding.
+///
+/// Wrap `Compressed` in `Encrypted` rather than the other way around, since ciphertext doesn't
+/// compress.
+///
+/// ```rust
+/// use sled_extensions::{
+///     encryption::{ChaCha20Poly1305, Encrypted, KeyProvider},
+///     json::JsonEncoding,
+///     structured, Config, DbExt,
+/// };
+///
+/// struct SecretKey;
+///
+/// impl KeyProvider for SecretKey {
+///     fn current() -> sled_extensions::Result<(u32, [u8; 32])> {
+///         // Read the key from somewhere safe instead
+///         Ok((1, [7; 32]))
+///     }
+///
+///     fn key(id: u32) -> sled_extensions::Result<Option<[u8; 32]>> {
+///         Ok(Some([7; 32]).filter(|_| id == 1))
+///     }
+/// }
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let db = Config::default().temporary(true).open()?;
+/// let tree: structured::Tree<String, Encrypted<JsonEncoding, SecretKey, ChaCha20Poly1305>> =
+///     db.open_structured_tree("secrets")?;
+///
+/// tree.insert(b"password", "hunter2".to_owned())?;
+/// assert_eq!(tree.get(b"password")?, Some("hunter2".to_owned()));
+///
+/// // Moving the ciphertext to another key makes it unreadable
+/// let raw = db.open_tree("secrets")?;
+/// raw.insert(b"stolen", raw.get(b"password")?.unwrap())?;
+/// assert!(tree.get(b"stolen").is_err());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Encrypted<E, K, C>(PhantomData<(E, K, C)>);
+
+#[cfg(feature = "chacha20poly1305")]
+#[derive(Clone, Debug, Default)]
+/// Encryption with ChaCha20-Poly1305, which is fast without hardware support
+pub struct ChaCha20Poly1305;
+
+#[cfg(feature = "aes-gcm")]
+#[derive(Clone, Debug, Default)]
+/// Encryption with AES-256-GCM, which is fast on processors with AES instructions
+pub struct Aes256Gcm;
+
+impl<T, E, K, C> Encoding<T> for Encrypted<E, K, C>
+where
+    E: Encoding<T>,
+    K: KeyProvider,
+    C: Cipher,
+{
+    fn encode(t: &T) -> Result<Vec<u8>> {
+        Self::encode_at("", &[], t)
+    }
+
+    fn decode(slice: &[u8]) -> Result<T> {
+        Self::decode_at("", &[], slice)
+    }
+
+    fn encode_at(tree: &str, key: &[u8], t: &T) -> Result<Vec<u8>> {
+        let (id, secret) = K::current()?;
+        let encoded = E::encode_at(tree, key, t)?;
+
+        encrypt::<C>(id, &secret, &associated_data(tree, key), &encoded)
+    }
+
+    fn decode_at(tree: &str, key: &[u8], slice: &[u8]) -> Result<T> {
+        let (id, sealed) = split::<C>(key, slice)?;
+        let plaintext = C::decrypt(&known_key::<K>(id)?, &associated_data(tree, key), sealed)?;
+
+        E::decode_at(tree, key, &plaintext)
+    }
+
+    fn open(db: &sled::Db, tree: &str) -> Result<Vec<TreeState>> {
+        E::open(db, tree)
+    }
+}
+
+impl<E, K, C> Rekey for Encrypted<E, K, C>
+where
+    K: KeyProvider,
+    C: Cipher,
+{
+    fn current_key_id() -> Result<u32> {
+        Ok(K::current()?.0)
+    }
+
+    fn rekey(tree: &str, key: &[u8], slice: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (current, secret) = K::current()?;
+        let (id, sealed) = split::<C>(key, slice)?;
+
+        if id == current {
+            return Ok(None);
+        }
+
+        let aad = associated_data(tree, key);
+        let plaintext = C::decrypt(&known_key::<K>(id)?, &aad, sealed)?;
+
+        encrypt::<C>(current, &secret, &aad, &plaintext).map(Some)
+    }
+}
+
+/// Re-encrypt every value in the tree that isn't encrypted with the current key
+///
+/// Values are rewritten `chunk_size` at a time, and the last key of each chunk is stored along
+/// with the id of the current key, so an interrupted job resumes where it left off as long as the
+/// current key hasn't changed. Each value is swapped only if it hasn't been written since it was
+/// read, since any newer value is already encrypted with the current key. The data tree is
+/// swapped directly, bypassing hooks, so companion trees keep the keys they were written with.
+/// Returns the number of values that were rewritten.
+pub(crate) fn reencrypt<R>(
+    db: &sled::Db,
+    data: &sled::Tree,
+    name: &str,
+    chunk_size: usize,
+) -> Result<usize>
+where
+    R: Rekey,
+{
+    let progress = db.open_tree(format!("{}-reencryption", name))?;
+    let current = R::current_key_id()?.to_be_bytes();
+
+    let mut after = match progress.get(POSITION)? {
+        Some(v) if v.len() >= 4 && v[..4] == current => Some(IVec::from(&v[4..])),
+        _ => None,
+    };
+    let mut rewritten = 0;
+
+    loop {
+        let lower = match after {
+            Some(ref key) => Bound::Excluded(key.clone()),
+            None => Bound::Unbounded,
+        };
+
+        let chunk = data
+            .range((lower, Bound::Unbounded))
+            .take(chunk_size.max(1))
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        let last = match chunk.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+
+        for (key, v) in chunk {
+            if let Some(new) = R::rekey(name, &key, &v)? {
+                if data.compare_and_swap(&key, Some(&v), Some(new))?.is_ok() {
+                    rewritten += 1;
+                }
+            }
+        }
+
+        let mut position = current.to_vec();
+        position.extend_from_slice(&last);
+        progress.insert(POSITION, position)?;
+
+        after = Some(last);
+    }
+
+    progress.remove(POSITION)?;
+    Ok(rewritten)
+}
+
+/// Encrypt a value with the key with the given id, prefixing it with the header and key id
+fn encrypt<C>(id: u32, secret: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>>
+where
+    C: Cipher,
+{
+    let ciphertext = C::encrypt(secret, aad, plaintext)?;
+
+    let mut out = Vec::with_capacity(ciphertext.len() + 5);
+    out.push(C::HEADER);
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Split a stored value into the id of the key it was encrypted with and the ciphertext
+fn split<'a, C>(key: &[u8], slice: &'a [u8]) -> Result<(u32, &'a [u8])>
+where
+    C: Cipher,
+{
+    match slice.split_first() {
+        Some((&header, rest)) if header == C::HEADER && rest.len() >= 4 => {
+            let mut id = [0; 4];
+            id.copy_from_slice(&rest[..4]);
+            Ok((u32::from_be_bytes(id), &rest[4..]))
+        }
+        _ => Err(Error::Decrypt(format!("unknown cipher for key {:?}", key))),
+    }
+}
+
+fn known_key<K>(id: u32) -> Result<[u8; 32]>
+where
+    K: KeyProvider,
+{
+    K::key(id)?.ok_or_else(|| Error::Decrypt(format!("unknown key id {}", id)))
+}
+
+/// The location a value is bound to, with the tree name length-prefixed so that no two
+/// locations share the same bytes
+fn associated_data(tree: &str, key: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + tree.len() + key.len());
+    out.extend_from_slice(&(tree.len() as u32).to_be_bytes());
+    out.extend_from_slice(tree.as_bytes());
+    out.extend_from_slice(key);
+    out
+}
+
+#[cfg(feature = "chacha20poly1305")]
+impl Cipher for ChaCha20Poly1305 {
+    const HEADER: u8 = 0xEC;
+
+    fn encrypt(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        seal::<chacha20poly1305::ChaCha20Poly1305>(key, aad, plaintext)
+    }
+
+    fn decrypt(key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        open::<chacha20poly1305::ChaCha20Poly1305>(key, aad, ciphertext)
+    }
+}
+
+#[cfg(feature = "aes-gcm")]
+impl Cipher for Aes256Gcm {
+    const HEADER: u8 = 0xEA;
+
+    fn encrypt(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+        seal::<aes_gcm::Aes256Gcm>(key, aad, plaintext)
+    }
+
+    fn decrypt(key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>> {
+        open::<aes_gcm::Aes256Gcm>(key, aad, ciphertext)
+    }
+}
+
+/// Encrypt with a random nonce, returning the nonce followed by the ciphertext
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+fn seal<A>(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>>
+where
+    A: aead::Aead + aead::AeadCore + aead::KeyInit,
+{
+    let cipher = A::new_from_slice(key).map_err(|_| Error::Encrypt("invalid key".to_owned()))?;
+    let nonce = A::generate_nonce(aead::OsRng);
+
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            aead::Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| Error::Encrypt("the cipher failed".to_owned()))?;
+
+    let mut out = Vec::with_capacity(nonce.len() + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Split off the nonce and decrypt the rest
+#[cfg(any(feature = "aes-gcm", feature = "chacha20poly1305"))]
+fn open<A>(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>>
+where
+    A: aead::Aead + aead::AeadCore + aead::KeyInit,
+{
+    use aead::generic_array::typenum::Unsigned;
+
+    let nonce_len = A::NonceSize::USIZE;
+
+    if sealed.len() < nonce_len {
+        return Err(Error::Decrypt("the value is truncated".to_owned()));
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(nonce_len);
+    let cipher = A::new_from_slice(key).map_err(|_| Error::Decrypt("invalid key".to_owned()))?;
+
+    cipher
+        .decrypt(
+            aead::Nonce::<A>::from_slice(nonce),
+            aead::Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| {
+            Error::Decrypt("the value was altered, moved, or encrypted with another key".to_owned())
+        })
+}
+
+#[cfg(all(test, feature = "chacha20poly1305", feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::{
+        encoding::{JsonEncoding, PlainEncoding},
+        index::IndexKey,
+        structured_tree::StructuredTree,
+        Config, DbExt,
+    };
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    struct TestKey;
+
+    impl KeyProvider for TestKey {
+        fn current() -> Result<(u32, [u8; 32])> {
+            Ok((1, [7; 32]))
+        }
+
+        fn key(id: u32) -> Result<Option<[u8; 32]>> {
+            Ok(Some([7; 32]).filter(|_| id == 1))
+        }
+    }
+
+    type Secret = Encrypted<JsonEncoding, TestKey, ChaCha20Poly1305>;
+    type Tree = StructuredTree<String, Secret>;
+
+    static CURRENT: [AtomicU32; 5] = [const { AtomicU32::new(1) }; 5];
+    static OLDEST: [AtomicU32; 5] = [const { AtomicU32::new(1) }; 5];
+
+    /// Keys that each test can rotate and retire on its own
+    struct Rotating<const N: usize>;
+
+    impl<const N: usize> KeyProvider for Rotating<N> {
+        fn current() -> Result<(u32, [u8; 32])> {
+            let id = CURRENT[N].load(Ordering::SeqCst);
+            Ok((id, [id as u8; 32]))
+        }
+
+        fn key(id: u32) -> Result<Option<[u8; 32]>> {
+            let known = OLDEST[N].load(Ordering::SeqCst)..=CURRENT[N].load(Ordering::SeqCst);
+            Ok(Some([id as u8; 32]).filter(|_| known.contains(&id)))
+        }
+    }
+
+    fn rotate<const N: usize>() {
+        CURRENT[N].fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn retire<const N: usize>() {
+        OLDEST[N].store(CURRENT[N].load(Ordering::SeqCst), Ordering::SeqCst);
+    }
+
+    type Numbers<const N: usize> = Encrypted<JsonEncoding, Rotating<N>, ChaCha20Poly1305>;
+
+    /// The id of the key a stored value was encrypted with
+    fn key_id(stored: &[u8]) -> u32 {
+        split::<ChaCha20Poly1305>(&[], stored).unwrap().0
+    }
+
+    /// A tree holding the numbers 0 to 9, each encrypted with the current key
+    fn numbers<const N: usize>(db: &sled::Db) -> StructuredTree<u32, Numbers<N>> {
+        let tree = StructuredTree::new(db, "numbers").unwrap();
+
+        for i in 0..10u32 {
+            tree.insert(&i.to_be_bytes()[..], i).unwrap();
+        }
+
+        tree
+    }
+
+    /// The ids of the keys each stored value was encrypted with, in key order
+    fn key_ids<const N: usize>(tree: &StructuredTree<u32, Numbers<N>>) -> Vec<u32> {
+        tree.sled_tree()
+            .iter()
+            .values()
+            .map(|res| key_id(&res.unwrap()))
+            .collect()
+    }
+
+    /// Store how far an earlier job got, as the id of the key it was moving values to and the
+    /// last key it handled
+    fn seed_position(db: &sled::Db, key_id: u32, last: u32) {
+        let mut position = key_id.to_be_bytes().to_vec();
+        position.extend_from_slice(&last.to_be_bytes());
+
+        db.open_tree("numbers-reencryption")
+            .unwrap()
+            .insert(POSITION, position)
+            .unwrap();
+    }
+
+    #[test]
+    fn values_moved_to_another_key_or_tree_fail_to_decrypt() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let secrets = Tree::new(&db, "secrets").unwrap();
+        let others = Tree::new(&db, "others").unwrap();
+
+        secrets.insert(b"password", "hunter2".to_owned()).unwrap();
+        let stored = secrets.sled_tree().get(b"password").unwrap().unwrap();
+
+        secrets
+            .sled_tree()
+            .insert(b"stolen", stored.clone())
+            .unwrap();
+        others.sled_tree().insert(b"password", stored).unwrap();
+
+        match secrets.get(b"stolen") {
+            Err(Error::Decrypt(_)) => (),
+            _ => panic!("Expected a value moved to another key to fail"),
+        }
+        match others.get(b"password") {
+            Err(Error::Decrypt(_)) => (),
+            _ => panic!("Expected a value moved to another tree to fail"),
+        }
+
+        assert_eq!(
+            secrets.get(b"password").unwrap(),
+            Some("hunter2".to_owned())
+        );
+    }
+
+    #[test]
+    fn expiry_metadata_is_bound_to_where_it_is_stored() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = db
+            .open_expiring_tree::<String, Secret, JsonEncoding>("sessions")
+            .extend_on_fetch()
+            .expiration_length(chrono::Duration::milliseconds(-1))
+            .build()
+            .unwrap();
+
+        tree.insert(b"a", "one".to_owned()).unwrap();
+        tree.insert(b"b", "two".to_owned()).unwrap();
+
+        // Extending many keys at once writes metadata that single writes read back
+        tree.get_many([b"a", b"b"]).unwrap();
+        tree.insert(b"a", "uno".to_owned()).unwrap();
+
+        let mut expired: Vec<IVec> = tree.expired().collect();
+        expired.sort();
+        assert_eq!(expired, vec![IVec::from(b"a"), IVec::from(b"b")]);
+
+        // The expiration of one key can't be passed off as another's
+        let metadata = db.open_tree("sessions-expires-at").unwrap();
+        let stored = metadata.get(b"a").unwrap().unwrap();
+        metadata.insert(b"b", stored).unwrap();
+        assert!(tree.remove(b"b").is_err());
+    }
+
+    #[test]
+    fn reencryption_moves_every_value_to_the_current_key() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree: StructuredTree<u32, Encrypted<JsonEncoding, Rotating<0>, ChaCha20Poly1305>> =
+            StructuredTree::new(&db, "numbers").unwrap();
+
+        for i in 0..10u32 {
+            tree.insert(&i.to_be_bytes()[..], i).unwrap();
+        }
+
+        rotate::<0>();
+        tree.insert(&0u32.to_be_bytes()[..], 0).unwrap();
+
+        assert_eq!(tree.reencrypt(3).unwrap(), 9);
+        assert_eq!(tree.reencrypt(3).unwrap(), 0);
+
+        for res in tree.sled_tree().iter().values() {
+            assert_eq!(key_id(&res.unwrap()), 2);
+        }
+
+        retire::<0>();
+        for i in 0..10u32 {
+            assert_eq!(tree.get(&i.to_be_bytes()[..]).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn reencryption_resumes_after_the_stored_position() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = numbers::<2>(&db);
+
+        rotate::<2>();
+        seed_position(&db, 2, 4);
+
+        assert_eq!(tree.reencrypt(3).unwrap(), 5);
+        assert_eq!(key_ids(&tree), vec![1, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+
+        // The position is cleared once the job finishes, so the next one starts over
+        assert!(db
+            .open_tree("numbers-reencryption")
+            .unwrap()
+            .get(POSITION)
+            .unwrap()
+            .is_none());
+        assert_eq!(tree.reencrypt(3).unwrap(), 5);
+        assert_eq!(key_ids(&tree), vec![2; 10]);
+    }
+
+    #[test]
+    fn reencryption_ignores_positions_stored_for_another_key() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = numbers::<3>(&db);
+
+        // The job was moving values to key 2 when key 3 became current
+        rotate::<3>();
+        seed_position(&db, 2, 4);
+        rotate::<3>();
+
+        assert_eq!(tree.reencrypt(3).unwrap(), 10);
+        assert_eq!(key_ids(&tree), vec![3; 10]);
+    }
+
+    thread_local! {
+        static RACING: std::cell::RefCell<Option<sled::Tree>> = const {
+            std::cell::RefCell::new(None)
+        };
+    }
+
+    /// Rekeys like `Numbers<4>`, but writes a new value for the key 3 after it has been read
+    struct Racing;
+
+    impl Rekey for Racing {
+        fn current_key_id() -> Result<u32> {
+            Numbers::<4>::current_key_id()
+        }
+
+        fn rekey(tree: &str, key: &[u8], slice: &[u8]) -> Result<Option<Vec<u8>>> {
+            if key == 3u32.to_be_bytes() {
+                let newer = <Numbers<4> as Encoding<u32>>::encode_at(tree, key, &33)?;
+                RACING.with(|data| data.borrow().as_ref().unwrap().insert(key, newer))?;
+            }
+
+            Numbers::<4>::rekey(tree, key, slice)
+        }
+    }
+
+    #[test]
+    fn reencryption_keeps_values_written_while_it_runs() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = numbers::<4>(&db);
+        RACING.with(|data| *data.borrow_mut() = Some(tree.sled_tree().clone()));
+
+        rotate::<4>();
+
+        // The swap for the key 3 fails, since it no longer holds the value that was read
+        assert_eq!(
+            reencrypt::<Racing>(&db, tree.sled_tree(), "numbers", 4).unwrap(),
+            9
+        );
+        assert_eq!(key_ids(&tree), vec![2; 10]);
+        assert_eq!(tree.get(&3u32.to_be_bytes()[..]).unwrap(), Some(33));
+    }
+
+    #[test]
+    fn reencryption_leaves_covering_projections_on_their_key() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree: StructuredTree<IVec, Encrypted<PlainEncoding, Rotating<1>, ChaCha20Poly1305>> =
+            StructuredTree::new(&db, "documents")
+                .unwrap()
+                .with_covering_index(
+                    "first-byte",
+                    |value: &IVec| vec![IndexKey::from(&value[..1])],
+                    |value: &IVec| value.clone(),
+                )
+                .unwrap();
+
+        tree.insert(b"doc", IVec::from(b"abc")).unwrap();
+
+        rotate::<1>();
+        assert_eq!(tree.reencrypt(10).unwrap(), 1);
+
+        let projections = |tree: &StructuredTree<IVec, _>| -> Result<Vec<(IVec, IVec)>> {
+            tree.scan_prefix_covering("first-byte", IndexKey::from(&b"a"[..]))?
+                .collect()
+        };
+
+        // The projection was written with the old key, so it still needs it
+        assert_eq!(
+            projections(&tree).unwrap(),
+            vec![(IVec::from(b"doc"), IVec::from(b"abc"))]
+        );
+
+        retire::<1>();
+        assert_eq!(tree.get(b"doc").unwrap(), Some(IVec::from(b"abc")));
+        match projections(&tree) {
+            Err(Error::Decrypt(_)) => (),
+            _ => panic!("Expected the projection to need the retired key"),
+        }
+
+        // Writing the value again re-encrypts its projection
+        tree.insert(b"doc", IVec::from(b"abc")).unwrap();
+        assert!(projections(&tree).is_ok());
+    }
+}
@@ -9,6 +9,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// - `json` -- `JsonSerialize` | `JsonDeserialize`
 /// - `cbor` -- `CborSerialize` | `CborDeserialize`
 /// - `bincode` -- `BincodeSerialize` | `BincodeDeserialize`
+/// - `msgpack` -- `MsgPackSerialize` | `MsgPackDeserialize`
+/// - `postcard` -- `PostcardSerialize` | `PostcardDeserialize`
 ///
 #[derive(Debug)]
 pub enum Error {
@@ -34,6 +36,77 @@ pub enum Error {
     /// Bincode Deserialization error
     BincodeDeserialize(bincode::Error),
 
+    #[cfg(feature = "msgpack")]
+    /// MessagePack Serialization error
+    MsgPackSerialize(rmp_serde::encode::Error),
+    #[cfg(feature = "msgpack")]
+    /// MessagePack Deserialization error
+    MsgPackDeserialize(rmp_serde::decode::Error),
+
+    #[cfg(feature = "postcard")]
+    /// Postcard Serialization error
+    PostcardSerialize(postcard::Error),
+    #[cfg(feature = "postcard")]
+    /// Postcard Deserialization error
+    PostcardDeserialize(postcard::Error),
+
+    /// A value could not be compressed
+    Compress(std::io::Error),
+
+    /// A value could not be decompressed
+    Decompress(std::io::Error),
+
+    /// A value could not be encrypted
+    Encrypt(String),
+
+    /// A value could not be decrypted
+    Decrypt(String),
+
+    /// Data stored by this crate could not be read back
+    Corrupted(String),
+
+    /// A query named an index that was not registered on the tree
+    UnknownIndex(String),
+
+    /// A query named an index that is still being backfilled
+    IndexNotReady(String),
+
+    /// A write would have given two values the same key in a unique index
+    UniqueViolation {
+        /// The name of the unique index
+        index: String,
+        /// The primary key of the value that already holds the index key
+        key: sled::IVec,
+    },
+
+    /// A write referenced a key that doesn't exist in the referenced tree
+    MissingReference {
+        /// The name of the reference
+        reference: String,
+        /// The referenced key that is missing
+        key: sled::IVec,
+    },
+
+    /// A removal was refused because other values still reference the removed key
+    RestrictedDelete {
+        /// The name of the reference
+        reference: String,
+        /// The key of a value that references the removed key
+        key: sled::IVec,
+    },
+
+    /// The named counter has not been registered on this tree
+    UnknownCounter(String),
+
+    /// The named materialized view has not been registered on this tree
+    UnknownView(String),
+
+    /// The named tree's zstd dictionaries are in use by another database
+    DictionariesInUse(String),
+
+    /// A pagination cursor token could not be decoded
+    InvalidCursor(String),
+
     /// Custom errors provided by users of this crate
     Custom(Box<dyn StdError + Send + Sync>),
     /// Errors in the Sled database
@@ -90,6 +163,60 @@ impl fmt::Display for Error {
                 write!(f, "There was an error deserializing data, {}", e)
             }
 
+            #[cfg(feature = "msgpack")]
+            Error::MsgPackSerialize(ref e) => {
+                write!(f, "There was an error serializing data, {}", e)
+            }
+            #[cfg(feature = "msgpack")]
+            Error::MsgPackDeserialize(ref e) => {
+                write!(f, "There was an error deserializing data, {}", e)
+            }
+
+            #[cfg(feature = "postcard")]
+            Error::PostcardSerialize(ref e) => {
+                write!(f, "There was an error serializing data, {}", e)
+            }
+            #[cfg(feature = "postcard")]
+            Error::PostcardDeserialize(ref e) => {
+                write!(f, "There was an error deserializing data, {}", e)
+            }
+
+            Error::Compress(ref e) => write!(f, "There was an error compressing data, {}", e),
+            Error::Decompress(ref e) => write!(f, "There was an error decompressing data, {}", e),
+            Error::Encrypt(ref s) => write!(f, "There was an error encrypting data, {}", s),
+            Error::Decrypt(ref s) => write!(f, "There was an error decrypting data, {}", s),
+            Error::Corrupted(ref s) => write!(f, "Stored data is corrupted, {}", s),
+            Error::UnknownIndex(ref s) => write!(f, "There is no index named {}", s),
+            Error::IndexNotReady(ref s) => write!(f, "The index {} is still being built", s),
+            Error::UniqueViolation { ref index, ref key } => write!(
+                f,
+                "The value conflicts with key {:?} in unique index {}",
+                key, index
+            ),
+            Error::MissingReference {
+                ref reference,
+                ref key,
+            } => write!(
+                f,
+                "The referenced key {:?} does not exist for reference {}",
+                key, reference
+            ),
+            Error::RestrictedDelete {
+                ref reference,
+                ref key,
+            } => write!(
+                f,
+                "The value is still referenced by key {:?} through reference {}",
+                key, reference
+            ),
+            Error::UnknownCounter(ref s) => write!(f, "There is no counter named {}", s),
+            Error::UnknownView(ref s) => write!(f, "There is no view named {}", s),
+            Error::DictionariesInUse(ref s) => write!(
+                f,
+                "The dictionaries for {} are in use by another database",
+                s
+            ),
+            Error::InvalidCursor(ref s) => write!(f, "The cursor {} is invalid", s),
             Error::Custom(ref e) => write!(f, "There was a custom error, {}", e),
             Error::Sled(ref e) => write!(f, "There was an error in the database, {}", e),
         }
@@ -114,6 +241,30 @@ impl StdError for Error {
             #[cfg(feature = "bincode")]
             Error::BincodeDeserialize(ref e) => e.description(),
 
+            #[cfg(feature = "msgpack")]
+            Error::MsgPackSerialize(_) => "There was an error serializing data",
+            #[cfg(feature = "msgpack")]
+            Error::MsgPackDeserialize(_) => "There was an error deserializing data",
+
+            #[cfg(feature = "postcard")]
+            Error::PostcardSerialize(_) => "There was an error serializing data",
+            #[cfg(feature = "postcard")]
+            Error::PostcardDeserialize(_) => "There was an error deserializing data",
+
+            Error::Compress(_) => "There was an error compressing data",
+            Error::Decompress(_) => "There was an error decompressing data",
+            Error::Encrypt(_) => "There was an error encrypting data",
+            Error::Decrypt(_) => "There was an error decrypting data",
+            Error::Corrupted(_) => "Stored data is corrupted",
+            Error::UnknownIndex(_) => "There is no index with that name",
+            Error::IndexNotReady(_) => "The index is still being built",
+            Error::UniqueViolation { .. } => "The value conflicts with a unique index",
+            Error::MissingReference { .. } => "The referenced key does not exist",
+            Error::RestrictedDelete { .. } => "The value is still referenced",
+            Error::UnknownCounter(_) => "There is no counter with that name",
+            Error::UnknownView(_) => "There is no view with that name",
+            Error::DictionariesInUse(_) => "The dictionaries are in use by another database",
+            Error::InvalidCursor(_) => "The cursor is invalid",
             Error::Custom(ref e) => e.description(),
             Error::Sled(ref e) => e.description(),
         }
@@ -122,7 +273,20 @@ impl StdError for Error {
     fn cause(&self) -> Option<&dyn StdError> {
         match *self {
             Error::Sled(ref e) => Some(e),
-            Error::Custom(_) => None,
+            Error::Compress(ref e) | Error::Decompress(ref e) => Some(e),
+            Error::Encrypt(_)
+            | Error::Decrypt(_)
+            | Error::Corrupted(_)
+            | Error::UnknownIndex(_)
+            | Error::IndexNotReady(_)
+            | Error::UniqueViolation { .. }
+            | Error::MissingReference { .. }
+            | Error::RestrictedDelete { .. }
+            | Error::UnknownCounter(_)
+            | Error::UnknownView(_)
+            | Error::DictionariesInUse(_)
+            | Error::InvalidCursor(_)
+            | Error::Custom(_) => None,
 
             #[cfg(feature = "bincode")]
             Error::BincodeSerialize(ref e) | Error::BincodeDeserialize(ref e) => Some(e),
@@ -132,6 +296,14 @@ impl StdError for Error {
 
             #[cfg(feature = "cbor")]
             Error::CborSerialize(ref e) | Error::CborDeserialize(ref e) => Some(e),
+
+            #[cfg(feature = "msgpack")]
+            Error::MsgPackSerialize(ref e) => Some(e),
+            #[cfg(feature = "msgpack")]
+            Error::MsgPackDeserialize(ref e) => Some(e),
+
+            #[cfg(feature = "postcard")]
+            Error::PostcardSerialize(ref e) | Error::PostcardDeserialize(ref e) => Some(e),
         }
     }
 }
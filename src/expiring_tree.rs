@@ -1,15 +1,20 @@
 use chrono::{offset::Utc, DateTime};
 use log::debug;
-use sled::IVec;
-use std::{collections::HashSet, marker::PhantomData};
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::{
+    collections::{BTreeMap, HashSet},
+    marker::PhantomData,
+};
 
 use crate::{
+    bulk::BulkLoader,
     encoding::Encoding,
     error::Result,
     structured_tree::{
-        CompareAndSwapError, StructuredBatch, StructuredIter, StructuredTransactionalTree,
-        StructuredTree,
+        CompareAndSwapError, StructuredBatch, StructuredEntry, StructuredIter,
+        StructuredTransactionalTree, StructuredTree,
     },
+    transaction::atomically,
 };
 
 #[derive(Clone)]
@@ -153,6 +158,48 @@ where
         Ok(opt)
     }
 
+    /// Retrieve the values for many keys at once, in the order the keys were given.
+    ///
+    /// When values are extended on fetch, the expiration of every key that was found is extended
+    /// in a single transaction.
+    pub fn get_many<K, I>(&self, keys: I) -> Result<Vec<Option<V>>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let keys: Vec<IVec> = keys.into_iter().map(|key| key.as_ref().into()).collect();
+        let values = self.data.get_many(&keys)?;
+
+        if self.extend_on_fetch {
+            let found: Vec<IVec> = keys
+                .into_iter()
+                .zip(&values)
+                .filter(|(_, value)| value.is_some())
+                .map(|(key, _)| key)
+                .collect();
+
+            if !found.is_empty() {
+                self.update_many_expires_at(&found, Utc::now())?;
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Load large numbers of values into this tree
+    ///
+    /// When values are extended on update, the expiration of every value in a batch is recorded
+    /// in a single transaction once the batch has been written.
+    pub fn bulk_loader(&self) -> BulkLoader<'_, V, F> {
+        let loader = self.data.bulk_loader();
+
+        if !self.extend_on_update {
+            return loader;
+        }
+
+        loader.after_batch(move |keys| self.update_many_expires_at(keys, Utc::now()))
+    }
+
     /// Insert a key to a new value, returning the last value if it was set.
     pub fn insert<K>(&self, key: K, value: V) -> Result<Option<V>>
     where
@@ -391,6 +438,15 @@ where
         Ok(())
     }
 
+    /// Extend the expiration of a key read through an iterator, if values are extended on fetch
+    fn fetched(&self, key: IVec) -> Result<IVec> {
+        if self.extend_on_fetch {
+            self.update_expires_at(key.clone(), Utc::now())?;
+        }
+
+        Ok(key)
+    }
+
     fn update_expires_at(&self, key: IVec, now: DateTime<Utc>) -> Result<()> {
         let expires_at = now + self.expiration_length;
 
@@ -417,6 +473,103 @@ where
 
         Ok(())
     }
+
+    /// Extend the expiration of many keys at once
+    ///
+    /// Every key is given the same expiration, so the inverse index is read and written once per
+    /// timestamp rather than once per key.
+    fn update_many_expires_at(&self, keys: &[IVec], now: DateTime<Utc>) -> Result<()> {
+        let expires_at = now + self.expiration_length;
+        let stamp = expires_at.to_string().into_bytes();
+        let expires_at_name = self.expires_at.name();
+        let inverse_name = self.expires_at_inverse.name();
+
+        let trees = [
+            self.expires_at.sled_tree().clone(),
+            self.expires_at_inverse.sled_tree().clone(),
+        ];
+
+        atomically(&trees, |views| {
+            let mut sets = BTreeMap::new();
+
+            for key in keys {
+                let encoded = match <E as Encoding<DateTime<Utc>>>::encode_at(
+                    &expires_at_name,
+                    key,
+                    &expires_at,
+                ) {
+                    Ok(encoded) => encoded,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                let prev = match views[0].insert(key.as_ref(), encoded)? {
+                    Some(prev) => prev,
+                    None => continue,
+                };
+
+                let prev =
+                    match <E as Encoding<DateTime<Utc>>>::decode_at(&expires_at_name, key, &prev) {
+                        Ok(prev) => prev.to_string().into_bytes(),
+                        Err(e) => return Ok(Err(e)),
+                    };
+
+                if let Err(e) = Self::load_set(&views[1], &inverse_name, &mut sets, &prev)? {
+                    return Ok(Err(e));
+                }
+
+                if let Some(set) = sets.get_mut(&prev) {
+                    set.remove(key);
+                }
+            }
+
+            if let Err(e) = Self::load_set(&views[1], &inverse_name, &mut sets, &stamp)? {
+                return Ok(Err(e));
+            }
+
+            if let Some(set) = sets.get_mut(&stamp) {
+                set.extend(keys.iter().cloned());
+            }
+
+            for (stamp, set) in sets {
+                if set.is_empty() {
+                    views[1].remove(stamp)?;
+                    continue;
+                }
+
+                match <E as Encoding<HashSet<IVec>>>::encode_at(&inverse_name, &stamp, &set) {
+                    Ok(v) => {
+                        views[1].insert(stamp, v)?;
+                    }
+                    Err(e) => return Ok(Err(e)),
+                }
+            }
+
+            Ok(Ok(()))
+        })
+    }
+
+    /// Read the keys expiring at a timestamp into the cache, unless they are already there
+    fn load_set(
+        tree: &TransactionalTree,
+        name: &str,
+        sets: &mut BTreeMap<Vec<u8>, HashSet<IVec>>,
+        stamp: &[u8],
+    ) -> ConflictableTransactionResult<Result<()>> {
+        if sets.contains_key(stamp) {
+            return Ok(Ok(()));
+        }
+
+        let set = match tree.get(stamp)? {
+            Some(v) => match <E as Encoding<HashSet<IVec>>>::decode_at(name, stamp, &v) {
+                Ok(set) => set,
+                Err(e) => return Ok(Err(e)),
+            },
+            None => HashSet::new(),
+        };
+
+        sets.insert(stamp.to_vec(), set);
+        Ok(Ok(()))
+    }
 }
 
 impl<V, E, F> ExpiringTreeBuilder<V, E, F>
@@ -476,9 +629,24 @@ where
     E: Encoding<HashSet<IVec>> + Encoding<DateTime<Utc>> + 'static,
     F: Encoding<V> + 'static,
 {
-    /// Iterate over the keys of this Tree
+    /// Iterate over the keys of this Tree, without decoding any values
     pub fn keys(self) -> impl 'a + DoubleEndedIterator<Item = Result<IVec>> {
-        self.map(|res| res.map(|(key, _)| key))
+        let tree = self.1;
+
+        self.0
+            .keys()
+            .map(move |res| res.and_then(|key| tree.fetched(key)))
+    }
+
+    /// Iterate over entries whose values are only decoded on demand
+    pub fn entries(self) -> impl 'a + DoubleEndedIterator<Item = Result<StructuredEntry<V, F>>> {
+        let tree = self.1;
+
+        self.0.entries().map(move |res| {
+            let entry = res?;
+            tree.fetched(entry.key().clone())?;
+            Ok(entry)
+        })
     }
 
     /// Iterate over the values of this Tree
@@ -579,7 +747,9 @@ where
         batch: ExpiringBatch<V, F>,
     ) -> sled::ConflictableTransactionResult<Result<()>> {
         let keys = batch.1;
-        self.0.apply_batch(batch.0)?;
+        if let Err(e) = self.0.apply_batch(batch.0)? {
+            return Ok(Err(e));
+        }
 
         if self.1.extend_on_update {
             let now = Utc::now();
@@ -631,3 +801,40 @@ where
         }))
     }
 }
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use crate::{Config, DbExt};
+    use sled::IVec;
+
+    #[test]
+    fn get_many_extends_the_keys_it_finds() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = db
+            .open_expiring_json_tree::<String>("expiring")
+            .extend_on_fetch()
+            .expiration_length(chrono::Duration::milliseconds(-1))
+            .build()
+            .unwrap();
+
+        tree.insert(b"a", "one".to_owned()).unwrap();
+        tree.insert(b"b", "two".to_owned()).unwrap();
+        tree.insert(b"c", "three".to_owned()).unwrap();
+        assert_eq!(tree.expired().count(), 0);
+
+        assert_eq!(
+            tree.get_many([&b"c"[..], b"missing", b"a"]).unwrap(),
+            vec![Some("three".to_owned()), None, Some("one".to_owned())]
+        );
+
+        let mut expired: Vec<IVec> = tree.expired().collect();
+        expired.sort();
+        assert_eq!(expired, vec![IVec::from(b"a"), IVec::from(b"c")]);
+
+        // Extending again moves the keys to a new expiration
+        tree.get_many([b"a"]).unwrap();
+        let mut expired: Vec<IVec> = tree.expired().collect();
+        expired.sort();
+        assert_eq!(expired, vec![IVec::from(b"a"), IVec::from(b"c")]);
+    }
+}
@@ -0,0 +1,120 @@
+use sled::{ConflictableTransactionResult, TransactionalTree};
+use std::sync::Arc;
+
+use crate::{
+    error::{Error, Result},
+    transaction::MAX_TREES,
+};
+
+/// A write that is about to be applied to a structured tree
+pub(crate) struct Write<'a, V> {
+    /// The key being written
+    pub(crate) key: &'a [u8],
+
+    /// The value currently stored under the key
+    pub(crate) old: Option<&'a V>,
+
+    /// The value being stored, along with its encoded form. None for removals
+    pub(crate) new: Option<(&'a V, &'a [u8])>,
+}
+
+/// Logic that keeps companion trees in sync with a structured tree
+///
+/// Hooks run inside the same transaction as the write they observe, so the companion trees are
+/// never out of date with the data they describe.
+pub(crate) trait Hook<V>: Send + Sync {
+    /// The companion trees this hook reads and writes within a transaction
+    fn trees(&self) -> &[sled::Tree];
+
+    /// Validate a write before anything has been written
+    fn check(
+        &self,
+        _trees: &[TransactionalTree],
+        _write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        Ok(Ok(()))
+    }
+
+    /// Update the companion trees to reflect a write
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>>;
+}
+
+/// The hooks registered on a structured tree
+///
+/// Every tree the hooks touch is recorded once, with the data tree first, so that a single
+/// transaction can span them all even when several hooks share a tree.
+pub(crate) struct Hooks<V> {
+    trees: Vec<sled::Tree>,
+    hooks: Vec<(Arc<dyn Hook<V>>, Vec<usize>)>,
+}
+
+impl<V> Hooks<V> {
+    pub(crate) fn new(tree: sled::Tree) -> Self {
+        Hooks {
+            trees: vec![tree],
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Every tree a write to the data tree may touch
+    pub(crate) fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub(crate) fn add(&mut self, hook: Arc<dyn Hook<V>>) -> Result<()> {
+        let mut trees = self.trees.clone();
+        let mut positions = Vec::new();
+
+        for tree in hook.trees() {
+            let position = match trees.iter().position(|t| t.name() == tree.name()) {
+                Some(position) => position,
+                None => {
+                    trees.push(tree.clone());
+                    trees.len() - 1
+                }
+            };
+
+            positions.push(position);
+        }
+
+        if trees.len() > MAX_TREES {
+            return Err(Error::Sled(sled::Error::Unsupported(format!(
+                "a tree and its companions may span at most {} trees",
+                MAX_TREES
+            ))));
+        }
+
+        self.trees = trees;
+        self.hooks.push((hook, positions));
+        Ok(())
+    }
+
+    /// Pair each hook with its view of the trees in a transaction over `trees()`
+    pub(crate) fn with_views<'a>(
+        &'a self,
+        views: &'a [TransactionalTree],
+    ) -> impl Iterator<Item = (&'a dyn Hook<V>, Vec<TransactionalTree>)> + 'a {
+        self.hooks.iter().map(move |(hook, positions)| {
+            let trees = positions.iter().map(|&i| views[i].clone()).collect();
+
+            (&**hook, trees)
+        })
+    }
+}
+
+impl<V> Clone for Hooks<V> {
+    fn clone(&self) -> Self {
+        Hooks {
+            trees: self.trees.clone(),
+            hooks: self.hooks.clone(),
+        }
+    }
+}
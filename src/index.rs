@@ -0,0 +1,1068 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::{marker::PhantomData, ops::Bound, sync::Arc};
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    hook::{Hook, Write},
+    transaction::atomically,
+};
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED: u8 = 0xff;
+const TERMINATOR: [u8; 2] = [0x00, 0x00];
+
+const READY: u8 = 0;
+const PENDING: u8 = 1;
+const BUILDING: u8 = 2;
+
+/// A key that a value can be looked up by in a secondary index
+///
+/// Index keys are compared bytewise, so integers are encoded big-endian to keep their numeric
+/// order. Composite keys made of several parts sort by each part in turn, and can be scanned by
+/// any leading subset of their parts.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IndexKey(Vec<u8>);
+
+/// An iterator over the values found through a secondary index
+///
+/// Values are yielded in index key order along with their primary keys.
+pub struct IndexIter<V, E> {
+    iter: sled::Iter,
+    tree: sled::Tree,
+    index: Arc<Index<V>>,
+    encoding: PhantomData<E>,
+}
+
+/// An iterator over the projections stored in a covering index
+///
+/// Projections are yielded in index key order along with their primary keys, without reading
+/// the values they were projected from.
+pub struct CoveringIter<P, E>(sled::Iter, Arc<str>, PhantomData<P>, PhantomData<E>);
+
+pub(crate) type Extractor<V> = dyn Fn(&V) -> Vec<IndexKey> + Send + Sync;
+
+pub(crate) type Projection<V> = dyn Fn(&str, &[u8], &V) -> Result<Vec<u8>> + Send + Sync;
+
+/// A secondary index kept up to date with a structured tree
+///
+/// Entries are stored in a companion tree, keyed by the escaped index key followed by the
+/// primary key, so that a single index key may point to many values. Entries of unique indexes
+/// are keyed by the escaped index key alone. Each entry holds the length-prefixed primary key,
+/// followed by the encoded projection of the value for covering indexes.
+///
+/// The state of each index is kept in a metadata tree shared by every index on the same tree.
+/// An index is either ready, or still being built, in which case the metadata records the last
+/// primary key that has been backfilled.
+pub(crate) struct Index<V> {
+    name: String,
+    data: Arc<str>,
+    unique: bool,
+    trees: [sled::Tree; 1],
+    meta: sled::Tree,
+    extractor: Box<Extractor<V>>,
+    projection: Option<Box<Projection<V>>>,
+}
+
+impl IndexKey {
+    /// Start an empty composite key
+    ///
+    /// ```rust
+    /// use sled_extensions::structured::index::IndexKey;
+    ///
+    /// let earlier = IndexKey::composite().push("tenant").push(5u64);
+    /// let later = IndexKey::composite().push("tenant").push(10u64);
+    ///
+    /// assert!(earlier < later);
+    /// assert_eq!(later, IndexKey::from(("tenant", 10u64)));
+    /// ```
+    pub fn composite() -> Self {
+        IndexKey(Vec::new())
+    }
+
+    /// Append a part to a composite key
+    pub fn push<K>(mut self, part: K) -> Self
+    where
+        K: Into<IndexKey>,
+    {
+        self.0
+            .extend_from_slice(&terminated(part.into().as_bytes()));
+        self
+    }
+
+    /// The raw bytes of this key
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<V> Index<V> {
+    /// Open an index over the data tree
+    ///
+    /// Indexes registered on a tree that already holds values must be backfilled before they can
+    /// be queried.
+    pub(crate) fn new<F>(
+        db: &sled::Db,
+        data: &sled::Tree,
+        tree: &str,
+        name: &str,
+        unique: bool,
+        extractor: F,
+    ) -> Result<Self>
+    where
+        F: Fn(&V) -> Vec<IndexKey> + Send + Sync + 'static,
+    {
+        let index = Index {
+            name: name.to_owned(),
+            data: tree.into(),
+            unique,
+            trees: [db.open_tree(format!("{}-index-{}", tree, name))?],
+            meta: db.open_tree(format!("{}-index-meta", tree))?,
+            extractor: Box::new(extractor),
+            projection: None,
+        };
+
+        let state = if data.is_empty() { READY } else { PENDING };
+        let _ = index
+            .meta
+            .compare_and_swap(name, None as Option<&[u8]>, Some(&[state][..]))?;
+
+        Ok(index)
+    }
+
+    /// Store a projection of each value alongside its entries
+    ///
+    /// The projection is encoded with the name of the data tree and the primary key of the value.
+    pub(crate) fn covering<F>(mut self, projection: F) -> Self
+    where
+        F: Fn(&str, &[u8], &V) -> Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.projection = Some(Box::new(projection));
+        self
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn is_covering(&self) -> bool {
+        self.projection.is_some()
+    }
+
+    pub(crate) fn tree(&self) -> &sled::Tree {
+        &self.trees[0]
+    }
+
+    /// Whether every value in the data tree has been indexed
+    pub(crate) fn is_ready(&self) -> Result<bool> {
+        Ok(self.meta.get(&self.name)?.as_deref() == Some(&[READY][..]))
+    }
+
+    /// Fail unless the index can be queried
+    pub(crate) fn ensure_ready(&self) -> Result<()> {
+        if self.is_ready()? {
+            Ok(())
+        } else {
+            Err(Error::IndexNotReady(self.name.clone()))
+        }
+    }
+
+    /// Index the next chunk of values that were written before the index was registered
+    ///
+    /// Each chunk is indexed in a single transaction that also records how far the backfill has
+    /// progressed, so an interrupted backfill resumes where it left off. Returns `true` once
+    /// every value has been indexed and the index is marked ready.
+    pub(crate) fn backfill<E>(&self, data: &sled::Tree, chunk_size: usize) -> Result<bool>
+    where
+        E: Encoding<V>,
+    {
+        let state = self.meta.get(&self.name)?;
+
+        let keys = match state.as_deref() {
+            Some([READY]) => return Ok(true),
+            Some([PENDING]) | None => data.iter(),
+            Some([BUILDING, after @ ..]) => {
+                data.range::<&[u8], _>((Bound::Excluded(after), Bound::Unbounded))
+            }
+            Some(_) => return Err(Error::Corrupted(format!("state of index {}", self.name))),
+        }
+        .keys()
+        .take(chunk_size.max(1))
+        .collect::<sled::Result<Vec<_>>>()?;
+
+        let done = keys.len() < chunk_size.max(1);
+
+        let next = match keys.last() {
+            Some(last) if !done => {
+                let mut next = vec![BUILDING];
+                next.extend_from_slice(last);
+                next
+            }
+            _ => vec![READY],
+        };
+
+        atomically(
+            &[data.clone(), self.tree().clone(), self.meta.clone()],
+            |views| {
+                let (data, index, meta) = (&views[0], &views[1], &views[2]);
+
+                for key in &keys {
+                    let v = match data.get(key)? {
+                        Some(v) => v,
+                        None => continue,
+                    };
+
+                    let value = match E::decode_at(&self.data, key, &v) {
+                        Ok(value) => value,
+                        Err(e) => return Ok(Err(e)),
+                    };
+
+                    let stored = match self.stored(key, &value) {
+                        Ok(stored) => stored,
+                        Err(e) => return Ok(Err(e)),
+                    };
+
+                    for index_key in self.extract(&value) {
+                        let entry = self.entry(&index_key, key);
+
+                        if let Err(e) = self.check_entry(index, &entry, key)? {
+                            return Ok(Err(e));
+                        }
+
+                        index.insert(entry, stored.as_slice())?;
+                    }
+                }
+
+                meta.insert(self.name.as_bytes(), next.as_slice())?;
+                Ok(Ok(()))
+            },
+        )?;
+
+        Ok(done)
+    }
+
+    /// Discard every entry and mark the index as needing a backfill
+    pub(crate) fn reset(&self) -> Result<()> {
+        self.meta.insert(self.name.as_bytes(), &[PENDING][..])?;
+
+        for entry in self.tree().iter().keys() {
+            self.tree().remove(entry?)?;
+        }
+
+        Ok(())
+    }
+
+    /// The deduplicated, sorted index keys for a value
+    pub(crate) fn extract(&self, value: &V) -> Vec<IndexKey> {
+        let mut keys = (self.extractor)(value);
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// The key of the entry pointing from an index key to a primary key
+    fn entry(&self, key: &IndexKey, primary: &[u8]) -> Vec<u8> {
+        let mut out = terminated(key.as_bytes());
+
+        if !self.unique {
+            out.extend_from_slice(primary);
+        }
+
+        out
+    }
+
+    /// What an entry stores for a value
+    fn stored(&self, key: &[u8], value: &V) -> Result<Vec<u8>> {
+        let mut out = Vec::with_capacity(4 + key.len());
+        out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        out.extend_from_slice(key);
+
+        if let Some(projection) = &self.projection {
+            out.extend_from_slice(&(projection)(&self.data, key, value)?);
+        }
+
+        Ok(out)
+    }
+
+    /// Iterate over the values whose index key lies within the provided bounds
+    pub(crate) fn range<E>(
+        self: &Arc<Self>,
+        tree: &sled::Tree,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> IndexIter<V, E> {
+        IndexIter::new(self.entries(lower, upper), tree, self)
+    }
+
+    /// Iterate over the values with an index key whose primary keys lie within the provided
+    /// bounds
+    ///
+    /// Entries of unique indexes don't include the primary key, so the bounds only narrow the
+    /// entries of other indexes.
+    pub(crate) fn get_within<E>(
+        self: &Arc<Self>,
+        tree: &sled::Tree,
+        key: &IndexKey,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> IndexIter<V, E> {
+        let prefix = terminated(key.as_bytes());
+
+        if self.unique {
+            return IndexIter::new(self.tree().range(prefix.clone()..=prefix), tree, self);
+        }
+
+        let entry = |primary: &[u8]| {
+            let mut entry = prefix.clone();
+            entry.extend_from_slice(primary);
+            entry
+        };
+
+        let lower = match lower {
+            Bound::Included(primary) => Bound::Included(entry(primary)),
+            Bound::Excluded(primary) => Bound::Excluded(entry(primary)),
+            Bound::Unbounded => Bound::Included(prefix.clone()),
+        };
+        let upper = match upper {
+            Bound::Included(primary) => Bound::Included(entry(primary)),
+            Bound::Excluded(primary) => Bound::Excluded(entry(primary)),
+            Bound::Unbounded => Bound::Excluded(successor(key.as_bytes())),
+        };
+
+        IndexIter::new(self.tree().range((lower, upper)), tree, self)
+    }
+
+    /// Iterate over the values whose index key starts with the provided prefix
+    pub(crate) fn scan_prefix<E>(
+        self: &Arc<Self>,
+        tree: &sled::Tree,
+        prefix: &[u8],
+    ) -> IndexIter<V, E> {
+        IndexIter::new(self.entries_with_prefix(prefix), tree, self)
+    }
+
+    /// Iterate over the projections whose index key lies within the provided bounds
+    pub(crate) fn range_covering<P, E>(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> CoveringIter<P, E> {
+        CoveringIter(
+            self.entries(lower, upper),
+            self.data.clone(),
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    /// Iterate over the projections whose index key starts with the provided prefix
+    pub(crate) fn scan_prefix_covering<P, E>(&self, prefix: &[u8]) -> CoveringIter<P, E> {
+        CoveringIter(
+            self.entries_with_prefix(prefix),
+            self.data.clone(),
+            PhantomData,
+            PhantomData,
+        )
+    }
+
+    fn entries(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> sled::Iter {
+        let lower = match lower {
+            Bound::Included(key) => Bound::Included(terminated(key)),
+            Bound::Excluded(key) => Bound::Included(successor(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let upper = match upper {
+            Bound::Included(key) => Bound::Excluded(successor(key)),
+            Bound::Excluded(key) => Bound::Excluded(terminated(key)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        self.tree().range((lower, upper))
+    }
+
+    fn entries_with_prefix(&self, prefix: &[u8]) -> sled::Iter {
+        let mut escaped = Vec::new();
+        escape(prefix, &mut escaped);
+
+        self.tree().scan_prefix(escaped)
+    }
+}
+
+impl<V> Index<V> {
+    /// Ensure an entry doesn't already point to a different primary key in a unique index
+    fn check_entry(
+        &self,
+        index: &TransactionalTree,
+        entry: &[u8],
+        key: &[u8],
+    ) -> ConflictableTransactionResult<Result<()>> {
+        if !self.unique {
+            return Ok(Ok(()));
+        }
+
+        let stored = match index.get(entry)? {
+            Some(stored) => stored,
+            None => return Ok(Ok(())),
+        };
+
+        match read_stored(&stored) {
+            Ok((primary, _)) if primary == key => Ok(Ok(())),
+            Ok((primary, _)) => Ok(Err(Error::UniqueViolation {
+                index: self.name.clone(),
+                key: IVec::from(primary),
+            })),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    /// The index keys a write removes and adds
+    fn changes(&self, write: &Write<V>) -> (Vec<IndexKey>, Vec<IndexKey>) {
+        let old = write.old.map(|old| self.extract(old)).unwrap_or_default();
+        let new = write
+            .new
+            .map(|(new, _)| self.extract(new))
+            .unwrap_or_default();
+
+        let removed = old
+            .iter()
+            .filter(|key| new.binary_search(key).is_err())
+            .cloned()
+            .collect();
+        let added = new
+            .into_iter()
+            .filter(|key| old.binary_search(key).is_err())
+            .collect();
+
+        (removed, added)
+    }
+}
+
+impl<V> Hook<V> for Index<V> {
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn check(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        let (_, new) = self.changes(write);
+
+        for key in new {
+            if let Err(e) = self.check_entry(&trees[0], &self.entry(&key, write.key), write.key)? {
+                return Ok(Err(e));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        let (removed, added) = self.changes(write);
+
+        for key in removed {
+            let entry = self.entry(&key, write.key);
+
+            // Unique entries don't include the primary key, and may already belong to another
+            // value that was written while the index was being backfilled
+            if self.unique {
+                match trees[0].get(&entry)? {
+                    Some(stored) => match read_stored(&stored) {
+                        Ok((primary, _)) if primary == write.key => (),
+                        Ok(_) => continue,
+                        Err(e) => return Ok(Err(e)),
+                    },
+                    None => continue,
+                }
+            }
+
+            trees[0].remove(entry)?;
+        }
+
+        let new = match write.new {
+            Some((new, _)) => new,
+            None => return Ok(Ok(())),
+        };
+
+        let stored = match self.stored(write.key, new) {
+            Ok(stored) => stored,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        // Projections may change even when the index keys don't, so every entry is rewritten
+        let keys = if self.is_covering() {
+            self.extract(new)
+        } else {
+            added
+        };
+
+        for key in keys {
+            trees[0].insert(self.entry(&key, write.key), stored.as_slice())?;
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+impl<V, E> IndexIter<V, E> {
+    fn new(iter: sled::Iter, tree: &sled::Tree, index: &Arc<Index<V>>) -> Self {
+        IndexIter {
+            iter,
+            tree: tree.clone(),
+            index: Arc::clone(index),
+            encoding: PhantomData,
+        }
+    }
+}
+
+impl<V, E> IndexIter<V, E>
+where
+    E: Encoding<V>,
+{
+    /// Iterate over the primary keys of the values found through the index
+    pub fn keys(self) -> impl DoubleEndedIterator<Item = Result<IVec>> {
+        self.map(|res| res.map(|(key, _)| key))
+    }
+
+    /// Iterate over the values found through the index
+    pub fn values(self) -> impl DoubleEndedIterator<Item = Result<V>> {
+        self.map(|res| res.map(|(_, v)| v))
+    }
+
+    /// Resolve an index entry to the value it points to
+    ///
+    /// The entry is skipped if the value has been changed or removed since the entry was read.
+    fn resolve(&self, entry: &[u8], stored: &[u8]) -> Option<Result<(IVec, V)>> {
+        let key = match read_stored(stored) {
+            Ok((key, _)) => IVec::from(key),
+            Err(e) => return Some(Err(e)),
+        };
+
+        let v = match self.tree.get(&key) {
+            Ok(v) => v?,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        let value = match E::decode_at(&self.index.data, &key, &v) {
+            Ok(value) => value,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let index_key = IndexKey(unescape(entry));
+
+        if self
+            .index
+            .extract(&value)
+            .binary_search(&index_key)
+            .is_err()
+        {
+            return None;
+        }
+
+        Some(Ok((key, value)))
+    }
+}
+
+impl<V, E> Iterator for IndexIter<V, E>
+where
+    E: Encoding<V>,
+{
+    type Item = Result<(IVec, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                Ok((entry, stored)) => {
+                    if let Some(res) = self.resolve(&entry, &stored) {
+                        return Some(res);
+                    }
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+impl<V, E> DoubleEndedIterator for IndexIter<V, E>
+where
+    E: Encoding<V>,
+{
+    fn next_back(&mut self) -> Option<<Self as Iterator>::Item> {
+        loop {
+            match self.iter.next_back()? {
+                Ok((entry, stored)) => {
+                    if let Some(res) = self.resolve(&entry, &stored) {
+                        return Some(res);
+                    }
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+impl<P, E> CoveringIter<P, E>
+where
+    E: Encoding<P>,
+{
+    /// Iterate over the primary keys of the entries in the index
+    pub fn keys(self) -> impl DoubleEndedIterator<Item = Result<IVec>> {
+        self.map(|res| res.map(|(key, _)| key))
+    }
+
+    /// Iterate over the projections stored in the index
+    pub fn values(self) -> impl DoubleEndedIterator<Item = Result<P>> {
+        self.map(|res| res.map(|(_, v)| v))
+    }
+
+    fn decode(&self, stored: &[u8]) -> Result<(IVec, P)> {
+        let (key, projection) = read_stored(stored)?;
+
+        Ok((IVec::from(key), E::decode_at(&self.1, key, projection)?))
+    }
+}
+
+impl<P, E> Iterator for CoveringIter<P, E>
+where
+    E: Encoding<P>,
+{
+    type Item = Result<(IVec, P)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.next()? {
+            Ok((_, stored)) => Some(self.decode(&stored)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl<P, E> DoubleEndedIterator for CoveringIter<P, E>
+where
+    E: Encoding<P>,
+{
+    fn next_back(&mut self) -> Option<<Self as Iterator>::Item> {
+        match self.0.next_back()? {
+            Ok((_, stored)) => Some(self.decode(&stored)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+pub(crate) fn bound<K>(bound: Bound<&K>) -> Bound<IndexKey>
+where
+    K: Into<IndexKey> + Clone,
+{
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Split what an entry stores into the primary key and the projection
+fn read_stored(stored: &[u8]) -> Result<(&[u8], &[u8])> {
+    let corrupted = || Error::Corrupted("index entry".to_owned());
+
+    if stored.len() < 4 {
+        return Err(corrupted());
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&stored[..4]);
+    let len = u32::from_be_bytes(len) as usize;
+
+    if stored.len() < 4 + len {
+        return Err(corrupted());
+    }
+
+    Ok(stored[4..].split_at(len))
+}
+
+/// Escape an index key so that it can be followed by a terminator without losing its ordering
+///
+/// Zero bytes are written as `0x00 0xff`, leaving `0x00 0x00` free to mark the end of the key.
+pub(crate) fn escape(key: &[u8], out: &mut Vec<u8>) {
+    for &byte in key {
+        out.push(byte);
+
+        if byte == ESCAPE {
+            out.push(ESCAPED);
+        }
+    }
+}
+
+/// Read the index key back out of an index entry
+fn unescape(entry: &[u8]) -> Vec<u8> {
+    split(entry).0
+}
+
+/// Split an entry into its unescaped key and whatever follows the terminator
+pub(crate) fn split(entry: &[u8]) -> (Vec<u8>, &[u8]) {
+    let mut key = Vec::with_capacity(entry.len());
+    let mut i = 0;
+
+    while i < entry.len() {
+        let byte = entry[i];
+        i += 1;
+
+        if byte == ESCAPE {
+            match entry.get(i) {
+                Some(&ESCAPED) => i += 1,
+                _ => return (key, entry.get(i + 1..).unwrap_or_default()),
+            }
+        }
+
+        key.push(byte);
+    }
+
+    (key, &[])
+}
+
+/// The prefix shared by every entry for an index key
+pub(crate) fn terminated(key: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(key.len() + TERMINATOR.len());
+    escape(key, &mut out);
+    out.extend_from_slice(&TERMINATOR);
+    out
+}
+
+/// The smallest entry that sorts after every entry for an index key
+pub(crate) fn successor(key: &[u8]) -> Vec<u8> {
+    let mut out = terminated(key);
+
+    if let Some(last) = out.last_mut() {
+        *last = 0x01;
+    }
+
+    out
+}
+
+impl From<Vec<u8>> for IndexKey {
+    fn from(bytes: Vec<u8>) -> Self {
+        IndexKey(bytes)
+    }
+}
+
+impl From<&[u8]> for IndexKey {
+    fn from(bytes: &[u8]) -> Self {
+        IndexKey(bytes.to_vec())
+    }
+}
+
+impl From<IVec> for IndexKey {
+    fn from(bytes: IVec) -> Self {
+        IndexKey(bytes.to_vec())
+    }
+}
+
+impl From<String> for IndexKey {
+    fn from(s: String) -> Self {
+        IndexKey(s.into_bytes())
+    }
+}
+
+impl From<&str> for IndexKey {
+    fn from(s: &str) -> Self {
+        IndexKey(s.as_bytes().to_vec())
+    }
+}
+
+impl From<u64> for IndexKey {
+    fn from(n: u64) -> Self {
+        IndexKey(n.to_be_bytes().to_vec())
+    }
+}
+
+impl From<i64> for IndexKey {
+    fn from(n: i64) -> Self {
+        IndexKey(((n as u64) ^ (1 << 63)).to_be_bytes().to_vec())
+    }
+}
+
+impl<A, B> From<(A, B)> for IndexKey
+where
+    A: Into<IndexKey>,
+    B: Into<IndexKey>,
+{
+    fn from((a, b): (A, B)) -> Self {
+        IndexKey::composite().push(a).push(b)
+    }
+}
+
+impl<A, B, C> From<(A, B, C)> for IndexKey
+where
+    A: Into<IndexKey>,
+    B: Into<IndexKey>,
+    C: Into<IndexKey>,
+{
+    fn from((a, b, c): (A, B, C)) -> Self {
+        IndexKey::composite().push(a).push(b).push(c)
+    }
+}
+
+impl AsRef<[u8]> for IndexKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, structured_tree::StructuredTree, Config};
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn open(db: &sled::Db) -> Tree {
+        StructuredTree::new(db, "indexed").unwrap()
+    }
+
+    fn by_value(value: &IVec) -> Vec<IndexKey> {
+        vec![IndexKey::from(value.clone())]
+    }
+
+    fn keys(iter: IndexIter<IVec, PlainEncoding>) -> Vec<IVec> {
+        iter.keys().collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn unique_removal_keeps_entries_owned_by_other_keys() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+        tree.insert(b"old", IVec::from(b"x")).unwrap();
+
+        let tree = tree.with_unique_index("value", by_value).unwrap();
+        assert!(!tree.is_index_ready("value").unwrap());
+
+        // The pending index has no entry for "x" yet, so this claims it
+        tree.insert(b"new", IVec::from(b"x")).unwrap();
+
+        // Moving the old value away must not release the entry the new value now owns
+        tree.insert(b"old", IVec::from(b"y")).unwrap();
+
+        match tree.insert(b"other", IVec::from(b"x")) {
+            Err(Error::UniqueViolation { index, key }) => {
+                assert_eq!(index, "value");
+                assert_eq!(key, IVec::from(b"new"));
+            }
+            _ => panic!("Expected a unique violation"),
+        }
+
+        tree.backfill_index("value", 10).unwrap();
+
+        assert_eq!(
+            keys(tree.get_by_index("value", "x").unwrap()),
+            vec![IVec::from(b"new")]
+        );
+        assert_eq!(
+            keys(tree.get_by_index("value", "y").unwrap()),
+            vec![IVec::from(b"old")]
+        );
+    }
+
+    #[test]
+    fn unique_values_can_move_between_keys() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db).with_unique_index("value", by_value).unwrap();
+
+        tree.insert(b"a", IVec::from(b"x")).unwrap();
+        tree.insert(b"a", IVec::from(b"y")).unwrap();
+        tree.insert(b"b", IVec::from(b"x")).unwrap();
+        tree.remove(b"a").unwrap();
+        tree.insert(b"c", IVec::from(b"y")).unwrap();
+
+        assert_eq!(
+            keys(tree.get_by_index("value", "x").unwrap()),
+            vec![IVec::from(b"b")]
+        );
+        assert_eq!(
+            keys(tree.get_by_index("value", "y").unwrap()),
+            vec![IVec::from(b"c")]
+        );
+    }
+
+    #[test]
+    fn index_follows_inserts_updates_and_removals() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db)
+            .with_index("bytes", |value: &IVec| {
+                value.iter().map(|b| IndexKey::from(vec![*b])).collect()
+            })
+            .unwrap();
+        assert!(tree.is_index_ready("bytes").unwrap());
+
+        tree.insert(b"a", IVec::from(b"xy")).unwrap();
+        tree.insert(b"b", IVec::from(b"yz")).unwrap();
+        tree.insert(b"c", IVec::from(b"y")).unwrap();
+
+        assert_eq!(
+            keys(tree.get_by_index("bytes", &b"y"[..]).unwrap()),
+            vec![IVec::from(b"a"), IVec::from(b"b"), IVec::from(b"c")]
+        );
+
+        tree.insert(b"a", IVec::from(b"z")).unwrap();
+        tree.remove(b"b").unwrap();
+
+        assert_eq!(
+            keys(tree.get_by_index("bytes", &b"y"[..]).unwrap()),
+            vec![IVec::from(b"c")]
+        );
+        assert_eq!(
+            keys(tree.get_by_index("bytes", &b"x"[..]).unwrap()),
+            Vec::<IVec>::new()
+        );
+
+        let values: Vec<IVec> = tree
+            .range_by_index("bytes", &b"x"[..]..=&b"z"[..])
+            .unwrap()
+            .values()
+            .rev()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(values, vec![IVec::from(b"z"), IVec::from(b"y")]);
+    }
+
+    #[test]
+    fn unknown_indexes_are_rejected() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        match tree.get_by_index("missing", "x") {
+            Err(Error::UnknownIndex(name)) => assert_eq!(name, "missing"),
+            _ => panic!("Expected an unknown index"),
+        }
+    }
+
+    #[test]
+    fn backfill_resumes_from_stored_progress() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+        for i in 0..5u8 {
+            tree.insert(&[i][..], IVec::from(&[i % 2][..])).unwrap();
+        }
+
+        let data = db.open_tree("indexed").unwrap();
+        let index: Index<IVec> =
+            Index::new(&db, &data, "indexed", "value", false, by_value).unwrap();
+        assert!(!index.backfill::<PlainEncoding>(&data, 2).unwrap());
+        assert!(!index.is_ready().unwrap());
+        assert_eq!(index.tree().len(), 2);
+
+        // A new handle for the same index continues after the first chunk
+        let tree = tree.with_index("value", by_value).unwrap();
+        match tree.get_by_index("value", &[0u8][..]) {
+            Err(Error::IndexNotReady(_)) => (),
+            _ => panic!("Expected the index to be building"),
+        }
+
+        tree.backfill_index("value", 2).unwrap();
+        assert!(tree.is_index_ready("value").unwrap());
+        assert_eq!(
+            keys(tree.get_by_index("value", &[0u8][..]).unwrap()),
+            vec![
+                IVec::from(&[0][..]),
+                IVec::from(&[2][..]),
+                IVec::from(&[4][..])
+            ]
+        );
+        assert_eq!(
+            keys(tree.get_by_index("value", &[1u8][..]).unwrap()),
+            vec![IVec::from(&[1][..]), IVec::from(&[3][..])]
+        );
+    }
+
+    #[test]
+    fn rebuild_discards_stale_entries() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db).with_index("value", by_value).unwrap();
+        tree.insert(b"a", IVec::from(b"x")).unwrap();
+
+        let entries = db.open_tree("indexed-index-value").unwrap();
+        let stale = entries.iter().keys().next().unwrap().unwrap();
+        let mut stale = stale.to_vec();
+        *stale.last_mut().unwrap() = b'b';
+        entries
+            .insert(stale, entries.iter().values().next().unwrap().unwrap())
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+
+        tree.rebuild_index("value", 1).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            keys(tree.get_by_index("value", &b"x"[..]).unwrap()),
+            vec![IVec::from(b"a")]
+        );
+    }
+
+    #[test]
+    fn composite_keys_sort_by_each_part() {
+        let mut keys = vec![
+            IndexKey::from(("ab", 0u64)),
+            IndexKey::from(("a", u64::MAX)),
+            IndexKey::from((&b"a\0"[..], 0u64)),
+            IndexKey::from(("a", -1i64)),
+            IndexKey::from(("a", 1i64)),
+        ];
+        keys.sort();
+
+        assert_eq!(
+            keys,
+            vec![
+                IndexKey::from(("a", -1i64)),
+                IndexKey::from(("a", 1i64)),
+                IndexKey::from(("a", u64::MAX)),
+                IndexKey::from((&b"a\0"[..], 0u64)),
+                IndexKey::from(("ab", 0u64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn covering_projections_follow_their_values() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db)
+            .with_covering_index(
+                "prefix-len",
+                |value: &IVec| vec![(&value[..1], value.len() as u64).into()],
+                |value: &IVec| IVec::from(&value[1..]),
+            )
+            .unwrap();
+
+        tree.insert(b"1", IVec::from(b"abc")).unwrap();
+        tree.insert(b"2", IVec::from(b"ad")).unwrap();
+        tree.insert(b"3", IVec::from(b"bcd")).unwrap();
+
+        let projections = |tree: &Tree| -> Vec<(IVec, IVec)> {
+            tree.scan_prefix_covering("prefix-len", IndexKey::composite().push("a"))
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            projections(&tree),
+            vec![
+                (IVec::from(b"2"), IVec::from(b"d")),
+                (IVec::from(b"1"), IVec::from(b"bc")),
+            ]
+        );
+
+        tree.insert(b"2", IVec::from(b"aefg")).unwrap();
+        tree.remove(b"1").unwrap();
+
+        assert_eq!(
+            projections(&tree),
+            vec![(IVec::from(b"2"), IVec::from(b"efg"))]
+        );
+
+        match tree.range_covering::<IVec, _, _>("missing", "a".."b") {
+            Err(Error::UnknownIndex(_)) => (),
+            _ => panic!("Expected an unknown index"),
+        }
+    }
+}
@@ -27,21 +27,48 @@
 //! ```
 //!
 //! Available features
+//! - `aes-gcm` - Enable encrypting values with AES-256-GCM
+//! - `async` - Enable async wrappers around trees, backed by tokio's blocking thread pool
 //! - `bincode` - Enable storing bincode-encoded data
 //! - `cbor` - Enable storing cbor-encoded data
+//! - `chacha20poly1305` - Enable encrypting values with ChaCha20-Poly1305
 //! - `json` - Enable storing json-encoded data
-
+//! - `lz4` - Enable compressing values with lz4
+//! - `msgpack` - Enable storing MessagePack-encoded data
+//! - `postcard` - Enable storing postcard-encoded data
+//! - `rayon` - Enable scanning trees in parallel on rayon's thread pool
+//! - `snappy` - Enable compressing values with snappy
+//! - `zstd` - Enable compressing values with zstd
+
+mod aggregate;
+#[cfg(feature = "async")]
+mod async_tree;
+mod bulk;
+mod changelog;
+mod compress;
 mod db;
 mod encoding;
+mod encrypt;
 mod error;
 mod expiring_tree;
+mod hook;
+mod index;
+mod merge;
+mod pagination;
+mod parallel;
+mod progress;
+mod query;
+mod reference;
 mod structured_tree;
+mod text;
+mod transaction;
+mod view;
 
 pub use sled::{abort, Config, Db, IVec, TransactionError};
 
 pub use self::{
     db::DbExt,
-    encoding::Encoding,
+    encoding::{BorrowedEncoding, Encoding, TreeState},
     error::{Error, Result},
     structured_tree::CompareAndSwapError,
 };
@@ -51,11 +78,81 @@ pub use self::{
 /// This module contains the base types that other trees are built on. In fact, most trees are
 /// just type aliases for the basic trees here with pre-defined encodings.
 pub mod structured {
+    pub use crate::reference::OnDelete;
     pub use crate::structured_tree::{
-        StructuredBatch as Batch, StructuredIter as Iter,
+        StructuredBatch as Batch, StructuredEntry as Entry, StructuredEvent as Event,
+        StructuredIter as Iter, StructuredRef as Ref, StructuredSubscriber as Subscriber,
         StructuredTransactionalTree as TransactionalTree, StructuredTree as Tree,
     };
 
+    /// Aggregates over the values in structured trees
+    ///
+    /// Counts, sums, minimums and maximums can be computed over any range or prefix, optionally
+    /// grouped by key prefix. Trees opened `with_counter` also maintain counts per key prefix.
+    pub mod aggregate {
+        pub use crate::aggregate::{Aggregate, GroupBy};
+    }
+
+    /// Fast bulk loading of structured trees
+    ///
+    /// Bulk loads encode values in parallel and write them in large batches, reporting progress
+    /// as they go.
+    pub mod bulk {
+        pub use crate::bulk::{BulkLoader, Progress};
+    }
+
+    /// Durable change-data-capture for structured trees
+    ///
+    /// Trees opened `with_changelog` record every insert and removal in an append-only,
+    /// sequence-numbered log. Consumers read from a cursor stored alongside the log, so they can
+    /// resume after a restart without missing changes.
+    pub mod changelog {
+        pub use crate::changelog::{Changelog, ChangelogIter as Iter, Consumer};
+    }
+
+    /// Secondary indexes over the values in structured trees
+    ///
+    /// Trees opened `with_index` keep a companion tree mapping index keys to primary keys, updated
+    /// in the same transaction as every write.
+    pub mod index {
+        pub use crate::index::{CoveringIter, IndexIter as Iter, IndexKey};
+    }
+
+    /// Cursor-based pagination over structured trees
+    pub mod pagination {
+        pub use crate::pagination::{Cursor, Page, Paginator};
+    }
+
+    /// Splitting structured trees into ranges that can be scanned in parallel
+    pub mod parallel {
+        pub use crate::parallel::KeyRange;
+    }
+
+    /// Queries over the values in structured trees
+    ///
+    /// Queries combine conditions on secondary indexes with key ranges and arbitrary filters,
+    /// reading through an index when one can answer a condition.
+    pub mod query {
+        pub use crate::query::{Condition, Plan, Query};
+    }
+
+    /// Full-text search over the values in structured trees
+    ///
+    /// Trees opened `with_text_index` keep an inverted index of the terms in their values,
+    /// updated in the same transaction as every write. Queries combine terms, prefixes and
+    /// phrases, and results are ranked by BM25.
+    pub mod text {
+        pub use crate::text::{SimpleTokenizer, TextQuery as Query, Tokenizer};
+    }
+
+    /// Materialized views derived from structured trees
+    ///
+    /// Trees opened `with_view` fold each write into the outputs of a map/reduce, in the same
+    /// transaction as the write, so summaries never drift from the data they describe.
+    pub mod view {
+        pub use crate::view::MapReduce;
+    }
+
     /// This module names types for more easily interacting with Expiring Trees
     ///
     /// The number of type parameters are reduced by asserting that the encoder used for the
@@ -120,12 +217,103 @@ pub mod expiring {
     }
 }
 
+#[cfg(feature = "async")]
+/// Async wrappers around trees
+///
+/// Sled's operations block the calling thread, so every call made through these wrappers is
+/// offloaded to tokio's blocking thread pool. Iterators are exposed as `Stream`s that fetch
+/// records in small chunks.
+///
+/// ```rust
+/// use sled_extensions::{asynchronous, structured::Event, Config, DbExt};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+///
+/// runtime.block_on(async {
+///     let db = Config::default().temporary(true).open()?;
+///     let tree = asynchronous::Tree::new(db.open_json_tree::<usize>("json-tree")?);
+///     let mut subscriber = tree.subscribe(b"h".to_vec());
+///
+///     tree.insert(b"hey", 32).await?;
+///     tree.insert(b"hi", 16).await?;
+///     tree.flush().await?;
+///
+///     assert_eq!(tree.get(b"hey").await?, Some(32));
+///
+///     match subscriber.next().await {
+///         Some(Ok(Event::Insert(key, value))) => {
+///             assert_eq!(key, b"hey");
+///             assert_eq!(value, 32);
+///         }
+///         _ => unreachable!("Should have witnessed the insert"),
+///     }
+///
+///     let mut stream = tree.scan_prefix(b"h");
+///     let mut count = 0;
+///
+///     while let Some(res) = stream.next().await {
+///         let (_key, _value) = res?;
+///         count += 1;
+///     }
+///
+///     assert_eq!(count, 2);
+///     Ok::<_, sled_extensions::Error>(())
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub mod asynchronous {
+    pub use crate::async_tree::{
+        AsyncExpiringTree as ExpiringTree, AsyncIter as Iter, AsyncStructuredTree as Tree,
+        AsyncSubscriber as Subscriber, Scan,
+    };
+}
+
+/// Transparent compression of stored values
+///
+/// `Compressed` wraps any other Encoding, compressing its output with one of the algorithms
+/// enabled by the `zstd`, `lz4` and `snappy` features, or with a custom `Compression`.
+pub mod compression {
+    pub use crate::compress::{Compressed, Compression};
+
+    #[cfg(feature = "lz4")]
+    pub use crate::compress::Lz4;
+
+    #[cfg(feature = "snappy")]
+    pub use crate::compress::Snappy;
+
+    #[cfg(feature = "zstd")]
+    pub use crate::compress::{Zstd, ZstdDictionary};
+}
+
+/// Encryption of stored values
+///
+/// `Encrypted` wraps any other Encoding, encrypting its output with one of the ciphers enabled by
+/// the `chacha20poly1305` and `aes-gcm` features, or with a custom `Cipher`, using keys supplied
+/// by a `KeyProvider`. Keys can be rotated, and `reencrypt` moves stored values to the new key.
+///
+/// The built-in ciphers are only available with their features. Without them, `Encrypted`,
+/// `KeyProvider`, `Rekey` and `Cipher` are still here, for use with a custom `Cipher`.
+pub mod encryption {
+    pub use crate::encrypt::{Cipher, Encrypted, KeyProvider, Rekey};
+
+    #[cfg(feature = "aes-gcm")]
+    pub use crate::encrypt::Aes256Gcm;
+
+    #[cfg(feature = "chacha20poly1305")]
+    pub use crate::encrypt::ChaCha20Poly1305;
+}
+
 #[cfg(feature = "bincode")]
 /// A module containing trees that are pre-configured to store Bincode-encoded data
 pub mod bincode {
     use crate::structured;
 
-    pub use crate::encoding::BincodeEncoding;
+    pub use crate::encoding::{
+        BigEndian, BincodeEncoding, BincodeEndian, BincodeIntEncoding, BincodeLimit, BincodeWith,
+        Fixint, Limit, LittleEndian, NoLimit, Varint,
+    };
 
     /// A tree that stores data of type V encoded as Bincode
     pub type Tree<V> = structured::Tree<V, BincodeEncoding>;
@@ -133,6 +321,9 @@ pub mod bincode {
     /// The bincode tree's iterator
     pub type Iter<V> = structured::Iter<V, BincodeEncoding>;
 
+    /// The bincode tree's lazily decoded entry
+    pub type Entry<V> = structured::Entry<V, BincodeEncoding>;
+
     /// The bincode tree's batch
     pub type Batch<V> = structured::Batch<V, BincodeEncoding>;
 
@@ -163,11 +354,58 @@ pub mod bincode {
     }
 }
 
+#[cfg(feature = "postcard")]
+/// A module containing trees that are pre-configured to store Postcard-encoded data
+pub mod postcard {
+    use crate::structured;
+
+    pub use crate::encoding::PostcardEncoding;
+
+    /// A tree that stores data of type V encoded as Postcard
+    pub type Tree<V> = structured::Tree<V, PostcardEncoding>;
+
+    /// The postcard tree's iterator
+    pub type Iter<V> = structured::Iter<V, PostcardEncoding>;
+
+    /// The postcard tree's lazily decoded entry
+    pub type Entry<V> = structured::Entry<V, PostcardEncoding>;
+
+    /// The postcard tree's batch
+    pub type Batch<V> = structured::Batch<V, PostcardEncoding>;
+
+    /// The postcard tree's transaction
+    pub type TransactionalTree<'a, V> = structured::TransactionalTree<'a, V, PostcardEncoding>;
+
+    /// A module containing expiring trees that store Postcard-encoded data
+    pub mod expiring {
+        use crate::expiring;
+
+        use super::PostcardEncoding;
+
+        /// An expiring tree that stores data of type V encoded as Postcard
+        pub type Tree<V> = expiring::Tree<V, PostcardEncoding, PostcardEncoding>;
+
+        /// The expiring postcard tree's builder
+        pub type TreeBuilder<V> = expiring::TreeBuilder<V, PostcardEncoding, PostcardEncoding>;
+
+        /// The expiring postcard tree's iterator
+        pub type Iter<'a, V> = expiring::Iter<'a, V, PostcardEncoding, PostcardEncoding>;
+
+        /// The expiring postcard tree's batch
+        pub type Batch<V> = expiring::Batch<V, PostcardEncoding>;
+
+        /// The expiring postcard tree's transaction
+        pub type TransactionalTree<'a, V> =
+            expiring::TransactionalTree<'a, V, PostcardEncoding, PostcardEncoding>;
+    }
+}
+
 #[cfg(feature = "cbor")]
 /// A module containing trees that are pre-configured to store Cbor-encoded data
 pub mod cbor {
     use crate::structured_tree::{
-        StructuredBatch, StructuredIter, StructuredTransactionalTree, StructuredTree,
+        StructuredBatch, StructuredEntry, StructuredIter, StructuredTransactionalTree,
+        StructuredTree,
     };
 
     pub use crate::encoding::CborEncoding;
@@ -178,6 +416,9 @@ pub mod cbor {
     /// The cbor tree's iterator
     pub type Iter<V> = StructuredIter<V, CborEncoding>;
 
+    /// The cbor tree's lazily decoded entry
+    pub type Entry<V> = StructuredEntry<V, CborEncoding>;
+
     /// The cbor tree's batch
     pub type Batch<V> = StructuredBatch<V, CborEncoding>;
 
@@ -212,7 +453,8 @@ pub mod cbor {
 /// A module containing trees that are pre-configured to store Json-encoded data
 pub mod json {
     use crate::structured_tree::{
-        StructuredBatch, StructuredIter, StructuredTransactionalTree, StructuredTree,
+        StructuredBatch, StructuredEntry, StructuredIter, StructuredTransactionalTree,
+        StructuredTree,
     };
 
     pub use crate::encoding::JsonEncoding;
@@ -223,6 +465,9 @@ pub mod json {
     /// The json tree's iterator
     pub type Iter<V> = StructuredIter<V, JsonEncoding>;
 
+    /// The json tree's lazily decoded entry
+    pub type Entry<V> = StructuredEntry<V, JsonEncoding>;
+
     /// The json tree's batch
     pub type Batch<V> = StructuredBatch<V, JsonEncoding>;
 
@@ -252,3 +497,52 @@ pub mod json {
             expiring::TransactionalTree<'a, V, JsonEncoding, JsonEncoding>;
     }
 }
+
+#[cfg(feature = "msgpack")]
+/// A module containing trees that are pre-configured to store MessagePack-encoded data
+pub mod msgpack {
+    use crate::structured_tree::{
+        StructuredBatch, StructuredEntry, StructuredIter, StructuredTransactionalTree,
+        StructuredTree,
+    };
+
+    pub use crate::encoding::{MsgPackEncoding, MsgPackMode, StructArray, StructMap};
+
+    /// A tree that stores data of type V encoded as MessagePack
+    pub type Tree<V> = StructuredTree<V, MsgPackEncoding>;
+
+    /// The msgpack tree's iterator
+    pub type Iter<V> = StructuredIter<V, MsgPackEncoding>;
+
+    /// The msgpack tree's lazily decoded entry
+    pub type Entry<V> = StructuredEntry<V, MsgPackEncoding>;
+
+    /// The msgpack tree's batch
+    pub type Batch<V> = StructuredBatch<V, MsgPackEncoding>;
+
+    /// The msgpack tree's transaction
+    pub type TransactionalTree<'a, V> = StructuredTransactionalTree<'a, V, MsgPackEncoding>;
+
+    /// A module containing expiring trees that store MessagePack-encoded data
+    pub mod expiring {
+        use crate::expiring;
+
+        use super::MsgPackEncoding;
+
+        /// An expiring tree that stores data of type V encoded as MessagePack
+        pub type Tree<V> = expiring::Tree<V, MsgPackEncoding, MsgPackEncoding>;
+
+        /// The expiring msgpack tree's builder
+        pub type TreeBuilder<V> = expiring::TreeBuilder<V, MsgPackEncoding, MsgPackEncoding>;
+
+        /// The expiring msgpack tree's iterator
+        pub type Iter<'a, V> = expiring::Iter<'a, V, MsgPackEncoding, MsgPackEncoding>;
+
+        /// The expiring msgpack tree's batch
+        pub type Batch<V> = expiring::Batch<V, MsgPackEncoding>;
+
+        /// The expiring msgpack tree's transaction
+        pub type TransactionalTree<'a, V> =
+            expiring::TransactionalTree<'a, V, MsgPackEncoding, MsgPackEncoding>;
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+};
+
+type Operator = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+pub(crate) type TypedOperator<V, M> = dyn Fn(&[u8], Option<V>, M) -> Option<V> + Send + Sync;
+
+// sled only accepts plain function pointers as merge operators, so typed operators are kept here
+// and looked up by the id each merged operand is prefixed with
+static OPERATORS: RwLock<BTreeMap<u64, Arc<Operator>>> = RwLock::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // sled can't report errors from merge operators, but runs them on the thread calling merge,
+    // so failures are left here for it to pick up
+    static FAILURE: RefCell<Option<Error>> = const { RefCell::new(None) };
+}
+
+/// A typed merge operator registered on a structured tree
+///
+/// The operator stays registered for as long as this is alive.
+pub(crate) struct MergeOperator<V> {
+    id: u64,
+    typed: Box<dyn Any + Send + Sync>,
+    value: PhantomData<fn(&V)>,
+}
+
+/// The typed half of a merge operator, for merges that run inside transactions
+pub(crate) struct Typed<V, M> {
+    pub(crate) encode: fn(&M) -> Result<Vec<u8>>,
+    pub(crate) decode: fn(&[u8]) -> Result<M>,
+    pub(crate) merge: Arc<TypedOperator<V, M>>,
+}
+
+impl<V> MergeOperator<V>
+where
+    V: 'static,
+{
+    pub(crate) fn new<E, M, ME, F>(tree: &str, f: F) -> Self
+    where
+        E: Encoding<V> + 'static,
+        M: 'static,
+        ME: Encoding<M> + 'static,
+        F: Fn(&[u8], Option<V>, M) -> Option<V> + Send + Sync + 'static,
+    {
+        let merge: Arc<TypedOperator<V, M>> = Arc::new(f);
+        let operator = merge.clone();
+        let tree = tree.to_owned();
+
+        let erased = move |key: &[u8], old: Option<&[u8]>, operand: &[u8]| {
+            // Values that can't be decoded or encoded are left as they were
+            let fail = |e| {
+                FAILURE.with(|failure| *failure.borrow_mut() = Some(e));
+                old.map(|old| old.to_vec())
+            };
+
+            let operand = match ME::decode(operand) {
+                Ok(operand) => operand,
+                Err(e) => return fail(e),
+            };
+
+            let old_value = match old.map(|v| E::decode_at(&tree, key, v)).transpose() {
+                Ok(old_value) => old_value,
+                Err(e) => return fail(e),
+            };
+
+            match (operator)(key, old_value, operand) {
+                Some(value) => match E::encode_at(&tree, key, &value) {
+                    Ok(v) => Some(v),
+                    Err(e) => fail(e),
+                },
+                None => None,
+            }
+        };
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+        if let Ok(mut operators) = OPERATORS.write() {
+            operators.insert(id, Arc::new(erased));
+        }
+
+        MergeOperator {
+            id,
+            typed: Box::new(Typed {
+                encode: ME::encode,
+                decode: ME::decode,
+                merge,
+            }),
+            value: PhantomData,
+        }
+    }
+
+    /// The typed operator, if it takes operands of type M
+    pub(crate) fn typed<M>(&self) -> Option<&Typed<V, M>>
+    where
+        M: 'static,
+    {
+        self.typed.downcast_ref()
+    }
+
+    /// Prefix an encoded operand with the id of this operator, for use with `sled::Tree::merge`
+    pub(crate) fn operand(&self, encoded: &[u8]) -> Vec<u8> {
+        let mut out = self.id.to_be_bytes().to_vec();
+        out.extend_from_slice(encoded);
+        out
+    }
+}
+
+impl<V> Drop for MergeOperator<V> {
+    fn drop(&mut self) {
+        if let Ok(mut operators) = OPERATORS.write() {
+            operators.remove(&self.id);
+        }
+    }
+}
+
+/// Take the error from the last merge on this thread that failed, if any
+pub(crate) fn take_failure() -> Option<Error> {
+    FAILURE.with(|failure| failure.borrow_mut().take())
+}
+
+/// The merge operator every structured tree hands to sled
+///
+/// Operands for operators that are no longer registered leave the value untouched.
+pub(crate) fn dispatch(key: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+    let operator = if operand.len() >= 8 {
+        let mut id = [0; 8];
+        id.copy_from_slice(&operand[..8]);
+
+        OPERATORS
+            .read()
+            .ok()
+            .and_then(|operators| operators.get(&u64::from_be_bytes(id)).cloned())
+    } else {
+        None
+    };
+
+    match operator {
+        Some(operator) => (operator)(key, old, &operand[8..]),
+        None => old.map(|old| old.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        encoding::Encoding,
+        error::{Error, Result},
+        structured_tree::StructuredTree,
+        Config,
+    };
+
+    /// Numbers up to 1000 stored as decimal text
+    struct Digits;
+
+    /// Numbers that can be encoded but never decoded
+    struct OneWay;
+
+    impl Encoding<u64> for Digits {
+        fn encode(t: &u64) -> Result<Vec<u8>> {
+            if *t > 1000 {
+                return Err(Error::Corrupted(format!("{} is too large", t)));
+            }
+
+            Ok(t.to_string().into_bytes())
+        }
+
+        fn decode(slice: &[u8]) -> Result<u64> {
+            std::str::from_utf8(slice)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| Error::Corrupted("digits".to_owned()))
+        }
+    }
+
+    impl Encoding<u64> for OneWay {
+        fn encode(t: &u64) -> Result<Vec<u8>> {
+            Digits::encode(t)
+        }
+
+        fn decode(_: &[u8]) -> Result<u64> {
+            Err(Error::Corrupted("one way".to_owned()))
+        }
+    }
+
+    fn open(db: &sled::Db) -> StructuredTree<u64, Digits> {
+        let tree = StructuredTree::new(db, "numbers").unwrap();
+        tree.set_merge_operator::<Digits, _, _>(|_, total, n: u64| {
+            Some(total.unwrap_or(0) + n).filter(|total| *total > 0)
+        });
+        tree
+    }
+
+    #[test]
+    fn operands_are_folded_into_values() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        assert_eq!(tree.merge(b"n", 5u64).unwrap(), Some(5));
+        assert_eq!(tree.merge(b"n", 7u64).unwrap(), Some(12));
+        assert_eq!(tree.get(b"n").unwrap(), Some(12));
+
+        tree.set_merge_operator::<Digits, _, _>(|_, _, _: u64| None);
+        assert_eq!(tree.merge(b"n", 1u64).unwrap(), None);
+        assert!(tree.is_empty());
+
+        match tree.merge(b"n", "wrong type") {
+            Err(Error::Sled(sled::Error::Unsupported(_))) => (),
+            _ => panic!("Expected the operand type to be rejected"),
+        }
+    }
+
+    #[test]
+    fn failed_merges_leave_values_unchanged() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+        tree.insert(b"n", 999).unwrap();
+
+        // The merged value can't be encoded
+        match tree.merge(b"n", 2u64) {
+            Err(Error::Corrupted(_)) => (),
+            _ => panic!("Expected the merged value to be unencodable"),
+        }
+        assert_eq!(tree.get(b"n").unwrap(), Some(999));
+        assert_eq!(tree.merge(b"n", 1u64).unwrap(), Some(1000));
+
+        // The stored value can't be decoded
+        tree.sled_tree().insert(b"bad", b"nine").unwrap();
+        match tree.merge(b"bad", 1u64) {
+            Err(Error::Corrupted(_)) => (),
+            _ => panic!("Expected the stored value to be undecodable"),
+        }
+        assert_eq!(
+            tree.sled_tree().get(b"bad").unwrap(),
+            Some(sled::IVec::from(b"nine"))
+        );
+
+        // The operand can't be decoded
+        tree.set_merge_operator::<OneWay, _, _>(|_, total, n: u64| Some(total.unwrap_or(0) + n));
+        match tree.merge(b"n", 1u64) {
+            Err(Error::Corrupted(_)) => (),
+            _ => panic!("Expected the operand to be undecodable"),
+        }
+        assert_eq!(tree.get(b"n").unwrap(), Some(1000));
+    }
+}
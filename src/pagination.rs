@@ -0,0 +1,345 @@
+use sled::IVec;
+use std::ops::Bound;
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    structured_tree::StructuredTree,
+};
+
+const BACKWARD: u8 = 0;
+const FORWARD: u8 = 1;
+
+/// Pages through the values in a structured tree in key order
+///
+/// Pages are positioned by key rather than by offset, so values inserted or removed while a
+/// client is paging never cause values to be skipped or repeated.
+pub struct Paginator<'a, V, E> {
+    tree: &'a StructuredTree<V, E>,
+    page_size: usize,
+    prefix: IVec,
+}
+
+/// A page of values, along with cursors to the pages around it
+#[derive(Clone, Debug)]
+pub struct Page<V> {
+    /// The keys and values on this page, in ascending key order
+    pub items: Vec<(IVec, V)>,
+
+    /// The cursor for the page after this one, if there are more values
+    pub next: Option<Cursor>,
+
+    /// The cursor for the page before this one, if there are more values
+    pub previous: Option<Cursor>,
+}
+
+/// An opaque position to continue paging from
+///
+/// Cursors can be encoded as URL-safe tokens to be handed out to clients.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    key: IVec,
+    forward: bool,
+}
+
+impl<'a, V, E> Paginator<'a, V, E>
+where
+    E: Encoding<V> + 'static,
+{
+    pub(crate) fn new(tree: &'a StructuredTree<V, E>, page_size: usize) -> Self {
+        Paginator {
+            tree,
+            page_size: page_size.max(1),
+            prefix: IVec::from(&[][..]),
+        }
+    }
+
+    /// Only page through values whose keys start with the prefix
+    pub fn prefix<P>(mut self, prefix: P) -> Self
+    where
+        P: AsRef<[u8]>,
+    {
+        self.prefix = IVec::from(prefix.as_ref());
+        self
+    }
+
+    /// Fetch the page with the smallest keys
+    pub fn first(&self) -> Result<Page<V>> {
+        self.fetch(Bound::Unbounded, true)
+    }
+
+    /// Fetch the page with the largest keys
+    pub fn last(&self) -> Result<Page<V>> {
+        self.fetch(Bound::Unbounded, false)
+    }
+
+    /// Fetch the page a cursor points to
+    ///
+    /// Cursors from outside the prefix are rejected with `Error::InvalidCursor`.
+    pub fn page(&self, cursor: &Cursor) -> Result<Page<V>> {
+        if !cursor.key.starts_with(&self.prefix) {
+            return Err(Error::InvalidCursor(cursor.encode()));
+        }
+
+        self.fetch(Bound::Excluded(cursor.key.clone()), cursor.forward)
+    }
+
+    fn fetch(&self, from: Bound<IVec>, forward: bool) -> Result<Page<V>> {
+        let name = self.tree.name();
+        let mut items = self
+            .keys_from(from, forward)
+            .take(self.page_size + 1)
+            .map(|res| {
+                let (key, v) = res?;
+                let value = E::decode_at(&name, &key, &v)?;
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let more = items.len() > self.page_size;
+        items.truncate(self.page_size);
+
+        if !forward {
+            items.reverse();
+        }
+
+        let (first, last) = match (items.first(), items.last()) {
+            (Some((first, _)), Some((last, _))) => (first.clone(), last.clone()),
+            _ => {
+                return Ok(Page {
+                    items,
+                    next: None,
+                    previous: None,
+                })
+            }
+        };
+
+        let (has_next, has_previous) = if forward {
+            (more, self.exists(&first, false)?)
+        } else {
+            (self.exists(&last, true)?, more)
+        };
+
+        Ok(Page {
+            items,
+            next: if has_next {
+                Some(Cursor {
+                    key: last,
+                    forward: true,
+                })
+            } else {
+                None
+            },
+            previous: if has_previous {
+                Some(Cursor {
+                    key: first,
+                    forward: false,
+                })
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Whether any value in scope lies beyond the key in the given direction
+    fn exists(&self, key: &IVec, forward: bool) -> Result<bool> {
+        match self.keys_from(Bound::Excluded(key.clone()), forward).next() {
+            Some(res) => res.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Iterate over the raw entries in scope, starting after `from` in the given direction
+    fn keys_from(
+        &self,
+        from: Bound<IVec>,
+        forward: bool,
+    ) -> impl Iterator<Item = Result<(IVec, IVec)>> {
+        let tree = self.tree.sled_tree();
+        let lower = Bound::Included(self.prefix.clone());
+        let upper = prefix_end(&self.prefix);
+
+        let iter: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> = if forward {
+            Box::new(tree.range((max(lower, from), upper)))
+        } else {
+            Box::new(tree.range((lower, min(upper, from))).rev())
+        };
+
+        iter.map(|res| res.map_err(Error::from))
+    }
+}
+
+impl Cursor {
+    /// Encode the cursor as a URL-safe token
+    pub fn encode(&self) -> String {
+        let mut token = String::with_capacity(2 + self.key.len() * 2);
+        let direction = if self.forward { FORWARD } else { BACKWARD };
+
+        for byte in std::iter::once(&direction).chain(self.key.iter()) {
+            token.push_str(&format!("{:02x}", byte));
+        }
+
+        token
+    }
+
+    /// Decode a cursor from a token produced by `encode`
+    pub fn decode(token: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCursor(token.to_owned());
+
+        let pairs = token.as_bytes().chunks_exact(2);
+
+        if !pairs.remainder().is_empty() {
+            return Err(invalid());
+        }
+
+        let bytes = pairs
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(invalid)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        match bytes.split_first() {
+            Some((&FORWARD, key)) => Ok(Cursor {
+                key: IVec::from(key),
+                forward: true,
+            }),
+            Some((&BACKWARD, key)) => Ok(Cursor {
+                key: IVec::from(key),
+                forward: false,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// The tighter of two lower bounds
+pub(crate) fn max(a: Bound<IVec>, b: Bound<IVec>) -> Bound<IVec> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x > y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// The tighter of two upper bounds
+pub(crate) fn min(a: Bound<IVec>, b: Bound<IVec>) -> Bound<IVec> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x < y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// The upper bound of the keys starting with a prefix
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<IVec> {
+    let mut end = prefix.to_vec();
+
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Bound::Excluded(IVec::from(end));
+        }
+    }
+
+    Bound::Unbounded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, Config};
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn open(db: &sled::Db) -> Tree {
+        let tree = Tree::new(db, "paged").unwrap();
+
+        for key in &[&b"a"[..], b"b1", b"b2", b"b3", b"b\xff", b"c"] {
+            tree.insert(*key, IVec::from(*key)).unwrap();
+        }
+
+        tree
+    }
+
+    fn keys(page: &Page<IVec>) -> Vec<IVec> {
+        page.items.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    #[test]
+    fn pages_stay_within_the_prefix() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+        let pages = tree.paginate(2).prefix(b"b");
+
+        let first = pages.first().unwrap();
+        assert_eq!(keys(&first), vec![IVec::from(b"b1"), IVec::from(b"b2")]);
+        assert!(first.previous.is_none());
+
+        let second = pages.page(first.next.as_ref().unwrap()).unwrap();
+        assert_eq!(keys(&second), vec![IVec::from(b"b3"), IVec::from(b"b\xff")]);
+        assert!(second.next.is_none());
+
+        let back = pages.page(second.previous.as_ref().unwrap()).unwrap();
+        assert_eq!(keys(&back), keys(&first));
+        assert!(back.previous.is_none());
+
+        assert_eq!(keys(&pages.last().unwrap()), keys(&second));
+    }
+
+    #[test]
+    fn prefixes_ending_in_max_bytes_are_bounded() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        let page = tree.paginate(5).prefix(b"b\xff").last().unwrap();
+        assert_eq!(keys(&page), vec![IVec::from(b"b\xff")]);
+        assert!(page.next.is_none() && page.previous.is_none());
+
+        let token = Cursor {
+            key: IVec::from(b"b\xff"),
+            forward: false,
+        };
+        assert!(tree
+            .paginate(5)
+            .prefix(b"b\xff")
+            .page(&token)
+            .unwrap()
+            .items
+            .is_empty());
+    }
+
+    #[test]
+    fn cursors_outside_the_prefix_are_rejected() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        let next = tree.paginate(1).first().unwrap().next.unwrap();
+        let token = next.encode();
+        assert_eq!(Cursor::decode(&token).unwrap(), next);
+
+        match tree.paginate(1).prefix(b"b").page(&next) {
+            Err(Error::InvalidCursor(t)) => assert_eq!(t, token),
+            _ => panic!("Expected an invalid cursor"),
+        }
+
+        match Cursor::decode("02") {
+            Err(Error::InvalidCursor(_)) => (),
+            _ => panic!("Expected an invalid cursor"),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use sled::IVec;
+use std::ops::Bound;
+
+use crate::error::Result;
+
+// How many keys are sampled for each range the tree is split into
+const SAMPLES_PER_RANGE: usize = 256;
+
+/// The bounds of one of the ranges a tree's keys are split into
+pub type KeyRange = (Bound<IVec>, Bound<IVec>);
+
+/// Estimate the keys that split a tree into `n` ranges holding roughly equal numbers of values
+///
+/// Keys are sampled in a single pass without reading values, keeping a fixed-size reservoir so
+/// memory use doesn't grow with the tree.
+pub(crate) fn split_points(tree: &sled::Tree, n: usize) -> Result<Vec<IVec>> {
+    let capacity = n.max(1) * SAMPLES_PER_RANGE;
+    let mut sample = Vec::with_capacity(capacity);
+    let mut random = XorShift(0x9e37_79b9_7f4a_7c15);
+
+    for (seen, key) in tree.iter().keys().enumerate() {
+        let key = key?;
+
+        if sample.len() < capacity {
+            sample.push(key);
+        } else {
+            let slot = (random.next() % (seen as u64 + 1)) as usize;
+
+            if slot < capacity {
+                sample[slot] = key;
+            }
+        }
+    }
+
+    if sample.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    sample.sort();
+
+    let mut points: Vec<IVec> = (1..n)
+        .map(|i| sample[i * sample.len() / n].clone())
+        .filter(|point| Some(point) != sample.first())
+        .collect();
+
+    points.dedup();
+    Ok(points)
+}
+
+/// The ranges between consecutive split points, covering every key
+pub(crate) fn ranges(points: Vec<IVec>) -> Vec<KeyRange> {
+    let mut lower = Bound::Unbounded;
+    let mut ranges = Vec::with_capacity(points.len() + 1);
+
+    for point in points {
+        ranges.push((lower, Bound::Excluded(point.clone())));
+        lower = Bound::Included(point);
+    }
+
+    ranges.push((lower, Bound::Unbounded));
+    ranges
+}
+
+/// A small, fast pseudo-random generator for choosing which sampled keys to replace
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, structured_tree::StructuredTree, Config};
+    use std::ops::RangeBounds;
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn open(db: &sled::Db, n: u32) -> Tree {
+        let tree = Tree::new(db, "split").unwrap();
+
+        for i in 0..n {
+            tree.insert(&i.to_be_bytes()[..], IVec::from(&i.to_be_bytes()[..]))
+                .unwrap();
+        }
+
+        tree
+    }
+
+    #[test]
+    fn ranges_cover_every_key_once() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db, 5000);
+
+        let ranges = tree.split_ranges(4).unwrap();
+        assert_eq!(ranges.len(), 4);
+
+        let mut sizes = Vec::new();
+        for i in 0..5000u32 {
+            let key = IVec::from(&i.to_be_bytes()[..]);
+            let containing = ranges.iter().filter(|range| range.contains(&key)).count();
+            assert_eq!(containing, 1);
+        }
+
+        for range in &ranges {
+            sizes.push(tree.range::<IVec, _>(range.clone()).count());
+        }
+
+        assert_eq!(sizes.iter().sum::<usize>(), 5000);
+        assert!(sizes.iter().all(|size| *size > 500), "{:?}", sizes);
+    }
+
+    #[test]
+    fn small_trees_split_into_fewer_ranges() {
+        let db = Config::default().temporary(true).open().unwrap();
+
+        assert_eq!(open(&db, 0).split_ranges(4).unwrap().len(), 1);
+        assert!(open(&db, 2).split_points(8).unwrap().len() <= 1);
+        assert_eq!(
+            ranges(Vec::new()),
+            vec![(Bound::Unbounded, Bound::Unbounded)]
+        );
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter_visits_every_value() {
+        use rayon::iter::ParallelIterator;
+
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db, 1000);
+
+        let mut keys: Vec<IVec> = tree
+            .par_iter()
+            .unwrap()
+            .map(|res| res.map(|(key, _)| key))
+            .collect::<Result<_>>()
+            .unwrap();
+        keys.sort();
+
+        assert_eq!(keys.len(), 1000);
+        keys.dedup();
+        assert_eq!(keys.len(), 1000);
+    }
+}
@@ -0,0 +1,360 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::ops::Bound;
+
+use crate::{
+    error::{Error, Result},
+    transaction::atomically,
+};
+
+const READY: u8 = 0;
+const BUILDING: u8 = 1;
+
+const UNCLAIMED: u8 = 0;
+const CLAIMED_UP_TO: u8 = 1;
+const CLAIMED_REST: u8 = 2;
+
+/// How many keys a rebuild claims at a time, unless it is told otherwise
+pub(crate) const CHUNK: usize = 1024;
+
+/// The progress of rebuilding data derived from every key in a tree, while it is written to
+///
+/// The state is stored in a metadata tree under the name of what is being rebuilt, after a
+/// prefix describing how it is configured. A state stored with another prefix belongs to another
+/// configuration.
+///
+/// A rebuild claims a chunk of keys in a transaction, reads them outside of a transaction, and
+/// then commits what it read in a transaction that fails if a write to the claimed chunk was seen
+/// in the meantime, in which case the chunk is read again. Claiming waits for writers that are
+/// still running, so their writes are either seen by the read or mark the claim dirty.
+pub(crate) struct Progress {
+    kind: &'static str,
+    name: String,
+    configured: Vec<u8>,
+}
+
+/// Whether a rebuild is done, or how far it has progressed
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum State {
+    Ready,
+    Building {
+        counted: Option<IVec>,
+        claim: Claim,
+        dirty: bool,
+    },
+}
+
+/// The keys a rebuild is reading outside of a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Claim {
+    None,
+    UpTo(IVec),
+    Rest,
+}
+
+/// A claimed chunk of keys
+pub(crate) struct Claimed {
+    /// The bound the chunk starts after
+    pub(crate) after: Bound<IVec>,
+
+    /// The bound the chunk ends at
+    pub(crate) upper: Bound<IVec>,
+
+    /// The encoded state that claimed the chunk
+    pub(crate) state: Vec<u8>,
+
+    /// The encoded state once the chunk is committed
+    pub(crate) next: Vec<u8>,
+}
+
+/// What a write has to do while a rebuild is in progress
+pub(crate) enum Deferral {
+    /// The key was already rebuilt, so the write is applied
+    Apply,
+
+    /// The rebuild will read the key later
+    Defer,
+
+    /// The rebuild is reading the key, so the claim has to be stored as dirty
+    Dirty(State),
+}
+
+impl State {
+    /// A rebuild that has not started
+    pub(crate) fn restart() -> Self {
+        State::Building {
+            counted: None,
+            claim: Claim::None,
+            dirty: false,
+        }
+    }
+
+    /// What a write to the key has to do
+    pub(crate) fn defers(self, key: &[u8]) -> Deferral {
+        let (counted, claim, dirty) = match self {
+            State::Ready => return Deferral::Apply,
+            State::Building {
+                counted,
+                claim,
+                dirty,
+            } => (counted, claim, dirty),
+        };
+
+        if matches!(counted, Some(ref counted) if key <= &counted[..]) {
+            return Deferral::Apply;
+        }
+
+        let claimed = match claim {
+            Claim::None => false,
+            Claim::UpTo(ref last) => key <= &last[..],
+            Claim::Rest => true,
+        };
+
+        // The rebuild will read this key, but has to start over if it was already reading it
+        if claimed && !dirty {
+            Deferral::Dirty(State::Building {
+                counted,
+                claim,
+                dirty: true,
+            })
+        } else {
+            Deferral::Defer
+        }
+    }
+}
+
+impl Progress {
+    pub(crate) fn new(kind: &'static str, name: &str, configured: &[u8]) -> Self {
+        Progress {
+            kind,
+            name: name.to_owned(),
+            configured: configured.to_vec(),
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Read the stored state, or `None` if it is missing or stored for another configuration
+    pub(crate) fn state(&self, meta: &sled::Tree) -> Result<Option<State>> {
+        self.decode(meta.get(&self.name)?.as_deref())
+    }
+
+    /// Read the stored state within a transaction
+    pub(crate) fn state_in(
+        &self,
+        meta: &TransactionalTree,
+    ) -> ConflictableTransactionResult<Result<Option<State>>> {
+        Ok(self.decode(meta.get(self.name.as_bytes())?.as_deref()))
+    }
+
+    /// Store a state within a transaction
+    pub(crate) fn store(
+        &self,
+        meta: &TransactionalTree,
+        state: &State,
+    ) -> ConflictableTransactionResult<()> {
+        meta.insert(self.name.as_bytes(), self.encode(state))?;
+        Ok(())
+    }
+
+    /// Start the rebuild over, once writers that are still running have finished
+    ///
+    /// The metadata tree is the last of the trees.
+    pub(crate) fn restart(&self, trees: &[sled::Tree]) -> Result<()> {
+        let restart = self.encode(&State::restart());
+
+        atomically(trees, |views| {
+            views[views.len() - 1].insert(self.name.as_bytes(), restart.as_slice())?;
+            Ok(Ok(()))
+        })
+    }
+
+    /// Claim the next chunk of up to `chunk_size` keys in the data tree, or `None` when there is
+    /// nothing left
+    ///
+    /// The claim is made in a transaction over the trees, the last of which is the metadata
+    /// tree, so writers that are still running finish first.
+    pub(crate) fn claim(
+        &self,
+        trees: &[sled::Tree],
+        data: &sled::Tree,
+        chunk_size: usize,
+    ) -> Result<Option<Claimed>> {
+        let chunk_size = chunk_size.max(1);
+        let meta = &trees[trees.len() - 1];
+
+        loop {
+            let current = meta.get(&self.name)?;
+
+            let counted = match self.decode(current.as_deref())? {
+                Some(State::Building { counted, .. }) => counted,
+                _ => return Ok(None),
+            };
+
+            let after = match counted {
+                Some(ref key) => Bound::Excluded(key.clone()),
+                None => Bound::Unbounded,
+            };
+
+            let mut chunk = data
+                .range::<IVec, _>((after.clone(), Bound::Unbounded))
+                .keys()
+                .take(chunk_size + 1)
+                .collect::<sled::Result<Vec<_>>>()?;
+
+            let claim = if chunk.len() > chunk_size {
+                chunk.truncate(chunk_size);
+                Claim::UpTo(chunk[chunk_size - 1].clone())
+            } else {
+                Claim::Rest
+            };
+
+            let state = self.encode(&State::Building {
+                counted,
+                claim: claim.clone(),
+                dirty: false,
+            });
+
+            let claimed = atomically(trees, |views| {
+                if views[views.len() - 1].get(&self.name)? != current {
+                    return Ok(Ok(false));
+                }
+
+                views[views.len() - 1].insert(self.name.as_bytes(), state.as_slice())?;
+                Ok(Ok(true))
+            })?;
+
+            if !claimed {
+                continue;
+            }
+
+            let (upper, next) = match claim {
+                Claim::UpTo(last) => (
+                    Bound::Included(last.clone()),
+                    State::Building {
+                        counted: Some(last),
+                        claim: Claim::None,
+                        dirty: false,
+                    },
+                ),
+                _ => (Bound::Unbounded, State::Ready),
+            };
+
+            return Ok(Some(Claimed {
+                after,
+                upper,
+                state,
+                next: self.encode(&next),
+            }));
+        }
+    }
+
+    /// Move past a claimed chunk within a transaction, returning `false` if it was dirtied
+    pub(crate) fn commit(
+        &self,
+        meta: &TransactionalTree,
+        claimed: &Claimed,
+    ) -> ConflictableTransactionResult<bool> {
+        if meta.get(self.name.as_bytes())?.as_deref() != Some(claimed.state.as_slice()) {
+            return Ok(false);
+        }
+
+        meta.insert(self.name.as_bytes(), claimed.next.as_slice())?;
+        Ok(true)
+    }
+
+    pub(crate) fn decode(&self, v: Option<&[u8]>) -> Result<Option<State>> {
+        let v = match v {
+            Some(v) if v.starts_with(&self.configured) => &v[self.configured.len()..],
+            _ => return Ok(None),
+        };
+
+        let corrupted = || Error::Corrupted(format!("state of {} {}", self.kind, self.name));
+
+        let (counted, v) = match v {
+            [READY] => return Ok(Some(State::Ready)),
+            [BUILDING, 0, v @ ..] => (None, v),
+            [BUILDING, 1, v @ ..] => {
+                let (key, v) = read_key(v).ok_or_else(corrupted)?;
+                (Some(key), v)
+            }
+            _ => return Err(corrupted()),
+        };
+
+        let (claim, dirty) = match v {
+            [UNCLAIMED, dirty] => (Claim::None, *dirty),
+            [CLAIMED_REST, dirty] => (Claim::Rest, *dirty),
+            [CLAIMED_UP_TO, v @ ..] => match read_key(v).ok_or_else(corrupted)? {
+                (key, [dirty]) => (Claim::UpTo(key), *dirty),
+                _ => return Err(corrupted()),
+            },
+            _ => return Err(corrupted()),
+        };
+
+        Ok(Some(State::Building {
+            counted,
+            claim,
+            dirty: dirty != 0,
+        }))
+    }
+
+    pub(crate) fn encode(&self, state: &State) -> Vec<u8> {
+        let mut out = self.configured.clone();
+
+        let (counted, claim, dirty) = match state {
+            State::Ready => {
+                out.push(READY);
+                return out;
+            }
+            State::Building {
+                counted,
+                claim,
+                dirty,
+            } => (counted, claim, dirty),
+        };
+
+        out.push(BUILDING);
+
+        match counted {
+            Some(key) => {
+                out.push(1);
+                write_key(key, &mut out);
+            }
+            None => out.push(0),
+        }
+
+        match claim {
+            Claim::None => out.push(UNCLAIMED),
+            Claim::Rest => out.push(CLAIMED_REST),
+            Claim::UpTo(key) => {
+                out.push(CLAIMED_UP_TO);
+                write_key(key, &mut out);
+            }
+        }
+
+        out.push(*dirty as u8);
+        out
+    }
+}
+
+fn write_key(key: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    out.extend_from_slice(key);
+}
+
+fn read_key(v: &[u8]) -> Option<(IVec, &[u8])> {
+    if v.len() < 4 {
+        return None;
+    }
+
+    let mut len = [0; 4];
+    len.copy_from_slice(&v[..4]);
+    let len = u32::from_be_bytes(len) as usize;
+
+    if v.len() < 4 + len {
+        return None;
+    }
+
+    Some((IVec::from(&v[4..4 + len]), &v[4 + len..]))
+}
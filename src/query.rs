@@ -0,0 +1,471 @@
+use sled::IVec;
+use std::{
+    collections::BTreeSet,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    encoding::Encoding,
+    error::Result,
+    index::{bound, IndexKey},
+    pagination::{max, min, prefix_end},
+    structured_tree::{StructuredIter, StructuredTree},
+};
+
+/// A condition on the keys a value has in a secondary index
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// The value has the index key
+    Equals(IndexKey),
+
+    /// The value has an index key within the bounds
+    Range(Bound<IndexKey>, Bound<IndexKey>),
+
+    /// The value has an index key starting with the prefix
+    Prefix(IndexKey),
+}
+
+/// How a query finds the values it considers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Plan {
+    /// Read the values through the named index
+    Index(String),
+
+    /// Read every value within the query's key range or prefix
+    Scan,
+}
+
+/// A query over the values in a structured tree
+///
+/// Conditions on indexes are answered through the first index that can be queried, preferring
+/// equality over prefix and range conditions, and fall back to scanning the tree. Every other
+/// condition and filter is checked against each value found.
+pub struct Query<'a, V, E, P = V> {
+    tree: &'a StructuredTree<V, E>,
+    conditions: Vec<(String, Condition)>,
+    filters: Vec<Filter<'a, V>>,
+    range: (Bound<IVec>, Bound<IVec>),
+    prefix: Option<IVec>,
+    offset: usize,
+    limit: Option<usize>,
+    reverse: bool,
+    select: Box<dyn Fn(V) -> P + 'a>,
+}
+
+type Filter<'a, V> = Box<dyn Fn(&V) -> bool + 'a>;
+
+type Source<V> = Box<dyn DoubleEndedIterator<Item = Result<(IVec, V)>>>;
+
+impl Condition {
+    /// Match values with the index key
+    pub fn eq<K>(key: K) -> Self
+    where
+        K: Into<IndexKey>,
+    {
+        Condition::Equals(key.into())
+    }
+
+    /// Match values with an index key within the range
+    pub fn range<K, R>(range: R) -> Self
+    where
+        K: Into<IndexKey> + Clone,
+        R: RangeBounds<K>,
+    {
+        Condition::Range(bound(range.start_bound()), bound(range.end_bound()))
+    }
+
+    /// Match values with an index key starting with the prefix
+    pub fn prefix<K>(prefix: K) -> Self
+    where
+        K: Into<IndexKey>,
+    {
+        Condition::Prefix(prefix.into())
+    }
+
+    /// Whether any of a value's index keys satisfy the condition
+    fn matches(&self, keys: &[IndexKey]) -> bool {
+        match self {
+            Condition::Equals(key) => keys.binary_search(key).is_ok(),
+            Condition::Range(lower, upper) => keys
+                .iter()
+                .any(|key| (lower.as_ref(), upper.as_ref()).contains(key)),
+            Condition::Prefix(prefix) => keys
+                .iter()
+                .any(|key| key.as_bytes().starts_with(prefix.as_bytes())),
+        }
+    }
+
+    /// How useful the condition is for narrowing down values, lowest first
+    fn rank(&self) -> u8 {
+        match self {
+            Condition::Equals(_) => 0,
+            Condition::Prefix(_) => 1,
+            Condition::Range(_, _) => 2,
+        }
+    }
+}
+
+impl<'a, V, E> Query<'a, V, E>
+where
+    E: Encoding<V> + 'static,
+    V: 'static,
+{
+    pub(crate) fn new(tree: &'a StructuredTree<V, E>) -> Self {
+        Query {
+            tree,
+            conditions: Vec::new(),
+            filters: Vec::new(),
+            range: (Bound::Unbounded, Bound::Unbounded),
+            prefix: None,
+            offset: 0,
+            limit: None,
+            reverse: false,
+            select: Box::new(|value| value),
+        }
+    }
+}
+
+impl<'a, V, E, P> Query<'a, V, E, P>
+where
+    E: Encoding<V> + 'static,
+    V: 'static,
+    P: 'a,
+{
+    /// Only include values whose keys in the named index satisfy the condition
+    pub fn where_(mut self, index: &str, condition: Condition) -> Self {
+        self.conditions.push((index.to_owned(), condition));
+        self
+    }
+
+    /// Only include values the predicate accepts
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&V) -> bool + 'a,
+    {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Only include values whose primary keys fall within the range
+    pub fn range<K, R>(mut self, range: R) -> Self
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(IVec::from(key.as_ref())),
+            Bound::Excluded(key) => Bound::Excluded(IVec::from(key.as_ref())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+
+        self.range = (owned(range.start_bound()), owned(range.end_bound()));
+        self
+    }
+
+    /// Only include values whose primary keys start with the prefix
+    pub fn prefix<K>(mut self, prefix: K) -> Self
+    where
+        K: AsRef<[u8]>,
+    {
+        self.prefix = Some(IVec::from(prefix.as_ref()));
+        self
+    }
+
+    /// Skip the first values that match
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Return at most this many values
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Return values in descending order
+    pub fn reverse(mut self) -> Self {
+        self.reverse = !self.reverse;
+        self
+    }
+
+    /// Transform each matching value before it is returned
+    pub fn select<Q, F>(self, f: F) -> Query<'a, V, E, Q>
+    where
+        F: Fn(P) -> Q + 'a,
+    {
+        let select = self.select;
+
+        Query {
+            tree: self.tree,
+            conditions: self.conditions,
+            filters: self.filters,
+            range: self.range,
+            prefix: self.prefix,
+            offset: self.offset,
+            limit: self.limit,
+            reverse: self.reverse,
+            select: Box::new(move |value| (f)((select)(value))),
+        }
+    }
+
+    /// Decide how the query will find the values it considers
+    pub fn plan(&self) -> Result<Plan> {
+        Ok(match self.planned()? {
+            Some(i) => Plan::Index(self.conditions[i].0.clone()),
+            None => Plan::Scan,
+        })
+    }
+
+    /// Run the query, returning the primary key and selected result of each matching value
+    pub fn fetch(self) -> Result<Vec<(IVec, P)>> {
+        let planned = self.planned()?;
+
+        let source: Source<V> = match planned {
+            Some(i) => {
+                let (name, condition) = &self.conditions[i];
+
+                match condition {
+                    Condition::Equals(key) => {
+                        let (lower, upper) = self.bounds();
+
+                        Box::new(self.tree.find_index(name)?.get_within::<E>(
+                            self.tree.sled_tree(),
+                            key,
+                            lower.as_ref().map(|key| &key[..]),
+                            upper.as_ref().map(|key| &key[..]),
+                        ))
+                    }
+                    Condition::Range(lower, upper) => Box::new(
+                        self.tree
+                            .range_by_index(name, (lower.clone(), upper.clone()))?,
+                    ),
+                    Condition::Prefix(prefix) => {
+                        Box::new(self.tree.scan_prefix_by_index(name, prefix.clone())?)
+                    }
+                }
+            }
+            None => Box::new(self.scan()),
+        };
+
+        let source: Source<V> = if self.reverse {
+            Box::new(source.rev())
+        } else {
+            source
+        };
+
+        let mut residual = Vec::new();
+
+        for (j, (name, condition)) in self.conditions.iter().enumerate() {
+            if Some(j) != planned {
+                residual.push((self.tree.find_index(name)?, condition));
+            }
+        }
+
+        let range = (self.range.0.as_ref(), self.range.1.as_ref());
+        let mut results = Vec::new();
+        let mut seen = BTreeSet::new();
+        let mut skipped = 0;
+
+        for res in source {
+            if self.limit.is_some_and(|limit| results.len() >= limit) {
+                break;
+            }
+
+            let (key, value) = res?;
+
+            if !range.contains(&key) {
+                continue;
+            }
+
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    continue;
+                }
+            }
+
+            // Values with several index keys within the condition are found more than once
+            if planned.is_some() && !seen.insert(key.clone()) {
+                continue;
+            }
+
+            let accepted = residual
+                .iter()
+                .all(|(index, condition)| condition.matches(&index.extract(&value)))
+                && self.filters.iter().all(|filter| (filter)(&value));
+
+            if !accepted {
+                continue;
+            }
+
+            if skipped < self.offset {
+                skipped += 1;
+                continue;
+            }
+
+            results.push((key, (self.select)(value)));
+        }
+
+        Ok(results)
+    }
+
+    /// The condition that will be answered through its index, if any
+    fn planned(&self) -> Result<Option<usize>> {
+        let mut best: Option<usize> = None;
+
+        for (i, (name, condition)) in self.conditions.iter().enumerate() {
+            if !self.tree.find_index(name)?.is_ready()? {
+                continue;
+            }
+
+            if best.is_none_or(|j| condition.rank() < self.conditions[j].1.rank()) {
+                best = Some(i);
+            }
+        }
+
+        Ok(best)
+    }
+
+    fn scan(&self) -> StructuredIter<V, E> {
+        self.tree.range::<IVec, _>(self.bounds())
+    }
+
+    /// The primary key bounds of the query's range and prefix combined
+    fn bounds(&self) -> (Bound<IVec>, Bound<IVec>) {
+        let (lower, upper) = self.range.clone();
+
+        match &self.prefix {
+            Some(prefix) => (
+                max(lower, Bound::Included(prefix.clone())),
+                min(upper, prefix_end(prefix)),
+            ),
+            None => (lower, upper),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, Config};
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn bytes(value: &IVec) -> Vec<IndexKey> {
+        value.iter().map(|b| IndexKey::from(vec![*b])).collect()
+    }
+
+    fn open(db: &sled::Db) -> Tree {
+        let tree = Tree::new(db, "queried")
+            .unwrap()
+            .with_index("bytes", bytes)
+            .unwrap()
+            .with_unique_index("value", |value: &IVec| vec![value.clone().into()])
+            .unwrap();
+
+        for (key, value) in &[
+            (&b"a1"[..], &b"xyz"[..]),
+            (b"a2", b"xy"),
+            (b"a\xff", b"x"),
+            (b"b1", b"yx"),
+            (b"b2", b"z"),
+        ] {
+            tree.insert(*key, IVec::from(*value)).unwrap();
+        }
+
+        tree
+    }
+
+    fn keys<P>(results: Vec<(IVec, P)>) -> Vec<IVec> {
+        results.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn values_with_several_matching_keys_are_returned_once() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        let query = tree
+            .query()
+            .where_("bytes", Condition::range(&b"x"[..]..=&b"z"[..]));
+        assert_eq!(query.plan().unwrap(), Plan::Index("bytes".to_owned()));
+        assert_eq!(
+            keys(query.offset(1).limit(3).fetch().unwrap()),
+            vec![IVec::from(b"a2"), IVec::from(b"a\xff"), IVec::from(b"b1")]
+        );
+
+        assert_eq!(
+            keys(
+                tree.query()
+                    .where_("bytes", Condition::prefix(&b""[..]))
+                    .reverse()
+                    .fetch()
+                    .unwrap()
+            ),
+            vec![
+                IVec::from(b"b2"),
+                IVec::from(b"a1"),
+                IVec::from(b"b1"),
+                IVec::from(b"a2"),
+                IVec::from(b"a\xff"),
+            ]
+        );
+    }
+
+    #[test]
+    fn range_and_prefix_narrow_each_other() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        assert_eq!(
+            keys(
+                tree.query()
+                    .prefix(b"a")
+                    .range(&b"a2"[..]..)
+                    .fetch()
+                    .unwrap()
+            ),
+            vec![IVec::from(b"a2"), IVec::from(b"a\xff")]
+        );
+        assert_eq!(
+            keys(tree.query().prefix(b"a\xff").fetch().unwrap()),
+            vec![IVec::from(b"a\xff")]
+        );
+        assert_eq!(
+            keys(
+                tree.query()
+                    .prefix(b"b")
+                    .range(..&b"b2"[..])
+                    .fetch()
+                    .unwrap()
+            ),
+            vec![IVec::from(b"b1")]
+        );
+    }
+
+    #[test]
+    fn equality_conditions_apply_key_bounds() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        assert_eq!(
+            keys(
+                tree.query()
+                    .where_("bytes", Condition::eq(&b"x"[..]))
+                    .prefix(b"a")
+                    .range(..=&b"a2"[..])
+                    .reverse()
+                    .fetch()
+                    .unwrap()
+            ),
+            vec![IVec::from(b"a2"), IVec::from(b"a1")]
+        );
+
+        let query = tree
+            .query()
+            .where_("value", Condition::eq(&b"z"[..]))
+            .prefix(b"a");
+        assert_eq!(query.plan().unwrap(), Plan::Index("value".to_owned()));
+        assert!(query.fetch().unwrap().is_empty());
+    }
+}
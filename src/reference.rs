@@ -0,0 +1,518 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::marker::PhantomData;
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    hook::{Hook, Hooks, Write},
+    index::{successor, terminated},
+    structured_tree::StructuredTransactionalTree,
+};
+
+/// What happens to referencing values when the value they reference is removed
+pub enum OnDelete<V> {
+    /// Refuse to remove a value while anything references it
+    Restrict,
+
+    /// Remove every value that references the removed value
+    Cascade,
+
+    /// Clear the reference in every value that references the removed value
+    ///
+    /// The provided function must update the value so that it no longer references anything.
+    SetNull(Box<dyn Fn(&mut V) + Send + Sync>),
+}
+
+pub(crate) type Extractor<V> = dyn Fn(&V) -> Option<IVec> + Send + Sync;
+
+/// The referencing side of a reference
+///
+/// Ensures referenced keys exist, and keeps a companion tree with one entry per referencing key,
+/// keyed by the escaped referenced key followed by the referencing key.
+///
+/// Transactions can't scan, so the entries for each referenced key also form a doubly linked
+/// list. Every entry holds the previous and next referencing keys, and the first referencing key
+/// is stored just after the entries, so adding or removing a reference only touches its
+/// neighbours.
+pub(crate) struct Referencing<V> {
+    name: String,
+    trees: [sled::Tree; 2],
+    extractor: Box<Extractor<V>>,
+}
+
+/// The referenced side of a reference
+///
+/// Applies the `OnDelete` behavior to the referencing tree, running that tree's own hooks in the
+/// same transaction.
+pub(crate) struct Referenced<C, CE> {
+    name: String,
+    child_name: String,
+    trees: Vec<sled::Tree>,
+    child: Hooks<C>,
+    on_delete: OnDelete<C>,
+    encoding: PhantomData<CE>,
+}
+
+impl<V> OnDelete<V> {
+    /// Clear references with the provided function when the referenced value is removed
+    pub fn set_null<F>(f: F) -> Self
+    where
+        F: Fn(&mut V) + Send + Sync + 'static,
+    {
+        OnDelete::SetNull(Box::new(f))
+    }
+}
+
+impl<V> Referencing<V> {
+    pub(crate) fn new<F>(name: &str, parent: &sled::Tree, refs: &sled::Tree, extractor: F) -> Self
+    where
+        F: Fn(&V) -> Option<IVec> + Send + Sync + 'static,
+    {
+        Referencing {
+            name: name.to_owned(),
+            trees: [parent.clone(), refs.clone()],
+            extractor: Box::new(extractor),
+        }
+    }
+
+    /// The referenced keys before and after a write, if they differ
+    fn changes(&self, write: &Write<V>) -> Option<(Option<IVec>, Option<IVec>)> {
+        let old = write.old.and_then(|old| (self.extractor)(old));
+        let new = write.new.and_then(|(new, _)| (self.extractor)(new));
+
+        if old == new {
+            None
+        } else {
+            Some((old, new))
+        }
+    }
+}
+
+impl<V> Hook<V> for Referencing<V> {
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn check(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        if let Some((_, Some(key))) = self.changes(write) {
+            if trees[0].get(&key)?.is_none() {
+                return Ok(Err(Error::MissingReference {
+                    reference: self.name.clone(),
+                    key,
+                }));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        let refs = &trees[1];
+
+        let (old, new) = match self.changes(write) {
+            Some(changes) => changes,
+            None => return Ok(Ok(())),
+        };
+
+        if let Some(old) = old {
+            if let Err(e) = unlink(refs, &old, write.key)? {
+                return Ok(Err(e));
+            }
+        }
+
+        if let Some(new) = new {
+            if let Err(e) = link(refs, &new, write.key)? {
+                return Ok(Err(e));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+impl<C, CE> Referenced<C, CE> {
+    pub(crate) fn new(
+        name: &str,
+        refs: &sled::Tree,
+        child_name: &str,
+        child: Hooks<C>,
+        on_delete: OnDelete<C>,
+    ) -> Self {
+        let mut trees = vec![refs.clone()];
+        trees.extend(child.trees().iter().cloned());
+
+        Referenced {
+            name: name.to_owned(),
+            child_name: child_name.to_owned(),
+            trees,
+            child,
+            on_delete,
+            encoding: PhantomData,
+        }
+    }
+}
+
+impl<P, C, CE> Hook<P> for Referenced<C, CE>
+where
+    CE: Encoding<C> + Send + Sync,
+{
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn check(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<P>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        if write.old.is_none() || write.new.is_some() {
+            return Ok(Ok(()));
+        }
+
+        if let OnDelete::Restrict = self.on_delete {
+            if let Some(key) = trees[0].get(successor(write.key))? {
+                return Ok(Err(Error::RestrictedDelete {
+                    reference: self.name.clone(),
+                    key,
+                }));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<P>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        if write.old.is_none() || write.new.is_some() {
+            return Ok(Ok(()));
+        }
+
+        let keys = match referencing(&trees[0], write.key)? {
+            Ok(keys) => keys,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let child =
+            StructuredTransactionalTree::<C, CE>::new(&self.child_name, &trees[1..], &self.child);
+
+        for key in keys {
+            let res = match self.on_delete {
+                OnDelete::Restrict => Ok(()),
+                OnDelete::Cascade => child.write(&key, None)?.map(|_| ()),
+                OnDelete::SetNull(ref f) => match child.get(&key)? {
+                    Ok(Some(mut value)) => {
+                        (f)(&mut value);
+
+                        match CE::encode_at(&self.child_name, &key, &value) {
+                            Ok(v) => child.write(&key, Some((&value, &v)))?.map(|_| ()),
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                },
+            };
+
+            if let Err(e) = res {
+                return Ok(Err(e));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+/// The keys that reference a key
+fn referencing(
+    refs: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Result<Vec<IVec>>> {
+    let prefix = terminated(key);
+    let mut keys = Vec::new();
+    let mut next = refs.get(successor(key))?;
+
+    while let Some(child) = next {
+        let entry = match refs.get(entry(&prefix, &child))? {
+            Some(entry) => entry,
+            None => return Ok(Err(corrupted())),
+        };
+
+        next = match decode_link(&entry) {
+            Ok((_, next)) => next,
+            Err(e) => return Ok(Err(e)),
+        };
+        keys.push(child);
+    }
+
+    Ok(Ok(keys))
+}
+
+/// Record that `child` references `parent`, at the head of the parent's list
+fn link(
+    refs: &TransactionalTree,
+    parent: &[u8],
+    child: &[u8],
+) -> ConflictableTransactionResult<Result<()>> {
+    let prefix = terminated(parent);
+    let head = successor(parent);
+
+    if let Some(first) = refs.get(&head)? {
+        if let Err(e) = relink(refs, &prefix, &first, |link| link.0 = Some(child.into()))? {
+            return Ok(Err(e));
+        }
+
+        refs.insert(entry(&prefix, child), encode_link(None, Some(&first)))?;
+    } else {
+        refs.insert(entry(&prefix, child), encode_link(None, None))?;
+    }
+
+    refs.insert(head, child)?;
+    Ok(Ok(()))
+}
+
+/// Forget that `child` references `parent`
+///
+/// Values written before the reference was declared have no entry, and are left alone.
+fn unlink(
+    refs: &TransactionalTree,
+    parent: &[u8],
+    child: &[u8],
+) -> ConflictableTransactionResult<Result<()>> {
+    let prefix = terminated(parent);
+
+    let (prev, next) = match refs.remove(entry(&prefix, child))? {
+        Some(entry) => match decode_link(&entry) {
+            Ok(link) => link,
+            Err(e) => return Ok(Err(e)),
+        },
+        None => return Ok(Ok(())),
+    };
+
+    if let Some(ref next) = next {
+        let prev = prev.clone();
+
+        if let Err(e) = relink(refs, &prefix, next, move |link| link.0 = prev)? {
+            return Ok(Err(e));
+        }
+    }
+
+    match prev {
+        Some(prev) => relink(refs, &prefix, &prev, move |link| link.1 = next),
+        None => {
+            match next {
+                Some(next) => refs.insert(successor(parent), next)?,
+                None => refs.remove(successor(parent))?,
+            };
+
+            Ok(Ok(()))
+        }
+    }
+}
+
+/// Update the neighbours stored in a referencing key's entry
+fn relink<F>(
+    refs: &TransactionalTree,
+    prefix: &[u8],
+    child: &[u8],
+    f: F,
+) -> ConflictableTransactionResult<Result<()>>
+where
+    F: FnOnce(&mut (Option<IVec>, Option<IVec>)),
+{
+    let key = entry(prefix, child);
+
+    let mut link = match refs.get(&key)? {
+        Some(entry) => match decode_link(&entry) {
+            Ok(link) => link,
+            Err(e) => return Ok(Err(e)),
+        },
+        None => return Ok(Err(corrupted())),
+    };
+
+    (f)(&mut link);
+    refs.insert(key, encode_link(link.0.as_deref(), link.1.as_deref()))?;
+    Ok(Ok(()))
+}
+
+fn entry(prefix: &[u8], child: &[u8]) -> Vec<u8> {
+    let mut out = prefix.to_vec();
+    out.extend_from_slice(child);
+    out
+}
+
+fn encode_link(prev: Option<&[u8]>, next: Option<&[u8]>) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    for key in &[prev, next] {
+        match key {
+            Some(key) => {
+                out.push(1);
+                out.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                out.extend_from_slice(key);
+            }
+            None => out.push(0),
+        }
+    }
+
+    out
+}
+
+fn decode_link(mut v: &[u8]) -> Result<(Option<IVec>, Option<IVec>)> {
+    let mut keys = [None, None];
+
+    for key in &mut keys {
+        match v.first() {
+            Some(0) => v = &v[1..],
+            Some(1) if v.len() >= 5 => {
+                let mut len = [0; 4];
+                len.copy_from_slice(&v[1..5]);
+                let len = u32::from_be_bytes(len) as usize;
+
+                if v.len() < 5 + len {
+                    return Err(corrupted());
+                }
+
+                *key = Some(IVec::from(&v[5..5 + len]));
+                v = &v[5 + len..];
+            }
+            _ => return Err(corrupted()),
+        }
+    }
+
+    if !v.is_empty() {
+        return Err(corrupted());
+    }
+
+    let [prev, next] = keys;
+    Ok((prev, next))
+}
+
+fn corrupted() -> Error {
+    Error::Corrupted("list of referencing keys".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, structured_tree::StructuredTree, Config};
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn open(db: &sled::Db, on_delete: OnDelete<IVec>) -> (Tree, Tree) {
+        let mut parents = Tree::new(db, "parents").unwrap();
+        let children = Tree::new(db, "children")
+            .unwrap()
+            .with_reference(
+                "parent",
+                &mut parents,
+                |value: &IVec| Some(value.clone()).filter(|value| !value.is_empty()),
+                on_delete,
+            )
+            .unwrap();
+
+        (parents, children)
+    }
+
+    fn keys(tree: &Tree) -> Vec<IVec> {
+        tree.iter().keys().collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn missing_references_are_rejected() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let (_, children) = open(&db, OnDelete::Cascade);
+
+        match children.insert(b"child", IVec::from(b"nobody")) {
+            Err(Error::MissingReference { reference, key }) => {
+                assert_eq!(reference, "parent");
+                assert_eq!(key, IVec::from(b"nobody"));
+            }
+            _ => panic!("Expected a missing reference"),
+        }
+        assert!(children.is_empty());
+    }
+
+    #[test]
+    fn restrict_checks_the_remaining_references() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let (parents, children) = open(&db, OnDelete::Restrict);
+        parents.insert(b"p", IVec::from(b"")).unwrap();
+        parents.insert(b"q", IVec::from(b"")).unwrap();
+
+        for key in &[&b"a"[..], b"b", b"c"] {
+            children.insert(*key, IVec::from(b"p")).unwrap();
+        }
+
+        // Unlink from the middle, the tail and the head of the list
+        children.insert(b"b", IVec::from(b"q")).unwrap();
+        children.remove(b"a").unwrap();
+
+        match parents.remove(b"p") {
+            Err(Error::RestrictedDelete { reference, key }) => {
+                assert_eq!(reference, "parent");
+                assert_eq!(key, IVec::from(b"c"));
+            }
+            _ => panic!("Expected a restricted delete"),
+        }
+
+        children.remove(b"c").unwrap();
+        parents.remove(b"p").unwrap();
+        assert_eq!(keys(&parents), vec![IVec::from(b"q")]);
+    }
+
+    #[test]
+    fn cascade_removes_every_reference() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let (parents, children) = open(&db, OnDelete::Cascade);
+        parents.insert(b"p", IVec::from(b"")).unwrap();
+        parents.insert(b"p\0", IVec::from(b"")).unwrap();
+
+        for i in 0..5u8 {
+            children.insert(&[i][..], IVec::from(b"p")).unwrap();
+        }
+        children.insert(b"other", IVec::from(b"p\0")).unwrap();
+        children.remove(&[2u8][..]).unwrap();
+
+        parents.remove(b"p").unwrap();
+        assert_eq!(keys(&children), vec![IVec::from(b"other")]);
+
+        parents.remove(b"p\0").unwrap();
+        assert!(children.is_empty());
+        assert!(db
+            .open_tree("children-references-parent")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn set_null_updates_referencing_values() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let (parents, children) = open(
+            &db,
+            OnDelete::set_null(|value: &mut IVec| *value = IVec::from(b"")),
+        );
+        parents.insert(b"p", IVec::from(b"")).unwrap();
+
+        children.insert(b"a", IVec::from(b"p")).unwrap();
+        children.insert(b"b", IVec::from(b"p")).unwrap();
+        parents.remove(b"p").unwrap();
+
+        assert_eq!(children.get(b"a").unwrap(), Some(IVec::from(b"")));
+        assert_eq!(children.get(b"b").unwrap(), Some(IVec::from(b"")));
+        assert!(db
+            .open_tree("children-references-parent")
+            .unwrap()
+            .is_empty());
+    }
+}
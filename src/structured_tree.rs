@@ -1,9 +1,32 @@
 use sled::IVec;
-use std::{marker::PhantomData, ops::RangeBounds};
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    marker::PhantomData,
+    ops::RangeBounds,
+    sync::{Arc, RwLock},
+};
+
+#[cfg(feature = "zstd")]
+use crate::compress::train_dictionary;
 
 use crate::{
-    encoding::Encoding,
-    error::{coerce, Result},
+    aggregate::{Aggregate, Counter},
+    bulk::BulkLoader,
+    changelog::Changelog,
+    encoding::{BorrowedEncoding, Encoding, TreeState},
+    encrypt::{reencrypt, Rekey},
+    error::{coerce, Error, Result},
+    hook::{Hook, Hooks, Write},
+    index::{bound, CoveringIter, Index, IndexIter, IndexKey},
+    merge::{dispatch, take_failure, MergeOperator},
+    pagination::Paginator,
+    parallel::{ranges, split_points, KeyRange},
+    query::Query,
+    reference::{OnDelete, Referenced, Referencing},
+    text::{TextIndex, TextQuery, Tokenizer},
+    transaction::{atomically, transaction},
+    view::{view_tree, MapReduce, Rebuild, View},
 };
 
 /// Compare and swap error.
@@ -16,39 +39,1091 @@ pub struct CompareAndSwapError<V> {
 
 #[derive(Clone)]
 /// A flash-sympathetic persistent lock-free B+ tree
-pub struct StructuredTree<V, E>(sled::Tree, String, PhantomData<V>, PhantomData<E>);
+pub struct StructuredTree<V, E> {
+    db: sled::Db,
+    tree: sled::Tree,
+    name: Arc<str>,
+    hooks: Hooks<V>,
+    indexes: Vec<Arc<Index<V>>>,
+    texts: Vec<Arc<TextIndex<V>>>,
+    counters: Vec<Arc<Counter>>,
+    views: Vec<Arc<dyn Rebuild>>,
+    state: Vec<TreeState>,
+    merge: Arc<RwLock<Option<Arc<MergeOperator<V>>>>>,
+    encoding: PhantomData<E>,
+}
 
 /// An iterator over keys and values in a `Tree`.
-pub struct StructuredIter<V, E>(sled::Iter, PhantomData<V>, PhantomData<E>);
+pub struct StructuredIter<V, E>(sled::Iter, Arc<str>, PhantomData<V>, PhantomData<E>);
+
+/// A value read from a `Tree` that views can be decoded from without copying
+pub struct StructuredRef<E>(IVec, PhantomData<E>);
+
+/// A key and its still-encoded value, decoded only when asked for
+pub struct StructuredEntry<V, E> {
+    tree: Arc<str>,
+    key: IVec,
+    value: IVec,
+    decoded: PhantomData<V>,
+    encoding: PhantomData<E>,
+}
+
+/// An event that happened to a key that a subscriber is interested in.
+#[derive(Clone, Debug)]
+pub enum StructuredEvent<V> {
+    /// A new complete (key, value) pair
+    Insert(IVec, V),
+    /// A deleted key
+    Remove(IVec),
+}
+
+/// A subscriber listening on a specified prefix, decoding the values it witnesses
+pub struct StructuredSubscriber<V, E>(sled::Subscriber, Arc<str>, PhantomData<V>, PhantomData<E>);
 
 #[derive(Clone, Debug, Default)]
 /// A batch of updates that will be applied atomically to the Tree.
-pub struct StructuredBatch<V, E>(sled::Batch, PhantomData<V>, PhantomData<E>);
+///
+/// Values are encoded when the batch is applied.
+pub struct StructuredBatch<V, E>(BTreeMap<IVec, Option<V>>, PhantomData<E>);
+
+/// The writes of a batch, with each new value alongside its encoded bytes
+type EncodedBatch<V> = Vec<(IVec, Option<(V, Vec<u8>)>)>;
 
 #[derive(Clone)]
 /// A transaction that will be applied atomically to the Tree.
-pub struct StructuredTransactionalTree<'a, V, E>(
-    &'a sled::TransactionalTree,
-    PhantomData<V>,
-    PhantomData<E>,
-);
+pub struct StructuredTransactionalTree<'a, V, E> {
+    name: &'a str,
+    tree: &'a sled::TransactionalTree,
+    views: &'a [sled::TransactionalTree],
+    hooks: &'a Hooks<V>,
+    encoding: PhantomData<E>,
+}
 
 impl<V, E> StructuredTree<V, E>
 where
     E: Encoding<V> + 'static,
 {
     pub(crate) fn new(db: &sled::Db, name: &str) -> Result<Self> {
-        Ok(StructuredTree(
-            db.open_tree(name)?,
-            name.to_owned(),
-            PhantomData,
-            PhantomData,
-        ))
+        let tree = db.open_tree(name)?;
+
+        Ok(StructuredTree {
+            db: db.clone(),
+            hooks: Hooks::new(tree.clone()),
+            tree,
+            name: name.into(),
+            indexes: Vec::new(),
+            texts: Vec::new(),
+            counters: Vec::new(),
+            views: Vec::new(),
+            state: E::open(db, name)?,
+            merge: Arc::new(RwLock::new(None)),
+            encoding: PhantomData,
+        })
     }
 
     /// Clone for structures where V and E aren't Clone
     pub fn cloned(&self) -> Self {
-        StructuredTree(self.0.clone(), self.1.clone(), PhantomData, PhantomData)
+        StructuredTree {
+            db: self.db.clone(),
+            tree: self.tree.clone(),
+            name: self.name.clone(),
+            hooks: self.hooks.clone(),
+            indexes: self.indexes.clone(),
+            texts: self.texts.clone(),
+            counters: self.counters.clone(),
+            views: self.views.clone(),
+            state: self.state.clone(),
+            merge: self.merge.clone(),
+            encoding: PhantomData,
+        }
+    }
+
+    /// Record every insert and removal made to this tree in a durable changelog
+    ///
+    /// Changes are written to the log in the same transaction as the data they describe. This
+    /// must be called before the tree is cloned, and only once per tree.
+    ///
+    /// ```rust
+    /// use sled_extensions::{structured::Event, Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<usize>("json-tree")?.with_changelog()?;
+    ///
+    /// tree.insert(b"hey", 32)?;
+    /// tree.remove(b"hey")?;
+    ///
+    /// let consumer = tree.changelog()?.consumer("indexer");
+    /// let changes = consumer.poll(10)?;
+    ///
+    /// match changes.as_slice() {
+    ///     [(0, Event::Insert(_, 32)), (1, Event::Remove(key))] => {
+    ///         assert_eq!(key, b"hey");
+    ///         consumer.commit(1)?;
+    ///     }
+    ///     _ => unreachable!("Should have logged both changes"),
+    /// }
+    ///
+    /// assert!(consumer.poll(10)?.is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_changelog(mut self) -> Result<Self> {
+        let changelog = self.changelog()?;
+        self.add_hook(Arc::new(changelog.hook()))?;
+        Ok(self)
+    }
+
+    /// Maintain a secondary index over the values in this tree
+    ///
+    /// The extractor produces the keys a value can be looked up by, and the index is updated in
+    /// the same transaction as every write to the tree. If the tree already holds values, the
+    /// index can't be queried until `backfill_index` has completed. This must be called before
+    /// the tree is cloned.
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db
+    ///     .open_json_tree::<(String, usize)>("json-tree")?
+    ///     .with_index("name", |(name, _)| vec![name.as_str().into()])?;
+    ///
+    /// tree.insert(b"1", ("alice".to_owned(), 32))?;
+    /// tree.insert(b"2", ("bob".to_owned(), 16))?;
+    /// tree.insert(b"3", ("bobby".to_owned(), 8))?;
+    ///
+    /// let alice = tree.get_by_index("name", "alice")?.values().next().transpose()?;
+    /// assert_eq!(alice, Some(("alice".to_owned(), 32)));
+    ///
+    /// assert_eq!(tree.scan_prefix_by_index("name", "bob")?.count(), 2);
+    /// assert_eq!(tree.range_by_index("name", "b".."bobby")?.count(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_index<F>(self, name: &str, extractor: F) -> Result<Self>
+    where
+        F: Fn(&V) -> Vec<IndexKey> + Send + Sync + 'static,
+        V: 'static,
+    {
+        let index = Index::new(&self.db, &self.tree, &self.name, name, false, extractor)?;
+        self.add_index(index)
+    }
+
+    /// Maintain a secondary index in which no two values may share an index key
+    ///
+    /// Writes that would give a value the same index key as another value fail with
+    /// `Error::UniqueViolation`, and nothing is written. Conflicts with values written before the
+    /// index was registered are reported by `backfill_index` instead.
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt, Error};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db
+    ///     .open_json_tree::<String>("json-tree")?
+    ///     .with_unique_index("email", |email| vec![email.as_str().into()])?;
+    ///
+    /// tree.insert(b"alice", "alice@example.com".to_owned())?;
+    ///
+    /// match tree.insert(b"bob", "alice@example.com".to_owned()) {
+    ///     Err(Error::UniqueViolation { index, key }) => {
+    ///         assert_eq!(index, "email");
+    ///         assert_eq!(key, b"alice");
+    ///     }
+    ///     _ => unreachable!("Should have rejected the duplicate email"),
+    /// }
+    ///
+    /// assert!(!tree.contains_key(b"bob")?);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_unique_index<F>(self, name: &str, extractor: F) -> Result<Self>
+    where
+        F: Fn(&V) -> Vec<IndexKey> + Send + Sync + 'static,
+        V: 'static,
+    {
+        let index = Index::new(&self.db, &self.tree, &self.name, name, true, extractor)?;
+        self.add_index(index)
+    }
+
+    /// Maintain a secondary index that also stores a projection of each value
+    ///
+    /// Listing the projections through `range_covering` or `scan_prefix_covering` reads only the
+    /// index, without decoding the values in this tree. Projections are encoded the same way as
+    /// the values they were taken from.
+    ///
+    /// ```rust
+    /// use sled_extensions::{structured::index::IndexKey, Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db
+    ///     .open_json_tree::<(String, u64, String)>("json-tree")?
+    ///     .with_covering_index(
+    ///         "tenant-created",
+    ///         |(tenant, created, _)| vec![(tenant.as_str(), *created).into()],
+    ///         |(_, created, _)| *created,
+    ///     )?;
+    ///
+    /// tree.insert(b"1", ("acme".to_owned(), 20, "second".to_owned()))?;
+    /// tree.insert(b"2", ("acme".to_owned(), 10, "first".to_owned()))?;
+    /// tree.insert(b"3", ("acme".to_owned(), 30, "third".to_owned()))?;
+    /// tree.insert(b"4", ("other".to_owned(), 15, "elsewhere".to_owned()))?;
+    ///
+    /// let newest: Vec<u64> = tree
+    ///     .scan_prefix_covering("tenant-created", IndexKey::composite().push("acme"))?
+    ///     .values()
+    ///     .rev()
+    ///     .collect::<Result<_, _>>()?;
+    /// assert_eq!(newest, vec![30, 20, 10]);
+    ///
+    /// let early: Vec<u64> = tree
+    ///     .range_covering("tenant-created", ("acme", 10u64)..("acme", 30u64))?
+    ///     .values()
+    ///     .collect::<Result<_, _>>()?;
+    /// assert_eq!(early, vec![10, 20]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_covering_index<F, G, P>(
+        self,
+        name: &str,
+        extractor: F,
+        projection: G,
+    ) -> Result<Self>
+    where
+        F: Fn(&V) -> Vec<IndexKey> + Send + Sync + 'static,
+        G: Fn(&V) -> P + Send + Sync + 'static,
+        E: Encoding<P>,
+        V: 'static,
+    {
+        let index = Index::new(&self.db, &self.tree, &self.name, name, false, extractor)?
+            .covering(move |tree, key, value| E::encode_at(tree, key, &(projection)(value)));
+        self.add_index(index)
+    }
+
+    /// List the projections stored in a covering index for index keys within the range
+    pub fn range_covering<P, K, R>(&self, index: &str, range: R) -> Result<CoveringIter<P, E>>
+    where
+        K: Into<IndexKey> + Clone,
+        R: RangeBounds<K>,
+        E: Encoding<P>,
+    {
+        let lower = bound(range.start_bound());
+        let upper = bound(range.end_bound());
+
+        Ok(self.covering_index(index)?.range_covering(
+            lower.as_ref().map(IndexKey::as_bytes),
+            upper.as_ref().map(IndexKey::as_bytes),
+        ))
+    }
+
+    /// List the projections stored in a covering index for index keys starting with the prefix
+    pub fn scan_prefix_covering<P, K>(&self, index: &str, prefix: K) -> Result<CoveringIter<P, E>>
+    where
+        K: Into<IndexKey>,
+        E: Encoding<P>,
+    {
+        Ok(self
+            .covering_index(index)?
+            .scan_prefix_covering(prefix.into().as_bytes()))
+    }
+
+    fn add_index(mut self, index: Index<V>) -> Result<Self>
+    where
+        V: 'static,
+    {
+        let index = Arc::new(index);
+
+        self.add_hook(index.clone())?;
+        self.indexes.push(index);
+        Ok(self)
+    }
+
+    /// Find the values stored under an index key
+    pub fn get_by_index<K>(&self, index: &str, key: K) -> Result<IndexIter<V, E>>
+    where
+        K: Into<IndexKey>,
+    {
+        let key = key.into();
+
+        self.range_by_index(index, key.clone()..=key)
+    }
+
+    /// Find the values whose index keys fall within the specified range
+    ///
+    /// The iterator is double-ended, so the values can be read in either direction.
+    pub fn range_by_index<K, R>(&self, index: &str, range: R) -> Result<IndexIter<V, E>>
+    where
+        K: Into<IndexKey> + Clone,
+        R: RangeBounds<K>,
+    {
+        let lower = bound(range.start_bound());
+        let upper = bound(range.end_bound());
+
+        Ok(self.index(index)?.range(
+            &self.tree,
+            lower.as_ref().map(IndexKey::as_bytes),
+            upper.as_ref().map(IndexKey::as_bytes),
+        ))
+    }
+
+    /// Find the values whose index keys start with the given prefix
+    pub fn scan_prefix_by_index<K>(&self, index: &str, prefix: K) -> Result<IndexIter<V, E>>
+    where
+        K: Into<IndexKey>,
+    {
+        Ok(self
+            .index(index)?
+            .scan_prefix(&self.tree, prefix.into().as_bytes()))
+    }
+
+    /// Index the values that were written before an index was registered
+    ///
+    /// Values are indexed `chunk_size` at a time, each chunk in its own transaction, so writes
+    /// to the tree can continue while the backfill runs. Progress is stored in the database, and
+    /// a backfill that is interrupted resumes where it left off the next time this is called.
+    /// The index can be queried once this returns.
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt, Error};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<usize>("json-tree")?;
+    ///
+    /// tree.insert(b"hey", 32)?;
+    /// tree.insert(b"hi", 16)?;
+    ///
+    /// let tree = tree.with_index("value", |value| vec![(*value as u64).into()])?;
+    ///
+    /// match tree.get_by_index("value", 32u64) {
+    ///     Err(Error::IndexNotReady(_)) => (),
+    ///     _ => unreachable!("Should not query an index that is being built"),
+    /// }
+    ///
+    /// tree.backfill_index("value", 1)?;
+    ///
+    /// assert!(tree.is_index_ready("value")?);
+    /// assert_eq!(tree.get_by_index("value", 32u64)?.count(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn backfill_index(&self, index: &str, chunk_size: usize) -> Result<()> {
+        let index = self.find_index(index)?;
+
+        while !index.backfill::<E>(&self.tree, chunk_size)? {}
+
+        Ok(())
+    }
+
+    /// Discard an index's entries and index every value in the tree again
+    ///
+    /// The index can't be queried until the rebuild is complete.
+    pub fn rebuild_index(&self, index: &str, chunk_size: usize) -> Result<()> {
+        self.find_index(index)?.reset()?;
+        self.backfill_index(index, chunk_size)
+    }
+
+    /// Returns `true` if the index has been backfilled and can be queried
+    pub fn is_index_ready(&self, index: &str) -> Result<bool> {
+        self.find_index(index)?.is_ready()
+    }
+
+    /// Find an index that can be queried
+    fn index(&self, name: &str) -> Result<&Arc<Index<V>>> {
+        let index = self.find_index(name)?;
+        index.ensure_ready()?;
+        Ok(index)
+    }
+
+    /// Find a covering index that can be queried
+    fn covering_index(&self, name: &str) -> Result<&Arc<Index<V>>> {
+        let index = self.index(name)?;
+
+        if !index.is_covering() {
+            return Err(Error::Sled(sled::Error::Unsupported(format!(
+                "index {} does not store projections",
+                name
+            ))));
+        }
+
+        Ok(index)
+    }
+
+    pub(crate) fn find_index(&self, name: &str) -> Result<&Arc<Index<V>>> {
+        self.indexes
+            .iter()
+            .find(|index| index.name() == name)
+            .ok_or_else(|| Error::UnknownIndex(name.to_owned()))
+    }
+
+    /// Start building a query over the values in this tree
+    ///
+    /// ```rust
+    /// use sled_extensions::{
+    ///     structured::query::{Condition, Plan},
+    ///     Config, DbExt,
+    /// };
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db
+    ///     .open_json_tree::<(String, u64)>("json-tree")?
+    ///     .with_index("age", |(_, age)| vec![(*age).into()])?;
+    ///
+    /// tree.insert(b"1", ("alice".to_owned(), 32))?;
+    /// tree.insert(b"2", ("bob".to_owned(), 16))?;
+    /// tree.insert(b"3", ("carol".to_owned(), 48))?;
+    /// tree.insert(b"4", ("dave".to_owned(), 24))?;
+    ///
+    /// let query = tree
+    ///     .query()
+    ///     .where_("age", Condition::range(20u64..))
+    ///     .filter(|(name, _)| name != "carol")
+    ///     .reverse()
+    ///     .limit(1)
+    ///     .select(|(name, _)| name);
+    ///
+    /// assert_eq!(query.plan()?, Plan::Index("age".to_owned()));
+    /// assert_eq!(query.fetch()?, vec![(b"1".into(), "alice".to_owned())]);
+    ///
+    /// let names: Vec<_> = tree
+    ///     .query()
+    ///     .range(b"2".to_vec()..)
+    ///     .offset(1)
+    ///     .select(|(name, _)| name)
+    ///     .fetch()?
+    ///     .into_iter()
+    ///     .map(|(_, name)| name)
+    ///     .collect();
+    ///
+    /// assert_eq!(names, vec!["carol".to_owned(), "dave".to_owned()]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn query(&self) -> Query<'_, V, E>
+    where
+        V: 'static,
+    {
+        Query::new(self)
+    }
+
+    /// Aggregate over the values whose keys fall within the range
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<u64>("json-tree")?;
+    ///
+    /// tree.insert(b"a1", 3)?;
+    /// tree.insert(b"a2", 5)?;
+    /// tree.insert(b"b1", 7)?;
+    /// tree.insert(b"c1", 1)?;
+    ///
+    /// assert_eq!(tree.aggregate(b"a1".as_ref()..b"c1".as_ref()).count()?, 3);
+    /// assert_eq!(tree.aggregate::<&[u8], _>(..).sum(|n| *n)?, 16);
+    /// assert_eq!(tree.aggregate_prefix(b"a").max(|n| *n)?, Some(5));
+    ///
+    /// let sums = tree.aggregate::<&[u8], _>(..).group_by(1).sum(|n| *n)?;
+    /// assert_eq!(sums[b"a".as_ref()], 8);
+    /// assert_eq!(sums.len(), 3);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn aggregate<K, R>(&self, range: R) -> Aggregate<V, E>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        Aggregate::new(self.tree.range(range), &self.name)
+    }
+
+    /// Aggregate over the values whose keys start with the prefix
+    pub fn aggregate_prefix<P>(&self, prefix: P) -> Aggregate<V, E>
+    where
+        P: AsRef<[u8]>,
+    {
+        Aggregate::new(self.tree.scan_prefix(prefix), &self.name)
+    }
+
+    /// Maintain a count of the values for each key prefix of `prefix_len` bytes
+    ///
+    /// The counter is updated in the same transaction as every insert and removal, so counting
+    /// the values under a prefix no longer requires a scan. Values already in the tree are
+    /// counted when the counter is first registered, a chunk at a time, while writes through
+    /// other handles that registered the same counter continue. This must be called before the
+    /// tree is cloned.
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db
+    ///     .open_json_tree::<usize>("json-tree")?
+    ///     .with_counter("by-tenant", 2)?;
+    ///
+    /// tree.insert(b"t1/a", 1)?;
+    /// tree.insert(b"t1/b", 2)?;
+    /// tree.insert(b"t2/a", 3)?;
+    /// tree.remove(b"t1/a")?;
+    ///
+    /// assert_eq!(tree.count_by_counter("by-tenant", b"t1")?, 1);
+    /// assert_eq!(tree.count_by_counter("by-tenant", b"t")?, 2);
+    /// assert_eq!(tree.count_by_counter("by-tenant", b"")?, 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_counter(mut self, name: &str, prefix_len: usize) -> Result<Self> {
+        let counter = Arc::new(Counter::new(
+            &self.db, &self.tree, &self.name, name, prefix_len,
+        )?);
+
+        self.add_hook(counter.clone())?;
+        self.counters.push(counter);
+        Ok(self)
+    }
+
+    /// Count the values whose keys start with the prefix using a maintained counter
+    ///
+    /// Prefixes up to the counter's prefix length are answered from the counter, longer prefixes
+    /// fall back to scanning the tree.
+    pub fn count_by_counter<P>(&self, counter: &str, prefix: P) -> Result<usize>
+    where
+        P: AsRef<[u8]>,
+    {
+        let counted = self
+            .counters
+            .iter()
+            .find(|c| c.name() == counter)
+            .ok_or_else(|| Error::UnknownCounter(counter.to_owned()))?
+            .count(prefix.as_ref())?;
+
+        match counted {
+            Some(count) => Ok(count),
+            None => self.aggregate_prefix(prefix).count(),
+        }
+    }
+
+    /// Load large numbers of values into this tree
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<u64>("json-tree")?;
+    ///
+    /// let progress = tree
+    ///     .bulk_loader()
+    ///     .batch_size(100)
+    ///     .on_progress(|progress| println!("{} values/s", progress.per_second()))
+    ///     .load((0..1000u64).rev().map(|i| (i.to_be_bytes(), i)))?;
+    ///
+    /// assert_eq!(progress.loaded, 1000);
+    /// assert_eq!(tree.get(500u64.to_be_bytes())?, Some(500));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn bulk_loader(&self) -> BulkLoader<'_, V, E> {
+        BulkLoader::new(self)
+    }
+
+    /// Write values that have already been encoded
+    ///
+    /// Trees with hooks are written in a single transaction.
+    pub(crate) fn write_encoded(&self, entries: &[(IVec, V, Vec<u8>)]) -> Result<()> {
+        if self.hooks.is_empty() {
+            let mut batch = sled::Batch::default();
+
+            for (key, _, v) in entries {
+                batch.insert(key.clone(), v.as_slice());
+            }
+
+            return Ok(self.tree.apply_batch(batch)?);
+        }
+
+        self.atomically(|trans_tree| {
+            for (key, value, v) in entries {
+                if let Err(e) = trans_tree.write(key, Some((value, v)))? {
+                    return Ok(Err(e));
+                }
+            }
+
+            Ok(Ok(()))
+        })
+    }
+
+    /// Page through the values in this tree, `page_size` at a time
+    ///
+    /// ```rust
+    /// use sled_extensions::{structured::pagination::Cursor, Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<usize>("json-tree")?;
+    ///
+    /// for i in 0..5u8 {
+    ///     tree.insert(&[b'a', i], i as usize)?;
+    /// }
+    /// tree.insert(b"b", 10)?;
+    ///
+    /// let pages = tree.paginate(2).prefix(b"a");
+    /// let first = pages.first()?;
+    /// assert_eq!(first.items.len(), 2);
+    /// assert!(first.previous.is_none());
+    ///
+    /// // Cursors survive a round trip through a client
+    /// let token = first.next.expect("There are more pages").encode();
+    /// let second = pages.page(&Cursor::decode(&token)?)?;
+    /// assert_eq!(second.items[0].1, 2);
+    ///
+    /// let back = pages.page(&second.previous.expect("There is a page before"))?;
+    /// assert_eq!(back.items[0].1, 0);
+    ///
+    /// let last = pages.last()?;
+    /// assert_eq!(last.items.iter().map(|(_, v)| *v).collect::<Vec<_>>(), vec![3, 4]);
+    /// assert!(last.next.is_none());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn paginate(&self, page_size: usize) -> Paginator<'_, V, E> {
+        Paginator::new(self, page_size)
+    }
+
+    pub(crate) fn sled_tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+
+    #[cfg(feature = "zstd")]
+    /// Train a new zstd dictionary from up to `samples` of this tree's values
+    ///
+    /// The dictionary is stored alongside the tree and added to the set named by `D`, and values
+    /// written with `ZstdDictionary<D>` are compressed with it from then on. Returns the id of
+    /// the new dictionary.
+    ///
+    /// ```rust
+    /// use sled_extensions::{
+    ///     compression::{Compressed, ZstdDictionary},
+    ///     json::JsonEncoding,
+    ///     structured, Config, DbExt,
+    /// };
+    ///
+    /// struct Users;
+    ///
+    /// type Encoding = Compressed<JsonEncoding, ZstdDictionary<Users>>;
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree: structured::Tree<(String, String, u32), Encoding> =
+    ///     db.open_structured_tree("users")?;
+    ///
+    /// for i in 0..1000u32 {
+    ///     let user = (format!("user-{}", i), format!("user-{}@example.com", i), i % 90);
+    ///     tree.insert(&i.to_be_bytes()[..], user)?;
+    /// }
+    ///
+    /// let id = tree.train_dictionary::<Users>(1000, 4096)?;
+    /// assert_eq!(id, 1);
+    ///
+    /// tree.insert(b"new", ("new".to_owned(), "new@example.com".to_owned(), 30))?;
+    /// assert_eq!(tree.get(b"new")?.map(|user| user.2), Some(30));
+    /// assert_eq!(tree.get(&5u32.to_be_bytes()[..])?.map(|user| user.2), Some(5));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn train_dictionary<D>(&self, samples: usize, max_size: usize) -> Result<u32>
+    where
+        D: 'static,
+    {
+        train_dictionary::<D>(&self.db, &self.tree, &self.name, samples, max_size)
+    }
+
+    /// Re-encrypt the values in this tree that aren't encrypted with the current key
+    ///
+    /// Values are rewritten `chunk_size` at a time without being decoded, so writes to the tree
+    /// can continue while the job runs. Progress is stored in the database, and a job that is
+    /// interrupted resumes where it left off the next time this is called, unless the current
+    /// key has changed since. Returns the number of values that were rewritten.
+    ///
+    /// Only this tree's values are rewritten. Rewriting a value doesn't change what it decodes
+    /// to, so hooks don't run and the changelog doesn't record it. Projections stored in covering
+    /// indexes, the outputs of views, and the values recorded in the changelog keep the key they
+    /// were encrypted with until they are next written, rebuilt or truncated. Old keys have to
+    /// stay available to the `KeyProvider` for as long as any of those remain.
+    ///
+    /// ```rust
+    /// use sled_extensions::{
+    ///     encryption::{ChaCha20Poly1305, Encrypted, KeyProvider},
+    ///     json::JsonEncoding,
+    ///     structured, Config, DbExt,
+    /// };
+    /// use std::sync::atomic::{AtomicU32, Ordering};
+    ///
+    /// static CURRENT: AtomicU32 = AtomicU32::new(1);
+    ///
+    /// struct Keys;
+    ///
+    /// impl KeyProvider for Keys {
+    ///     fn current() -> sled_extensions::Result<(u32, [u8; 32])> {
+    ///         let id = CURRENT.load(Ordering::SeqCst);
+    ///         Ok((id, [id as u8; 32]))
+    ///     }
+    ///
+    ///     fn key(id: u32) -> sled_extensions::Result<Option<[u8; 32]>> {
+    ///         Ok(Some([id as u8; 32]).filter(|_| id <= CURRENT.load(Ordering::SeqCst)))
+    ///     }
+    /// }
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree: structured::Tree<u32, Encrypted<JsonEncoding, Keys, ChaCha20Poly1305>> =
+    ///     db.open_structured_tree("secrets")?;
+    ///
+    /// for i in 0..10u32 {
+    ///     tree.insert(&i.to_be_bytes()[..], i)?;
+    /// }
+    ///
+    /// // Values written with the old key can still be read after rotating
+    /// CURRENT.store(2, Ordering::SeqCst);
+    /// tree.insert(&0u32.to_be_bytes()[..], 0)?;
+    /// assert_eq!(tree.get(&5u32.to_be_bytes()[..])?, Some(5));
+    ///
+    /// assert_eq!(tree.reencrypt(4)?, 9);
+    /// assert_eq!(tree.reencrypt(4)?, 0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn reencrypt(&self, chunk_size: usize) -> Result<usize>
+    where
+        E: Rekey,
+    {
+        reencrypt::<E>(&self.db, &self.tree, &self.name, chunk_size)
+    }
+
+    /// Maintain a full-text index over text extracted from the values in this tree
+    ///
+    /// The tokenizer splits the extracted text into terms, and is also applied to queries. The
+    /// index is updated in the same transaction as every write to the tree. Values already in the
+    /// tree have to be indexed with `backfill_text_index` before the index can be searched. This
+    /// must be called before the tree is cloned.
+    ///
+    /// ```rust
+    /// use sled_extensions::{
+    ///     structured::text::{Query, SimpleTokenizer},
+    ///     Config, DbExt,
+    /// };
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db
+    ///     .open_json_tree::<String>("json-tree")?
+    ///     .with_text_index("body", SimpleTokenizer, |body| body.clone())?;
+    ///
+    /// tree.insert(b"1", "The quick brown fox".to_owned())?;
+    /// tree.insert(b"2", "A quick brown dog, a quick dog".to_owned())?;
+    /// tree.insert(b"3", "Brown bread".to_owned())?;
+    ///
+    /// let results = tree.search("body", &Query::term("quick"))?;
+    /// assert_eq!(results[0].0, b"2");
+    /// assert_eq!(results.len(), 2);
+    ///
+    /// let results = tree.search("body", &Query::phrase("brown fox").or(Query::prefix("bre")))?;
+    /// assert_eq!(results.len(), 2);
+    ///
+    /// let results = tree.search("body", &Query::term("brown").and(!Query::term("dog")))?;
+    /// assert_eq!(results.len(), 2);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_text_index<T, F>(mut self, name: &str, tokenizer: T, extractor: F) -> Result<Self>
+    where
+        T: Tokenizer + 'static,
+        F: Fn(&V) -> String + Send + Sync + 'static,
+        V: 'static,
+    {
+        let index = Arc::new(TextIndex::new(
+            &self.db, &self.name, name, tokenizer, extractor,
+        )?);
+
+        self.add_hook(index.clone())?;
+        self.texts.push(index.clone());
+
+        // An empty tree has nothing to backfill
+        if self.tree.is_empty() {
+            while !index.backfill::<E>(&self.tree, 1)? {}
+        }
+
+        Ok(self)
+    }
+
+    /// Find the keys of values matching a full-text query, ranked by their BM25 score
+    pub fn search(&self, index: &str, query: &TextQuery) -> Result<Vec<(IVec, f64)>> {
+        self.find_text_index(index)?.search(query)
+    }
+
+    /// Index the values that were written before a full-text index was registered
+    ///
+    /// Values are indexed `chunk_size` at a time, each chunk in its own transaction, so writes
+    /// to the tree can continue while the backfill runs. Progress is stored in the database, and
+    /// a backfill that is interrupted resumes where it left off the next time this is called.
+    /// The index can be searched once this returns.
+    ///
+    /// ```rust
+    /// use sled_extensions::{structured::text::{Query, SimpleTokenizer}, Config, DbExt, Error};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<String>("json-tree")?;
+    ///
+    /// tree.insert(b"1", "Written before the index".to_owned())?;
+    ///
+    /// let tree = tree.with_text_index("body", SimpleTokenizer, |body| body.clone())?;
+    ///
+    /// match tree.search("body", &Query::term("index")) {
+    ///     Err(Error::IndexNotReady(_)) => (),
+    ///     _ => unreachable!("Should not search an index that is being built"),
+    /// }
+    ///
+    /// tree.backfill_text_index("body", 100)?;
+    ///
+    /// assert!(tree.is_text_index_ready("body")?);
+    /// assert_eq!(tree.search("body", &Query::term("index"))?.len(), 1);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn backfill_text_index(&self, index: &str, chunk_size: usize) -> Result<()> {
+        let index = self.find_text_index(index)?;
+
+        while !index.backfill::<E>(&self.tree, chunk_size)? {}
+
+        Ok(())
+    }
+
+    /// Discard a full-text index and index every value in the tree again
+    ///
+    /// The index can't be searched until the rebuild is complete.
+    pub fn rebuild_text_index(&self, index: &str, chunk_size: usize) -> Result<()> {
+        self.find_text_index(index)?.reset()?;
+        self.backfill_text_index(index, chunk_size)
+    }
+
+    /// Returns `true` if the full-text index has been backfilled and can be searched
+    pub fn is_text_index_ready(&self, index: &str) -> Result<bool> {
+        self.find_text_index(index)?.is_ready()
+    }
+
+    fn find_text_index(&self, name: &str) -> Result<&Arc<TextIndex<V>>> {
+        self.texts
+            .iter()
+            .find(|text| text.name() == name)
+            .ok_or_else(|| Error::UnknownIndex(name.to_owned()))
+    }
+
+    /// Maintain a materialized view derived from the values in this tree
+    ///
+    /// The view is updated in the same transaction as every write to this tree, by folding the
+    /// contributions of old values out and those of new values in. Values already in the tree
+    /// are only reflected in the view once it is rebuilt. This must be called before the tree is
+    /// cloned.
+    ///
+    /// ```rust
+    /// use sled_extensions::{structured::view::MapReduce, Config, DbExt, IVec};
+    ///
+    /// struct TotalPerUser;
+    ///
+    /// impl MapReduce<(String, u64)> for TotalPerUser {
+    ///     type Mapped = u64;
+    ///     type Output = u64;
+    ///
+    ///     fn map(&self, _: &[u8], (user, amount): &(String, u64)) -> Vec<(IVec, u64)> {
+    ///         vec![(user.as_bytes().into(), *amount)]
+    ///     }
+    ///
+    ///     fn reduce(&self, total: Option<u64>, amount: u64) -> u64 {
+    ///         total.unwrap_or(0) + amount
+    ///     }
+    ///
+    ///     fn unreduce(&self, total: u64, amount: u64) -> Option<u64> {
+    ///         Some(total - amount).filter(|total| *total > 0)
+    ///     }
+    /// }
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let payments = db
+    ///     .open_json_tree::<(String, u64)>("payments")?
+    ///     .with_view("totals", TotalPerUser)?;
+    ///
+    /// payments.insert(b"1", ("alice".to_owned(), 10))?;
+    /// payments.insert(b"2", ("alice".to_owned(), 5))?;
+    /// payments.insert(b"3", ("bob".to_owned(), 7))?;
+    /// payments.insert(b"3", ("alice".to_owned(), 7))?;
+    ///
+    /// let totals = payments.view::<u64>("totals")?;
+    /// assert_eq!(totals.get(b"alice")?, Some(22));
+    /// assert_eq!(totals.get(b"bob")?, None);
+    ///
+    /// payments.rebuild_view("totals")?;
+    /// assert_eq!(totals.get(b"alice")?, Some(22));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_view<M>(mut self, name: &str, map_reduce: M) -> Result<Self>
+    where
+        M: MapReduce<V> + 'static,
+        E: Encoding<M::Output> + Send + Sync,
+        V: 'static,
+    {
+        let view = Arc::new(View::<V, M, E>::new(
+            &self.db, &self.tree, &self.name, name, map_reduce,
+        )?);
+
+        self.add_hook(view.clone())?;
+        self.views.push(view);
+        Ok(self)
+    }
+
+    /// Open the outputs of a materialized view as a tree
+    ///
+    /// The view is owned by this tree, so anything written to it directly is overwritten by the
+    /// next rebuild.
+    pub fn view<O>(&self, name: &str) -> Result<StructuredTree<O, E>>
+    where
+        E: Encoding<O>,
+    {
+        self.find_view(name)?;
+        StructuredTree::new(&self.db, &view_tree(&self.name, name))
+    }
+
+    /// Recompute a materialized view from every value in this tree
+    ///
+    /// The outputs are cleared and every value is folded back in, in chunks. Writes made to this
+    /// tree while the view is rebuilding are folded in too, but the outputs are incomplete until
+    /// the rebuild finishes. An interrupted rebuild resumes the next time the view is registered.
+    pub fn rebuild_view(&self, name: &str) -> Result<()> {
+        self.find_view(name)?.rebuild(&self.tree)
+    }
+
+    fn find_view(&self, name: &str) -> Result<&Arc<dyn Rebuild>> {
+        self.views
+            .iter()
+            .find(|view| view.name() == name)
+            .ok_or_else(|| Error::UnknownView(name.to_owned()))
+    }
+
+    /// Declare that values in this tree reference keys in another tree
+    ///
+    /// The extractor produces the key a value references, if any. Writes that reference a key
+    /// missing from the parent tree fail with `Error::MissingReference`, and removing a key from
+    /// the parent tree applies the `OnDelete` behavior to the values that reference it. Both are
+    /// enforced in the same transaction as the write.
+    ///
+    /// This must be called before either tree is cloned, and after every other index or hook has
+    /// been registered on this tree, since cascading writes run this tree's hooks as they were
+    /// when the reference was declared.
+    ///
+    /// References are only recorded as values are written, and existing values aren't
+    /// backfilled. Removing a key that is only referenced by values written before the reference
+    /// was declared is neither restricted nor applied to those values, so references should be
+    /// declared before anything is written to this tree.
+    ///
+    /// ```rust
+    /// use sled_extensions::{structured::OnDelete, Config, DbExt, Error};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let mut customers = db.open_json_tree::<String>("customers")?;
+    /// let orders = db.open_json_tree::<(String, usize)>("orders")?.with_reference(
+    ///     "customer",
+    ///     &mut customers,
+    ///     |(customer, _)| Some(customer.as_bytes().into()),
+    ///     OnDelete::Cascade,
+    /// )?;
+    ///
+    /// customers.insert(b"alice", "Alice".to_owned())?;
+    /// orders.insert(b"1", ("alice".to_owned(), 3))?;
+    ///
+    /// match orders.insert(b"2", ("bob".to_owned(), 1)) {
+    ///     Err(Error::MissingReference { reference, key }) => {
+    ///         assert_eq!(reference, "customer");
+    ///         assert_eq!(key, b"bob");
+    ///     }
+    ///     _ => unreachable!("Should have rejected an order for a missing customer"),
+    /// }
+    ///
+    /// customers.remove(b"alice")?;
+    /// assert!(orders.is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_reference<P, PE, F>(
+        mut self,
+        name: &str,
+        parent: &mut StructuredTree<P, PE>,
+        extractor: F,
+        on_delete: OnDelete<V>,
+    ) -> Result<Self>
+    where
+        F: Fn(&V) -> Option<IVec> + Send + Sync + 'static,
+        V: 'static,
+        E: Send + Sync,
+        PE: Encoding<P> + 'static,
+    {
+        let refs = self
+            .db
+            .open_tree(format!("{}-references-{}", self.name, name))?;
+
+        self.add_hook(Arc::new(Referencing::new(
+            name,
+            &parent.tree,
+            &refs,
+            extractor,
+        )))?;
+
+        parent.add_hook(Arc::new(Referenced::<V, E>::new(
+            name,
+            &refs,
+            &self.name,
+            self.hooks.clone(),
+            on_delete,
+        )))?;
+
+        Ok(self)
+    }
+
+    /// Open the changelog for this tree
+    ///
+    /// Changes are only recorded if the tree was opened `with_changelog`.
+    pub fn changelog(&self) -> Result<Changelog<V, E>> {
+        Changelog::new(&self.db, &self.name)
+    }
+
+    /// Register a hook that will run inside the transaction of every write to this tree
+    pub(crate) fn add_hook(&mut self, hook: Arc<dyn Hook<V>>) -> Result<()> {
+        self.hooks.add(hook)
+    }
+
+    /// Run an internal transaction over this tree and its companion trees
+    ///
+    /// Nothing is committed if the closure produces an error.
+    fn atomically<F, R>(&self, f: F) -> Result<R>
+    where
+        F: Fn(&StructuredTransactionalTree<V, E>) -> sled::ConflictableTransactionResult<Result<R>>,
+    {
+        atomically(self.hooks.trees(), |views| {
+            (f)(&StructuredTransactionalTree::new(
+                &self.name,
+                views,
+                &self.hooks,
+            ))
+        })
     }
 
     /// Perform a multi-key serializable transaction.
@@ -60,11 +1135,11 @@ where
     where
         F: Fn(StructuredTransactionalTree<V, E>) -> sled::ConflictableTransactionResult<Result<R>>,
     {
-        self.0.transaction(move |trans_tree| {
-            (f)(StructuredTransactionalTree(
-                trans_tree,
-                PhantomData,
-                PhantomData,
+        transaction(self.hooks.trees(), move |views| {
+            (f)(StructuredTransactionalTree::new(
+                &self.name,
+                views,
+                &self.hooks,
             ))
         })
     }
@@ -73,7 +1148,23 @@ where
     ///
     /// It is possible to apply a Batch in a transaction as well, which is the way you can apply a Batch to multiple Trees atomically.
     pub fn apply_batch(&self, batch: StructuredBatch<V, E>) -> Result<()> {
-        Ok(self.0.apply_batch(batch.0)?)
+        if self.hooks.is_empty() {
+            return Ok(self.tree.apply_batch(batch.into_sled(&self.name)?)?);
+        }
+
+        let writes = batch.encode(&self.name)?;
+
+        self.atomically(|trans_tree| {
+            for (key, new) in &writes {
+                let new = new.as_ref().map(|(value, v)| (value, v.as_slice()));
+
+                if let Err(e) = trans_tree.write(key, new)? {
+                    return Ok(Err(e));
+                }
+            }
+
+            Ok(Ok(()))
+        })
     }
 
     /// Compare and swap. Capable of unique creation, conditional modification, or deletion. If
@@ -95,19 +1186,55 @@ where
     where
         K: AsRef<[u8]>,
     {
-        let ov = coerce(old.map(|value| E::encode(&value)))?;
-        let nv = coerce(new.map(|value| E::encode(&value)))?;
+        let key = key.as_ref();
+        let ov = coerce(old.map(|value| E::encode_at(&self.name, key, &value)))?;
+
+        if !self.hooks.is_empty() {
+            let nv = coerce(
+                new.as_ref()
+                    .map(|value| E::encode_at(&self.name, key, value)),
+            )?;
+
+            return self.atomically(|trans_tree| {
+                let current = trans_tree.tree.get(key)?;
 
-        match self.0.compare_and_swap(key, ov, nv)? {
+                if current.as_ref().map(|v| v.as_ref()) != ov.as_deref() {
+                    let current = match coerce(current.map(|v| E::decode_at(&self.name, key, &v))) {
+                        Ok(current) => current,
+                        Err(e) => return Ok(Err(e)),
+                    };
+                    let proposed =
+                        match coerce(nv.as_ref().map(|v| E::decode_at(&self.name, key, v))) {
+                            Ok(proposed) => proposed,
+                            Err(e) => return Ok(Err(e)),
+                        };
+
+                    return Ok(Ok(Err(CompareAndSwapError { current, proposed })));
+                }
+
+                let new = new
+                    .as_ref()
+                    .and_then(|value| Some((value, nv.as_ref()?.as_slice())));
+
+                match trans_tree.write(key, new)? {
+                    Ok(_) => Ok(Ok(Ok(()))),
+                    Err(e) => Ok(Err(e)),
+                }
+            });
+        }
+
+        let nv = coerce(new.map(|value| E::encode_at(&self.name, key, &value)))?;
+
+        match self.tree.compare_and_swap(key, ov, nv)? {
             Ok(()) => Ok(Ok(())),
             Err(sled::CompareAndSwapError { current, proposed }) => {
                 let current = if let Some(current) = current {
-                    Some(E::decode(&current)?)
+                    Some(E::decode_at(&self.name, key, &current)?)
                 } else {
                     None
                 };
                 let proposed = if let Some(proposed) = proposed {
-                    Some(E::decode(&proposed)?)
+                    Some(E::decode_at(&self.name, key, &proposed)?)
                 } else {
                     None
                 };
@@ -122,27 +1249,107 @@ where
     where
         K: AsRef<[u8]>,
     {
-        let opt = self.0.get(key)?;
+        let opt = self.tree.get(key.as_ref())?;
 
         if let Some(v) = opt {
-            Ok(Some(E::decode(&v)?))
+            Ok(Some(E::decode_at(&self.name, key.as_ref(), &v)?))
         } else {
             Ok(None)
         }
     }
 
+    /// Retrieve a value from the Tree if it exists, without decoding it.
+    ///
+    /// Views borrowing from the stored bytes can then be decoded from the returned reference.
+    ///
+    /// ```rust
+    /// use serde_derive::{Deserialize, Serialize};
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Post {
+    ///     title: String,
+    ///     body: String,
+    /// }
+    ///
+    /// #[derive(Deserialize)]
+    /// struct PostTitle<'a> {
+    ///     title: &'a str,
+    /// }
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<Post>("json-tree")?;
+    ///
+    /// tree.insert(b"1", Post { title: "Hello".to_owned(), body: "A long body".repeat(100) })?;
+    ///
+    /// let post = tree.get_ref(b"1")?.expect("The post exists");
+    /// let view: PostTitle = post.decode()?;
+    /// assert_eq!(view.title, "Hello");
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_ref<K>(&self, key: K) -> Result<Option<StructuredRef<E>>>
+    where
+        K: AsRef<[u8]>,
+    {
+        Ok(self.tree.get(key)?.map(StructuredRef::new))
+    }
+
+    /// Retrieve the values for many keys at once, in the order the keys were given.
+    ///
+    /// Every value is read before any is decoded.
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<usize>("json-tree")?;
+    ///
+    /// tree.insert(b"a", 1)?;
+    /// tree.insert(b"c", 3)?;
+    ///
+    /// let values = tree.get_many(&[b"a", b"b", b"c"])?;
+    /// assert_eq!(values, vec![Some(1), None, Some(3)]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn get_many<K, I>(&self, keys: I) -> Result<Vec<Option<V>>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let raw = keys
+            .into_iter()
+            .map(|key| Ok((self.tree.get(key.as_ref())?, key)))
+            .collect::<sled::Result<Vec<_>>>()?;
+
+        raw.into_iter()
+            .map(|(opt, key)| {
+                opt.map(|v| E::decode_at(&self.name, key.as_ref(), &v))
+                    .transpose()
+            })
+            .collect()
+    }
+
     /// Insert a key to a new value, returning the last value if it was set.
     pub fn insert<K>(&self, key: K, value: V) -> Result<Option<V>>
     where
         IVec: From<K>,
         K: AsRef<[u8]>,
     {
-        let v = E::encode(&value)?;
+        let v = E::encode_at(&self.name, key.as_ref(), &value)?;
 
-        let opt = self.0.insert::<K, Vec<u8>>(key, v)?;
+        if !self.hooks.is_empty() {
+            return self
+                .atomically(|trans_tree| trans_tree.write(key.as_ref(), Some((&value, &v))));
+        }
+
+        let opt = self.tree.insert::<&[u8], Vec<u8>>(key.as_ref(), v)?;
 
         if let Some(v) = opt {
-            Ok(Some(E::decode(&v)?))
+            Ok(Some(E::decode_at(&self.name, key.as_ref(), &v)?))
         } else {
             Ok(None)
         }
@@ -153,10 +1360,14 @@ where
     where
         K: AsRef<[u8]>,
     {
-        let opt = self.0.remove(key)?;
+        if !self.hooks.is_empty() {
+            return self.atomically(|trans_tree| trans_tree.write(key.as_ref(), None));
+        }
+
+        let opt = self.tree.remove(key.as_ref())?;
 
         if let Some(v) = opt {
-            Ok(Some(E::decode(&v)?))
+            Ok(Some(E::decode_at(&self.name, key.as_ref(), &v)?))
         } else {
             Ok(None)
         }
@@ -175,19 +1386,144 @@ where
     where
         K: AsRef<[u8]>,
     {
-        let opt = self.0.update_and_fetch(key, |opt| {
-            let o = opt.and_then(|v| E::decode(&v).ok());
+        if !self.hooks.is_empty() {
+            return self.atomically(|trans_tree| {
+                let old = match trans_tree.get(key.as_ref())? {
+                    Ok(old) => old,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                let new = (f)(old);
+
+                match trans_tree.put(key.as_ref(), new.as_ref())? {
+                    Ok(_) => Ok(Ok(new)),
+                    Err(e) => Ok(Err(e)),
+                }
+            });
+        }
+
+        let key = key.as_ref();
+        let opt = self.tree.update_and_fetch(key, |opt| {
+            let o = opt.and_then(|v| E::decode_at(&self.name, key, v).ok());
 
-            (f)(o).and_then(|value| E::encode(&value).ok())
+            (f)(o).and_then(|value| E::encode_at(&self.name, key, &value).ok())
         })?;
 
         if let Some(v) = opt {
-            Ok(Some(E::decode(&v)?))
+            Ok(Some(E::decode_at(&self.name, key, &v)?))
         } else {
             Ok(None)
         }
     }
 
+    /// Set a merge operator for use with the `merge` function.
+    ///
+    /// Operands are typed, and encoded with their own encoding. The operator is shared with every
+    /// clone of this tree, and replaces any operator that was set before.
+    ///
+    /// A stored value that can't be decoded, or a merged value that can't be encoded, is left
+    /// unchanged, and `merge` returns the error.
+    ///
+    /// ```rust
+    /// use sled_extensions::{json::JsonEncoding, Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<Vec<String>>("json-tree")?;
+    ///
+    /// tree.set_merge_operator::<JsonEncoding, _, _>(|_, list, item: String| {
+    ///     let mut list = list.unwrap_or_default();
+    ///     list.push(item);
+    ///     Some(list)
+    /// });
+    ///
+    /// tree.merge(b"list", "hello".to_owned())?;
+    /// let list = tree.merge(b"list", "world".to_owned())?;
+    /// assert_eq!(list, Some(vec!["hello".to_owned(), "world".to_owned()]));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_merge_operator<ME, M, F>(&self, f: F)
+    where
+        ME: Encoding<M> + 'static,
+        M: 'static,
+        F: Fn(&[u8], Option<V>, M) -> Option<V> + Send + Sync + 'static,
+        V: 'static,
+    {
+        let operator = Arc::new(MergeOperator::new::<E, M, ME, F>(&self.name, f));
+
+        self.tree.set_merge_operator(dispatch);
+        *self.merge.write().unwrap_or_else(|e| e.into_inner()) = Some(operator);
+    }
+
+    /// Merge an operand into a key's value using the configured merge operator, returning the
+    /// new value.
+    ///
+    /// Merges are applied by sled without a read-modify-write loop, unless the tree has indexes
+    /// or other companion trees, in which case they run in a transaction.
+    pub fn merge<K, M>(&self, key: K, operand: M) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        M: 'static,
+        V: 'static,
+    {
+        let operator = self
+            .merge
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .ok_or_else(|| {
+                Error::Sled(sled::Error::Unsupported(
+                    "must set a merge operator on this tree before calling merge".to_owned(),
+                ))
+            })?;
+
+        let typed = operator.typed::<M>().ok_or_else(|| {
+            Error::Sled(sled::Error::Unsupported(
+                "the merge operator on this tree takes a different type of operand".to_owned(),
+            ))
+        })?;
+
+        let encoded = (typed.encode)(&operand)?;
+
+        if self.hooks.is_empty() {
+            // sled applies the operand without being able to report errors, so make sure it can
+            // be decoded first
+            (typed.decode)(&encoded)?;
+        } else {
+            return self.atomically(|trans_tree| {
+                let old = match trans_tree.get(key.as_ref())? {
+                    Ok(old) => old,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                let operand = match (typed.decode)(&encoded) {
+                    Ok(operand) => operand,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                let new = (typed.merge)(key.as_ref(), old, operand);
+
+                match trans_tree.put(key.as_ref(), new.as_ref())? {
+                    Ok(_) => Ok(Ok(new)),
+                    Err(e) => Ok(Err(e)),
+                }
+            });
+        }
+
+        take_failure();
+        let merged = self.tree.merge(key.as_ref(), operator.operand(&encoded))?;
+
+        if let Some(e) = take_failure() {
+            return Err(e);
+        }
+
+        match merged {
+            Some(v) => Ok(Some(E::decode_at(&self.name, key.as_ref(), &v)?)),
+            None => Ok(None),
+        }
+    }
+
     /// Fetch the value, apply a function to it and return the previous value.
     ///
     /// ### Note
@@ -200,14 +1536,28 @@ where
     where
         K: AsRef<[u8]>,
     {
-        let opt = self.0.fetch_and_update(key, |opt| {
-            let o = opt.and_then(|v| E::decode(&v).ok());
+        if !self.hooks.is_empty() {
+            return self.atomically(|trans_tree| {
+                let old = match trans_tree.get(key.as_ref())? {
+                    Ok(old) => old,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                let new = (f)(old);
+
+                trans_tree.put(key.as_ref(), new.as_ref())
+            });
+        }
+
+        let key = key.as_ref();
+        let opt = self.tree.fetch_and_update(key, |opt| {
+            let o = opt.and_then(|v| E::decode_at(&self.name, key, v).ok());
 
-            (f)(o).and_then(|value| E::encode(&value).ok())
+            (f)(o).and_then(|value| E::encode_at(&self.name, key, &value).ok())
         })?;
 
         if let Some(v) = opt {
-            Ok(Some(E::decode(&v)?))
+            Ok(Some(E::decode_at(&self.name, key, &v)?))
         } else {
             Ok(None)
         }
@@ -220,28 +1570,54 @@ where
     /// buffer of 1024 items per `Subscriber`. This can be used to build reactive and replicated
     /// systems.
     pub fn watch_prefix(&self, prefix: Vec<u8>) -> sled::Subscriber {
-        self.0.watch_prefix(prefix)
+        self.tree.watch_prefix(prefix)
+    }
+
+    /// Subscribe to `Event`s that happen to keys that have the specified prefix, decoding the
+    /// inserted values.
+    ///
+    /// This has the same ordering and buffering semantics as `watch_prefix`.
+    pub fn subscribe(&self, prefix: Vec<u8>) -> StructuredSubscriber<V, E> {
+        StructuredSubscriber(
+            self.tree.watch_prefix(prefix),
+            self.name.clone(),
+            PhantomData,
+            PhantomData,
+        )
     }
 
     /// Synchronously flushes all dirty IO buffers and calls fsync. If this succeeds, it is guaranteed that all previous writes will be recovered if the system crashes. Returns the number of bytes flushed during this call.
     ///
     /// Flushing can take quite a lot of time, and you should measure the performance impact of using it on realistic sustained workloads running on realistic hardware.
     pub fn flush(&self) -> Result<()> {
-        self.0.flush()?;
+        self.tree.flush()?;
         Ok(())
     }
 
+    /// Asynchronously flushes all dirty IO buffers and calls fsync. If this succeeds, it is
+    /// guaranteed that all previous writes will be recovered if the system crashes.
+    ///
+    /// Flushing can take quite a lot of time, and you should measure the performance impact of using it on realistic sustained workloads running on realistic hardware.
+    pub fn flush_async(&self) -> impl Future<Output = Result<()>> {
+        let fut = self.tree.flush_async();
+
+        async move {
+            fut.await?;
+            Ok(())
+        }
+    }
+
     /// Returns `true` if the `Tree` contains a value for the specified key.
     pub fn contains_key<K>(&self, key: K) -> Result<bool>
     where
         K: AsRef<[u8]>,
     {
-        Ok(self.0.contains_key(key)?)
+        Ok(self.tree.contains_key(key)?)
     }
 
     /// Create a double-ended iterator over the tuples of keys and values in this tree.
     pub fn iter(&self) -> StructuredIter<V, E> {
-        StructuredIter::new(self.0.iter())
+        StructuredIter::new(self.tree.iter(), &self.name)
     }
 
     /// Create a double-ended iterator over tuples of keys and values, where the keys fall
@@ -251,7 +1627,94 @@ where
         K: AsRef<[u8]>,
         R: RangeBounds<K>,
     {
-        StructuredIter::new(self.0.range(range))
+        StructuredIter::new(self.tree.range(range), &self.name)
+    }
+
+    /// Estimate the keys that split this tree into `n` ranges holding roughly equal numbers of
+    /// values.
+    ///
+    /// Keys are sampled in a single pass that never decodes a value. Fewer points are returned
+    /// when the tree holds too few distinct keys.
+    pub fn split_points(&self, n: usize) -> Result<Vec<IVec>> {
+        split_points(&self.tree, n)
+    }
+
+    /// Split this tree into at most `n` ranges holding roughly equal numbers of values.
+    ///
+    /// The ranges cover every key, and can each be handed to `range` on a separate thread.
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<u64>("json-tree")?;
+    ///
+    /// for i in 0..1000u64 {
+    ///     tree.insert(&i.to_be_bytes()[..], i)?;
+    /// }
+    ///
+    /// let ranges = tree.split_ranges(4)?;
+    /// assert_eq!(ranges.len(), 4);
+    ///
+    /// let sum: u64 = std::thread::scope(|scope| {
+    ///     let workers: Vec<_> = ranges
+    ///         .into_iter()
+    ///         .map(|range| scope.spawn(|| tree.range(range).values().sum::<Result<u64, _>>()))
+    ///         .collect();
+    ///
+    ///     workers.into_iter().map(|worker| worker.join().unwrap()).sum::<Result<u64, _>>()
+    /// })?;
+    ///
+    /// assert_eq!(sum, 499_500);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn split_ranges(&self, n: usize) -> Result<Vec<KeyRange>> {
+        Ok(ranges(self.split_points(n)?))
+    }
+
+    #[cfg(feature = "rayon")]
+    /// Iterate over every key and value in this tree in parallel, decoding on each worker.
+    ///
+    /// The tree is split into a few ranges per thread in rayon's pool, so the order of the
+    /// results is unspecified.
+    ///
+    /// ```rust
+    /// use rayon::prelude::*;
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<u64>("json-tree")?;
+    ///
+    /// for i in 0..1000u64 {
+    ///     tree.insert(&i.to_be_bytes()[..], i)?;
+    /// }
+    ///
+    /// let sum = tree
+    ///     .par_iter()?
+    ///     .map(|res| res.map(|(_, v)| v))
+    ///     .sum::<Result<u64, _>>()?;
+    ///
+    /// assert_eq!(sum, 499_500);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn par_iter(
+        &self,
+    ) -> Result<impl rayon::iter::ParallelIterator<Item = Result<(IVec, V)>> + '_>
+    where
+        V: Send,
+        E: Sync,
+    {
+        use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+        let ranges = self.split_ranges(rayon::current_num_threads() * 4)?;
+
+        Ok(ranges
+            .into_par_iter()
+            .flat_map_iter(move |range| self.range(range)))
     }
 
     /// Retrieve the key and value before the provided key, if one exists.
@@ -259,9 +1722,9 @@ where
     where
         K: AsRef<[u8]>,
     {
-        match self.0.get_lt(key)? {
+        match self.tree.get_lt(key)? {
             Some((k, v)) => {
-                let value = E::decode(&v)?;
+                let value = E::decode_at(&self.name, &k, &v)?;
                 Ok(Some((k, value)))
             }
             None => Ok(None),
@@ -280,9 +1743,9 @@ where
     where
         K: AsRef<[u8]>,
     {
-        match self.0.get_gt(key)? {
+        match self.tree.get_gt(key)? {
             Some((k, v)) => {
-                let value = E::decode(&v)?;
+                let value = E::decode_at(&self.name, &k, &v)?;
                 Ok(Some((k, value)))
             }
             None => Ok(None),
@@ -295,14 +1758,18 @@ where
     where
         P: AsRef<[u8]>,
     {
-        StructuredIter::new(self.0.scan_prefix(prefix))
+        StructuredIter::new(self.tree.scan_prefix(prefix), &self.name)
     }
 
     /// Atomically removes the maximum item in the `Tree` instance.
     pub fn pop_max(&self) -> Result<Option<(IVec, V)>> {
-        match self.0.pop_max()? {
+        if !self.hooks.is_empty() {
+            return self.pop_with_hooks(|tree| tree.iter().next_back());
+        }
+
+        match self.tree.pop_max()? {
             Some((k, v)) => {
-                let value = E::decode(&v)?;
+                let value = E::decode_at(&self.name, &k, &v)?;
                 Ok(Some((k, value)))
             }
             None => Ok(None),
@@ -311,9 +1778,13 @@ where
 
     /// Atomically removes the minimum item in the `Tree` instance.
     pub fn pop_min(&self) -> Result<Option<(IVec, V)>> {
-        match self.0.pop_min()? {
+        if !self.hooks.is_empty() {
+            return self.pop_with_hooks(|tree| tree.iter().next());
+        }
+
+        match self.tree.pop_min()? {
             Some((k, v)) => {
-                let value = E::decode(&v)?;
+                let value = E::decode_at(&self.name, &k, &v)?;
                 Ok(Some((k, value)))
             }
             None => Ok(None),
@@ -324,24 +1795,50 @@ where
     ///
     /// Beware: performs a full O(n) scan under the hood.
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.tree.len()
     }
 
     /// Returns `true` if the `Tree` contains no elements.
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.tree.is_empty()
     }
 
     /// Clears the `Tree`, removing all values.
     ///
     /// Note that this is not atomic.
     pub fn clear(&self) -> Result<()> {
-        Ok(self.0.clear()?)
+        if !self.hooks.is_empty() {
+            for key in self.tree.iter().keys() {
+                self.remove(key?)?;
+            }
+
+            return Ok(());
+        }
+
+        Ok(self.tree.clear()?)
     }
 
     /// Returns the name of the tree.
     pub fn name(&self) -> String {
-        self.1.clone()
+        self.name.to_string()
+    }
+
+    fn pop_with_hooks<G>(&self, g: G) -> Result<Option<(IVec, V)>>
+    where
+        G: Fn(&sled::Tree) -> Option<sled::Result<(IVec, IVec)>>,
+    {
+        loop {
+            let key = match (g)(&self.tree) {
+                Some(res) => res?.0,
+                None => return Ok(None),
+            };
+
+            // The key may have been removed by another thread in the meantime, in which case
+            // we look for the next candidate
+            if let Some(value) = self.atomically(|trans_tree| trans_tree.write(&key, None))? {
+                return Ok(Some((key, value)));
+            }
+        }
     }
 }
 
@@ -349,19 +1846,130 @@ impl<V, E> StructuredIter<V, E>
 where
     E: Encoding<V> + 'static,
 {
-    fn new(iter: sled::Iter) -> Self {
-        StructuredIter(iter, PhantomData, PhantomData)
+    fn new(iter: sled::Iter, tree: &Arc<str>) -> Self {
+        StructuredIter(iter, tree.clone(), PhantomData, PhantomData)
     }
 
-    /// Iterate over the keys of this Tree
+    /// Iterate over the keys of this Tree, without decoding any values
     pub fn keys(self) -> impl DoubleEndedIterator<Item = Result<IVec>> {
-        self.map(|res| res.map(|(key, _)| key))
+        self.0.keys().map(|res| res.map_err(Error::from))
     }
 
     /// Iterate over the values of this Tree
     pub fn values(self) -> impl DoubleEndedIterator<Item = Result<V>> {
         self.map(|res| res.map(|(_, v)| v))
     }
+
+    /// Iterate over entries whose values are only decoded on demand
+    ///
+    /// ```rust
+    /// use sled_extensions::{Config, DbExt};
+    ///
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// let db = Config::default().temporary(true).open()?;
+    /// let tree = db.open_json_tree::<usize>("json-tree")?;
+    ///
+    /// tree.insert(b"a", 1)?;
+    /// tree.insert(b"b", 2)?;
+    /// tree.insert(b"c", 3)?;
+    ///
+    /// // Only the value stored under "b" is decoded
+    /// let values = tree
+    ///     .iter()
+    ///     .entries()
+    ///     .filter(|res| res.as_ref().map_or(true, |entry| entry.key() == b"b"))
+    ///     .map(|res| res?.value())
+    ///     .collect::<Result<Vec<_>, _>>()?;
+    ///
+    /// assert_eq!(values, vec![2]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn entries(self) -> impl DoubleEndedIterator<Item = Result<StructuredEntry<V, E>>> {
+        let tree = self.1;
+
+        self.0.map(move |res| {
+            res.map(|(key, value)| StructuredEntry::new(tree.clone(), key, value))
+                .map_err(Error::from)
+        })
+    }
+}
+
+impl<E> StructuredRef<E> {
+    fn new(value: IVec) -> Self {
+        StructuredRef(value, PhantomData)
+    }
+
+    /// Decode a view of the value that borrows from this reference
+    pub fn decode<'a, T>(&'a self) -> Result<T>
+    where
+        E: BorrowedEncoding<'a, T>,
+    {
+        E::decode_borrowed(&self.0)
+    }
+
+    /// The value, as it is stored
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Take the value, as it is stored
+    pub fn into_inner(self) -> IVec {
+        self.0
+    }
+}
+
+impl<V, E> StructuredEntry<V, E>
+where
+    E: Encoding<V>,
+{
+    fn new(tree: Arc<str>, key: IVec, value: IVec) -> Self {
+        StructuredEntry {
+            tree,
+            key,
+            value,
+            decoded: PhantomData,
+            encoding: PhantomData,
+        }
+    }
+
+    /// The key of this entry
+    pub fn key(&self) -> &IVec {
+        &self.key
+    }
+
+    /// The value of this entry, as it is stored
+    pub fn encoded(&self) -> &IVec {
+        &self.value
+    }
+
+    /// Decode the value of this entry
+    pub fn value(&self) -> Result<V> {
+        E::decode_at(&self.tree, &self.key, &self.value)
+    }
+
+    /// Take the key of this entry, discarding the value without decoding it
+    pub fn into_key(self) -> IVec {
+        self.key
+    }
+
+    /// Decode the value of this entry, returning it along with the key
+    pub fn into_pair(self) -> Result<(IVec, V)> {
+        let value = E::decode_at(&self.tree, &self.key, &self.value)?;
+        Ok((self.key, value))
+    }
+}
+
+impl<V, E> Clone for StructuredEntry<V, E> {
+    fn clone(&self) -> Self {
+        StructuredEntry {
+            tree: self.tree.clone(),
+            key: self.key.clone(),
+            value: self.value.clone(),
+            decoded: PhantomData,
+            encoding: PhantomData,
+        }
+    }
 }
 
 impl<V, E> StructuredBatch<V, E>
@@ -373,8 +1981,7 @@ where
     where
         IVec: From<K>,
     {
-        let v = E::encode(&value)?;
-        self.0.insert::<_, Vec<u8>>(key, v);
+        self.0.insert(IVec::from(key), Some(value));
         Ok(())
     }
 
@@ -383,7 +1990,37 @@ where
     where
         IVec: From<K>,
     {
-        self.0.remove(key)
+        self.0.insert(IVec::from(key), None);
+    }
+
+    /// Encode every value for the named tree
+    fn encode(self, tree: &str) -> Result<EncodedBatch<V>> {
+        self.0
+            .into_iter()
+            .map(|(key, opt)| match opt {
+                Some(value) => {
+                    let v = E::encode_at(tree, &key, &value)?;
+                    Ok((key, Some((value, v))))
+                }
+                None => Ok((key, None)),
+            })
+            .collect()
+    }
+
+    fn into_sled(self, tree: &str) -> Result<sled::Batch> {
+        let mut batch = sled::Batch::default();
+
+        for (key, opt) in self.0 {
+            match opt {
+                Some(value) => {
+                    let v = E::encode_at(tree, &key, &value)?;
+                    batch.insert(key, v)
+                }
+                None => batch.remove(key),
+            }
+        }
+
+        Ok(batch)
     }
 }
 
@@ -391,6 +2028,21 @@ impl<'a, V, E> StructuredTransactionalTree<'a, V, E>
 where
     E: Encoding<V>,
 {
+    /// View a transaction spanning every tree the hooks touch
+    pub(crate) fn new(
+        name: &'a str,
+        views: &'a [sled::TransactionalTree],
+        hooks: &'a Hooks<V>,
+    ) -> Self {
+        StructuredTransactionalTree {
+            name,
+            tree: &views[0],
+            views,
+            hooks,
+            encoding: PhantomData,
+        }
+    }
+
     /// Set a key to a new value
     pub fn insert<K>(
         &self,
@@ -401,21 +2053,12 @@ where
         IVec: From<K>,
         K: AsRef<[u8]>,
     {
-        let v = match E::encode(&value) {
+        let v = match E::encode_at(self.name, key.as_ref(), &value) {
             Ok(v) => v,
             Err(e) => return Ok(Err(e)),
         };
 
-        let opt = self.0.insert::<_, Vec<_>>(key, v)?;
-
-        if let Some(v) = opt {
-            match E::decode(&v) {
-                Ok(i) => return Ok(Ok(Some(i))),
-                Err(e) => return Ok(Err(e)),
-            }
-        } else {
-            Ok(Ok(None))
-        }
+        self.write(key.as_ref(), Some((&value, &v)))
     }
 
     /// Remove a key
@@ -424,16 +2067,7 @@ where
         IVec: From<K>,
         K: AsRef<[u8]>,
     {
-        let opt = self.0.remove(key)?;
-
-        if let Some(v) = opt {
-            match E::decode(&v) {
-                Ok(i) => return Ok(Ok(Some(i))),
-                Err(e) => return Ok(Err(e)),
-            }
-        } else {
-            Ok(Ok(None))
-        }
+        self.write(key.as_ref(), None)
     }
 
     /// Get the value associated with a key
@@ -441,12 +2075,12 @@ where
     where
         K: AsRef<[u8]>,
     {
-        let opt = self.0.get(key)?;
+        let opt = self.tree.get(key.as_ref())?;
 
         if let Some(v) = opt {
-            match E::decode(&v) {
-                Ok(i) => return Ok(Ok(Some(i))),
-                Err(e) => return Ok(Err(e)),
+            match E::decode_at(self.name, key.as_ref(), &v) {
+                Ok(i) => Ok(Ok(Some(i))),
+                Err(e) => Ok(Err(e)),
             }
         } else {
             Ok(Ok(None))
@@ -457,8 +2091,97 @@ where
     pub fn apply_batch(
         &self,
         batch: StructuredBatch<V, E>,
-    ) -> sled::ConflictableTransactionResult<()> {
-        Ok(self.0.apply_batch(batch.0)?)
+    ) -> sled::ConflictableTransactionResult<Result<()>> {
+        if self.hooks.is_empty() {
+            match batch.into_sled(self.name) {
+                Ok(batch) => self.tree.apply_batch(batch)?,
+                Err(e) => return Ok(Err(e)),
+            }
+
+            return Ok(Ok(()));
+        }
+
+        let writes = match batch.encode(self.name) {
+            Ok(writes) => writes,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        for (key, new) in &writes {
+            let new = new.as_ref().map(|(value, v)| (value, v.as_slice()));
+
+            if let Err(e) = self.write(key, new)? {
+                return Ok(Err(e));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// Set or remove a key depending on whether a value is provided
+    fn put(
+        &self,
+        key: &[u8],
+        value: Option<&V>,
+    ) -> sled::ConflictableTransactionResult<Result<Option<V>>> {
+        match value {
+            Some(value) => {
+                let v = match E::encode_at(self.name, key, value) {
+                    Ok(v) => v,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                self.write(key, Some((value, &v)))
+            }
+            None => self.write(key, None),
+        }
+    }
+
+    /// Write an encoded value, running any hooks registered on the tree
+    ///
+    /// Hooks are all given a chance to reject the write before anything is written.
+    pub(crate) fn write(
+        &self,
+        key: &[u8],
+        new: Option<(&V, &[u8])>,
+    ) -> sled::ConflictableTransactionResult<Result<Option<V>>> {
+        if self.hooks.is_empty() {
+            let opt = match new {
+                Some((_, v)) => self.tree.insert(key, v)?,
+                None => self.tree.remove(key)?,
+            };
+
+            return Ok(coerce(opt.map(|v| E::decode_at(self.name, key, &v))));
+        }
+
+        let old = match self.get(key)? {
+            Ok(old) => old,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let write = Write {
+            key,
+            old: old.as_ref(),
+            new,
+        };
+
+        for (hook, trees) in self.hooks.with_views(self.views) {
+            if let Err(e) = hook.check(&trees, &write)? {
+                return Ok(Err(e));
+            }
+        }
+
+        match new {
+            Some((_, v)) => self.tree.insert(key, v)?,
+            None => self.tree.remove(key)?,
+        };
+
+        for (hook, trees) in self.hooks.with_views(self.views) {
+            if let Err(e) = hook.apply(&trees, &write)? {
+                return Ok(Err(e));
+            }
+        }
+
+        Ok(Ok(old))
     }
 }
 
@@ -470,20 +2193,161 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.next()? {
-            Ok((key, v)) => Some(E::decode(&v).map(move |value| (key, value))),
+            Ok((key, v)) => Some(E::decode_at(&self.1, &key, &v).map(move |value| (key, value))),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    // Skipped values are never decoded
+    fn count(self) -> usize {
+        self.0.count()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        match self.0.nth(n)? {
+            Ok((key, v)) => Some(E::decode_at(&self.1, &key, &v).map(move |value| (key, value))),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+
+    fn last(self) -> Option<Self::Item> {
+        match self.0.last()? {
+            Ok((key, v)) => Some(E::decode_at(&self.1, &key, &v).map(move |value| (key, value))),
             Err(e) => Some(Err(e.into())),
         }
     }
 }
 
+impl<V, E> Iterator for StructuredSubscriber<V, E>
+where
+    E: Encoding<V>,
+{
+    type Item = Result<StructuredEvent<V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.next()? {
+            sled::Event::Insert(key, v) => Some(
+                E::decode_at(&self.1, &key, &v)
+                    .map(move |value| StructuredEvent::Insert(IVec::from(&*key), value)),
+            ),
+            sled::Event::Remove(key) => Some(Ok(StructuredEvent::Remove(IVec::from(&*key)))),
+        }
+    }
+}
+
 impl<V, E> DoubleEndedIterator for StructuredIter<V, E>
 where
     E: Encoding<V>,
 {
     fn next_back(&mut self) -> Option<<Self as Iterator>::Item> {
         match self.0.next_back()? {
-            Ok((key, v)) => Some(E::decode(&v).map(move |value| (key, value))),
+            Ok((key, v)) => Some(E::decode_at(&self.1, &key, &v).map(move |value| (key, value))),
             Err(e) => Some(Err(e.into())),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, Config};
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    /// Plain bytes, counting how often values are decoded on this thread
+    struct Counted;
+
+    thread_local! {
+        static DECODED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    impl Encoding<IVec> for Counted {
+        fn encode(t: &IVec) -> Result<Vec<u8>> {
+            Ok(t.to_vec())
+        }
+
+        fn decode(slice: &[u8]) -> Result<IVec> {
+            DECODED.with(|decoded| decoded.set(decoded.get() + 1));
+            Ok(IVec::from(slice))
+        }
+    }
+
+    fn decoded() -> usize {
+        DECODED.with(|decoded| decoded.replace(0))
+    }
+
+    fn open(db: &sled::Db) -> Tree {
+        let tree = Tree::new(db, "tree").unwrap();
+
+        for key in &[&b"a"[..], b"b", b"c"] {
+            tree.insert(*key, IVec::from(*key)).unwrap();
+        }
+
+        tree
+    }
+
+    #[test]
+    fn get_many_keeps_the_order_of_the_keys() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        assert_eq!(
+            tree.get_many([&b"c"[..], b"missing", b"a", b"c"]).unwrap(),
+            vec![
+                Some(IVec::from(b"c")),
+                None,
+                Some(IVec::from(b"a")),
+                Some(IVec::from(b"c")),
+            ]
+        );
+        assert!(tree.get_many(Vec::<&[u8]>::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn iterators_only_decode_values_they_yield() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = StructuredTree::<IVec, Counted>::new(&db, "counted").unwrap();
+
+        for i in 0..10u8 {
+            tree.insert(&[i][..], IVec::from(&[i][..])).unwrap();
+        }
+        decoded();
+
+        assert_eq!(tree.iter().keys().count(), 10);
+        assert_eq!(tree.iter().count(), 10);
+        assert_eq!(tree.iter().nth(5).unwrap().unwrap().1, IVec::from(&[5][..]));
+        assert_eq!(tree.iter().last().unwrap().unwrap().1, IVec::from(&[9][..]));
+        assert_eq!(decoded(), 2);
+
+        let odd: Vec<IVec> = tree
+            .iter()
+            .entries()
+            .filter(|res| res.as_ref().map_or(true, |entry| entry.key()[0] % 2 == 1))
+            .map(|res| res?.value())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(odd.len(), 5);
+        assert_eq!(decoded(), 5);
+
+        let entry = tree.iter().entries().next_back().unwrap().unwrap();
+        assert_eq!(entry.encoded(), &IVec::from(&[9][..]));
+        assert_eq!(entry.clone().into_key(), IVec::from(&[9][..]));
+        assert_eq!(decoded(), 0);
+        assert_eq!(entry.into_pair().unwrap().1, IVec::from(&[9][..]));
+        assert_eq!(decoded(), 1);
+    }
+
+    #[test]
+    fn get_ref_borrows_the_stored_value() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = open(&db);
+
+        let stored = tree.get_ref(b"b").unwrap().unwrap();
+        let view: &[u8] = stored.decode().unwrap();
+        assert_eq!(view, b"b");
+        assert_eq!(view.as_ptr(), stored.as_bytes().as_ptr());
+        assert_eq!(stored.into_inner(), IVec::from(b"b"));
+
+        assert!(tree.get_ref(b"missing").unwrap().is_none());
+    }
+}
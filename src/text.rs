@@ -0,0 +1,790 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    ops::{Bound, Not},
+};
+
+use crate::{
+    encoding::Encoding,
+    error::{Error, Result},
+    hook::{Hook, Write},
+    index::{escape, split, terminated},
+    progress::{Claimed, Deferral, Progress, State},
+    transaction::atomically,
+};
+
+const DOCUMENTS: &[u8] = b"documents";
+const TOKENS: &[u8] = b"tokens";
+
+// BM25 tuning parameters, using the usual defaults
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Splits text into normalized terms
+pub trait Tokenizer: Send + Sync {
+    /// Split text into terms, in the order they appear
+    ///
+    /// Both indexed values and queries are passed through the tokenizer, so any normalization
+    /// such as lowercasing should happen here.
+    fn tokenize(&self, text: &str) -> Vec<String>;
+}
+
+/// A tokenizer that splits on anything that isn't alphanumeric and lowercases each term
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SimpleTokenizer;
+
+/// A query against a full-text index
+#[derive(Clone, Debug, PartialEq)]
+pub enum TextQuery {
+    /// Values containing every term in the text
+    Term(String),
+
+    /// Values containing a term that starts with the text
+    Prefix(String),
+
+    /// Values containing the terms in the text, in order and next to each other
+    Phrase(String),
+
+    /// Values matching every query
+    And(Vec<TextQuery>),
+
+    /// Values matching any query
+    Or(Vec<TextQuery>),
+
+    /// Values not matching the query
+    Not(Box<TextQuery>),
+}
+
+/// A full-text index kept up to date with a structured tree
+///
+/// Postings are keyed by the escaped term followed by the primary key, and store the positions
+/// the term appears at. The length of each value and totals across the tree are kept alongside
+/// them for scoring.
+///
+/// Values already in the tree are backfilled in chunks, the same way counters are recounted. The
+/// progress of each backfill is kept in a metadata tree shared by every full-text index on the
+/// same tree, and while an index is being backfilled, writes are only applied to keys it has
+/// already indexed.
+pub(crate) struct TextIndex<V> {
+    data: String,
+    progress: Progress,
+    trees: [sled::Tree; 4],
+    tokenizer: Box<dyn Tokenizer>,
+    extractor: Box<dyn Fn(&V) -> String + Send + Sync>,
+}
+
+/// The totals BM25 scores are computed against
+struct Stats {
+    documents: f64,
+    average: f64,
+}
+
+type Scores = BTreeMap<IVec, f64>;
+
+impl Tokenizer for SimpleTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|term| !term.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+}
+
+impl<F> Tokenizer for F
+where
+    F: Fn(&str) -> Vec<String> + Send + Sync,
+{
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        (self)(text)
+    }
+}
+
+impl TextQuery {
+    /// Match values containing every term in the text
+    pub fn term<S: Into<String>>(text: S) -> Self {
+        TextQuery::Term(text.into())
+    }
+
+    /// Match values containing a term that starts with the text
+    pub fn prefix<S: Into<String>>(text: S) -> Self {
+        TextQuery::Prefix(text.into())
+    }
+
+    /// Match values containing the terms in the text, in order and next to each other
+    pub fn phrase<S: Into<String>>(text: S) -> Self {
+        TextQuery::Phrase(text.into())
+    }
+
+    /// Match values matching both this query and another
+    pub fn and(self, other: TextQuery) -> Self {
+        match self {
+            TextQuery::And(mut queries) => {
+                queries.push(other);
+                TextQuery::And(queries)
+            }
+            query => TextQuery::And(vec![query, other]),
+        }
+    }
+
+    /// Match values matching either this query or another
+    pub fn or(self, other: TextQuery) -> Self {
+        match self {
+            TextQuery::Or(mut queries) => {
+                queries.push(other);
+                TextQuery::Or(queries)
+            }
+            query => TextQuery::Or(vec![query, other]),
+        }
+    }
+}
+
+impl Not for TextQuery {
+    type Output = TextQuery;
+
+    fn not(self) -> Self::Output {
+        TextQuery::Not(Box::new(self))
+    }
+}
+
+impl<V> TextIndex<V> {
+    /// Open a full-text index over a tree
+    ///
+    /// An index without any recorded progress is cleared and has to be backfilled before it can
+    /// be searched.
+    pub(crate) fn new<T, F>(
+        db: &sled::Db,
+        tree: &str,
+        name: &str,
+        tokenizer: T,
+        extractor: F,
+    ) -> Result<Self>
+    where
+        T: Tokenizer + 'static,
+        F: Fn(&V) -> String + Send + Sync + 'static,
+    {
+        let index = TextIndex {
+            data: tree.to_owned(),
+            progress: Progress::new("full-text index", name, &[]),
+            trees: [
+                db.open_tree(format!("{}-text-{}", tree, name))?,
+                db.open_tree(format!("{}-text-{}-lengths", tree, name))?,
+                db.open_tree(format!("{}-text-{}-stats", tree, name))?,
+                db.open_tree(format!("{}-text-meta", tree))?,
+            ],
+            tokenizer: Box::new(tokenizer),
+            extractor: Box::new(extractor),
+        };
+
+        if index.progress.state(&index.trees[3])?.is_none() {
+            index.reset()?;
+        }
+
+        Ok(index)
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.progress.name()
+    }
+
+    /// Whether every value in the tree has been indexed
+    pub(crate) fn is_ready(&self) -> Result<bool> {
+        Ok(self.progress.state(&self.trees[3])? == Some(State::Ready))
+    }
+
+    /// Index the next chunk of values that were written before the index was registered
+    ///
+    /// Each chunk is indexed in a single transaction that also records how far the backfill has
+    /// progressed, so an interrupted backfill resumes where it left off. Returns `true` once
+    /// every value has been indexed and the index is marked ready.
+    pub(crate) fn backfill<E>(&self, data: &sled::Tree, chunk_size: usize) -> Result<bool>
+    where
+        E: Encoding<V>,
+    {
+        let claimed = match self.progress.claim(&self.trees, data, chunk_size)? {
+            Some(claimed) => claimed,
+            None => return Ok(true),
+        };
+
+        let committed = self.index_claimed::<E>(data, &claimed)?;
+        Ok(committed && claimed.upper == Bound::Unbounded)
+    }
+
+    /// Index the values of a claimed chunk, unless a write marked the claim dirty meanwhile
+    ///
+    /// Returns whether the chunk was indexed.
+    fn index_claimed<E>(&self, data: &sled::Tree, claimed: &Claimed) -> Result<bool>
+    where
+        E: Encoding<V>,
+    {
+        let values = data
+            .range::<IVec, _>((claimed.after.clone(), claimed.upper.clone()))
+            .map(|res| {
+                let (key, v) = res?;
+                let value = E::decode_at(&self.data, &key, &v)?;
+                Ok((key, self.tokenizer.tokenize(&(self.extractor)(&value))))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        atomically(&self.trees, |views| {
+            let (postings, lengths, stats, meta) = (&views[0], &views[1], &views[2], &views[3]);
+
+            if !self.progress.commit(meta, claimed)? {
+                return Ok(Ok(false));
+            }
+
+            let (mut documents, mut tokens) = match read_stats(stats)? {
+                Ok(totals) => totals,
+                Err(e) => return Ok(Err(e)),
+            };
+
+            for (key, terms) in &values {
+                add(postings, lengths, key, terms)?;
+                documents = documents.saturating_add(1);
+                tokens = tokens.saturating_add(terms.len() as u64);
+            }
+
+            write_stats(stats, documents, tokens)?;
+            Ok(Ok(true))
+        })
+    }
+
+    /// Discard every posting, length and total, and mark the index as needing a backfill
+    pub(crate) fn reset(&self) -> Result<()> {
+        // Writes stop updating the index before it is cleared
+        self.progress.restart(&self.trees)?;
+
+        for tree in &self.trees[..3] {
+            tree.clear()?;
+        }
+
+        Ok(())
+    }
+
+    /// Find the keys of values matching a query, best match first
+    pub(crate) fn search(&self, query: &TextQuery) -> Result<Vec<(IVec, f64)>> {
+        if !self.is_ready()? {
+            return Err(Error::IndexNotReady(self.name().to_owned()));
+        }
+
+        let documents = read_count(self.trees[2].get(DOCUMENTS)?.as_deref())?;
+        let tokens = read_count(self.trees[2].get(TOKENS)?.as_deref())?;
+
+        let stats = Stats {
+            documents: documents as f64,
+            average: if documents == 0 {
+                0.0
+            } else {
+                tokens as f64 / documents as f64
+            },
+        };
+
+        let mut results: Vec<_> = self.evaluate(query, &stats)?.into_iter().collect();
+
+        results.sort_by(|(a_key, a), (b_key, b)| {
+            b.partial_cmp(a)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a_key.cmp(b_key))
+        });
+
+        Ok(results)
+    }
+
+    fn evaluate(&self, query: &TextQuery, stats: &Stats) -> Result<Scores> {
+        match query {
+            TextQuery::Term(text) => {
+                let mut terms = self.tokenizer.tokenize(text);
+                terms.sort();
+                terms.dedup();
+
+                let scores = terms
+                    .iter()
+                    .map(|term| self.term(term, stats))
+                    .collect::<Result<Vec<_>>>()?;
+
+                Ok(intersect(scores))
+            }
+            TextQuery::Prefix(text) => {
+                let mut terms = self.tokenizer.tokenize(text);
+
+                let prefix = match terms.pop() {
+                    Some(prefix) => prefix,
+                    None => return Ok(Scores::new()),
+                };
+
+                let mut scores = terms
+                    .iter()
+                    .map(|term| self.term(term, stats))
+                    .collect::<Result<Vec<_>>>()?;
+
+                scores.push(self.prefix(&prefix, stats)?);
+
+                Ok(intersect(scores))
+            }
+            TextQuery::Phrase(text) => self.phrase(&self.tokenizer.tokenize(text), stats),
+            TextQuery::And(queries) => Ok(intersect(
+                queries
+                    .iter()
+                    .map(|query| self.evaluate(query, stats))
+                    .collect::<Result<Vec<_>>>()?,
+            )),
+            TextQuery::Or(queries) => {
+                let mut scores = Scores::new();
+
+                for query in queries {
+                    for (key, score) in self.evaluate(query, stats)? {
+                        *scores.entry(key).or_insert(0.0) += score;
+                    }
+                }
+
+                Ok(scores)
+            }
+            TextQuery::Not(query) => {
+                let excluded = self.evaluate(query, stats)?;
+                let mut scores = Scores::new();
+
+                for key in self.trees[1].iter().keys() {
+                    let key = key?;
+
+                    if !excluded.contains_key(&key) {
+                        scores.insert(key, 0.0);
+                    }
+                }
+
+                Ok(scores)
+            }
+        }
+    }
+
+    fn term(&self, term: &str, stats: &Stats) -> Result<Scores> {
+        let postings = self.postings(term)?;
+        self.score(&postings, stats)
+    }
+
+    fn prefix(&self, prefix: &str, stats: &Stats) -> Result<Scores> {
+        let mut escaped = Vec::new();
+        escape(prefix.as_bytes(), &mut escaped);
+
+        let mut terms: BTreeMap<Vec<u8>, Vec<(IVec, Vec<u32>)>> = BTreeMap::new();
+
+        for res in self.trees[0].scan_prefix(escaped) {
+            let (entry, v) = res?;
+            let (term, key) = split(&entry);
+
+            terms
+                .entry(term)
+                .or_default()
+                .push((IVec::from(key), read_positions(&v)?));
+        }
+
+        let mut scores = Scores::new();
+
+        for postings in terms.values() {
+            for (key, score) in self.score(postings, stats)? {
+                *scores.entry(key).or_insert(0.0) += score;
+            }
+        }
+
+        Ok(scores)
+    }
+
+    fn phrase(&self, terms: &[String], stats: &Stats) -> Result<Scores> {
+        let postings = terms
+            .iter()
+            .map(|term| {
+                let postings = self.postings(term)?;
+                let scores = self.score(&postings, stats)?;
+
+                Ok((postings.into_iter().collect::<BTreeMap<_, _>>(), scores))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (first, rest) = match postings.split_first() {
+            Some(split) => split,
+            None => return Ok(Scores::new()),
+        };
+
+        let mut scores = Scores::new();
+
+        for (key, positions) in &first.0 {
+            let matches = positions.iter().any(|&start| {
+                rest.iter().enumerate().all(|(i, (postings, _))| {
+                    postings.get(key).is_some_and(|positions| {
+                        positions.binary_search(&(start + i as u32 + 1)).is_ok()
+                    })
+                })
+            });
+
+            if matches {
+                let score = postings
+                    .iter()
+                    .filter_map(|(_, scores)| scores.get(key))
+                    .sum();
+
+                scores.insert(key.clone(), score);
+            }
+        }
+
+        Ok(scores)
+    }
+
+    /// The keys of the values containing a term, along with the positions it appears at
+    fn postings(&self, term: &str) -> Result<Vec<(IVec, Vec<u32>)>> {
+        let prefix = terminated(term.as_bytes());
+
+        self.trees[0]
+            .scan_prefix(&prefix)
+            .map(|res| {
+                let (entry, v) = res?;
+
+                Ok((IVec::from(&entry[prefix.len()..]), read_positions(&v)?))
+            })
+            .collect()
+    }
+
+    /// Compute the BM25 score of each value in the postings of a single term
+    fn score(&self, postings: &[(IVec, Vec<u32>)], stats: &Stats) -> Result<Scores> {
+        let frequency = postings.len() as f64;
+        let idf = ((stats.documents - frequency + 0.5) / (frequency + 0.5) + 1.0).ln();
+
+        postings
+            .iter()
+            .map(|(key, positions)| {
+                let length = read_count(self.trees[1].get(key)?.as_deref())? as f64;
+                let tf = positions.len() as f64;
+                let norm = if stats.average > 0.0 {
+                    length / stats.average
+                } else {
+                    1.0
+                };
+
+                let score = idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * norm));
+
+                Ok((key.clone(), score))
+            })
+            .collect()
+    }
+
+    fn terms(&self, value: Option<&V>) -> Option<Vec<String>> {
+        value.map(|value| self.tokenizer.tokenize(&(self.extractor)(value)))
+    }
+}
+
+impl<V> Hook<V> for TextIndex<V> {
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        let (postings, lengths, stats, meta) = (&trees[0], &trees[1], &trees[2], &trees[3]);
+
+        let state = match self.progress.state_in(meta)? {
+            Ok(state) => state,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        match state.map(|state| state.defers(write.key)) {
+            Some(Deferral::Apply) | None => (),
+            Some(Deferral::Defer) => return Ok(Ok(())),
+            Some(Deferral::Dirty(state)) => {
+                self.progress.store(meta, &state)?;
+                return Ok(Ok(()));
+            }
+        }
+
+        let old = self.terms(write.old);
+        let new = self.terms(write.new.map(|(new, _)| new));
+
+        if old == new {
+            return Ok(Ok(()));
+        }
+
+        let (mut documents, mut tokens) = match read_stats(stats)? {
+            Ok(totals) => totals,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        if let Some(old) = old {
+            for term in old.iter().collect::<BTreeSet<_>>() {
+                postings.remove(posting(term, write.key))?;
+            }
+
+            if let Some(length) = lengths.remove(write.key)? {
+                let length = match read_count(Some(&length)) {
+                    Ok(length) => length,
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                documents = documents.saturating_sub(1);
+                tokens = tokens.saturating_sub(length);
+            }
+        }
+
+        if let Some(new) = new {
+            add(postings, lengths, write.key, &new)?;
+            documents = documents.saturating_add(1);
+            tokens = tokens.saturating_add(new.len() as u64);
+        }
+
+        write_stats(stats, documents, tokens)?;
+        Ok(Ok(()))
+    }
+}
+
+/// Store the postings and length of a value's terms
+fn add(
+    postings: &TransactionalTree,
+    lengths: &TransactionalTree,
+    key: &[u8],
+    terms: &[String],
+) -> ConflictableTransactionResult<()> {
+    let mut positions: BTreeMap<&str, Vec<u8>> = BTreeMap::new();
+
+    for (position, term) in terms.iter().enumerate() {
+        positions
+            .entry(term)
+            .or_default()
+            .extend_from_slice(&(position as u32).to_be_bytes());
+    }
+
+    for (term, positions) in positions {
+        postings.insert(posting(term, key), positions)?;
+    }
+
+    lengths.insert(key, &(terms.len() as u64).to_be_bytes()[..])?;
+    Ok(())
+}
+
+/// The number of values indexed and the number of terms across them
+fn read_stats(stats: &TransactionalTree) -> ConflictableTransactionResult<Result<(u64, u64)>> {
+    let documents = read_count(stats.get(DOCUMENTS)?.as_deref());
+    let tokens = read_count(stats.get(TOKENS)?.as_deref());
+
+    Ok(documents.and_then(|documents| Ok((documents, tokens?))))
+}
+
+fn write_stats(
+    stats: &TransactionalTree,
+    documents: u64,
+    tokens: u64,
+) -> ConflictableTransactionResult<()> {
+    stats.insert(DOCUMENTS, &documents.to_be_bytes()[..])?;
+    stats.insert(TOKENS, &tokens.to_be_bytes()[..])?;
+    Ok(())
+}
+
+/// Keep only the keys present in every set of scores, summing their scores
+fn intersect(scores: Vec<Scores>) -> Scores {
+    let mut sets = scores.into_iter();
+
+    let mut out = match sets.next() {
+        Some(first) => first,
+        None => return Scores::new(),
+    };
+
+    for set in sets {
+        out = out
+            .into_iter()
+            .filter_map(|(key, score)| Some((key.clone(), score + set.get(&key)?)))
+            .collect();
+    }
+
+    out
+}
+
+fn posting(term: &str, key: &[u8]) -> Vec<u8> {
+    let mut out = terminated(term.as_bytes());
+    out.extend_from_slice(key);
+    out
+}
+
+fn read_count(v: Option<&[u8]>) -> Result<u64> {
+    let v = match v {
+        Some(v) => v,
+        None => return Ok(0),
+    };
+
+    let mut buf = [0; 8];
+
+    if v.len() != buf.len() {
+        return Err(Error::Corrupted("full-text index statistics".to_owned()));
+    }
+
+    buf.copy_from_slice(v);
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_positions(v: &[u8]) -> Result<Vec<u32>> {
+    let chunks = v.chunks_exact(4);
+
+    if !chunks.remainder().is_empty() {
+        return Err(Error::Corrupted("full-text index positions".to_owned()));
+    }
+
+    Ok(chunks
+        .map(|chunk| {
+            let mut buf = [0; 4];
+            buf.copy_from_slice(chunk);
+            u32::from_be_bytes(buf)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, structured_tree::StructuredTree, Config};
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    fn text(value: &IVec) -> String {
+        String::from_utf8_lossy(value).into_owned()
+    }
+
+    fn stats(db: &sled::Db) -> (u64, u64) {
+        let stats = db.open_tree("docs-text-body-stats").unwrap();
+
+        (
+            read_count(stats.get(DOCUMENTS).unwrap().as_deref()).unwrap(),
+            read_count(stats.get(TOKENS).unwrap().as_deref()).unwrap(),
+        )
+    }
+
+    fn keys(results: Vec<(IVec, f64)>) -> Vec<IVec> {
+        results.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn rarer_terms_and_shorter_values_rank_higher() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "docs")
+            .unwrap()
+            .with_text_index("body", SimpleTokenizer, text)
+            .unwrap();
+
+        tree.insert(b"long", IVec::from(b"fox and many other words here"))
+            .unwrap();
+        tree.insert(b"short", IVec::from(b"fox words")).unwrap();
+        tree.insert(b"twice", IVec::from(b"fox fox words here too"))
+            .unwrap();
+
+        assert_eq!(
+            keys(tree.search("body", &TextQuery::term("fox")).unwrap()),
+            vec![
+                IVec::from(b"twice"),
+                IVec::from(b"short"),
+                IVec::from(b"long")
+            ]
+        );
+
+        let results = tree
+            .search("body", &TextQuery::term("fox").or(TextQuery::term("many")))
+            .unwrap();
+        assert_eq!(results[0].0, IVec::from(b"long"));
+
+        assert_eq!(
+            keys(
+                tree.search(
+                    "body",
+                    &TextQuery::phrase("fox words").and(!TextQuery::prefix("he"))
+                )
+                .unwrap()
+            ),
+            vec![IVec::from(b"short")]
+        );
+    }
+
+    #[test]
+    fn backfills_values_written_before_the_index() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "docs").unwrap();
+        tree.insert(b"a", IVec::from(b"one two three")).unwrap();
+        tree.insert(b"b", IVec::from(b"four five")).unwrap();
+        tree.insert(b"c", IVec::from(b"six")).unwrap();
+        tree.insert(b"d", IVec::from(b"two")).unwrap();
+
+        let data = db.open_tree("docs").unwrap();
+        let index: TextIndex<IVec> =
+            TextIndex::new(&db, "docs", "body", SimpleTokenizer, text).unwrap();
+        assert!(!index.backfill::<PlainEncoding>(&data, 2).unwrap());
+        assert_eq!(stats(&db), (2, 5));
+
+        // A new handle for the same index continues after the first chunk
+        let tree = tree.with_text_index("body", SimpleTokenizer, text).unwrap();
+        match tree.search("body", &TextQuery::term("two")) {
+            Err(Error::IndexNotReady(name)) => assert_eq!(name, "body"),
+            _ => panic!("Expected the index to be building"),
+        }
+
+        // Writes to values that were indexed apply straight away, later ones are left to the
+        // backfill
+        tree.insert(b"a", IVec::from(b"one two")).unwrap();
+        tree.remove(b"b").unwrap();
+        tree.insert(b"c", IVec::from(b"two six")).unwrap();
+        assert_eq!(stats(&db), (1, 2));
+
+        tree.backfill_text_index("body", 2).unwrap();
+        assert!(tree.is_text_index_ready("body").unwrap());
+        assert_eq!(stats(&db), (3, 5));
+        assert_eq!(
+            keys(tree.search("body", &TextQuery::term("two")).unwrap()),
+            vec![IVec::from(b"d"), IVec::from(b"a"), IVec::from(b"c")]
+        );
+        assert!(tree
+            .search("body", &TextQuery::term("four"))
+            .unwrap()
+            .is_empty());
+
+        tree.rebuild_text_index("body", 1).unwrap();
+        assert_eq!(stats(&db), (3, 5));
+
+        tree.remove(b"a").unwrap();
+        tree.remove(b"c").unwrap();
+        tree.remove(b"d").unwrap();
+        assert_eq!(stats(&db), (0, 0));
+        assert!(tree
+            .search("body", &TextQuery::term("two"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn writes_to_a_claimed_chunk_are_indexed_once() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "docs")
+            .unwrap()
+            .with_text_index("body", SimpleTokenizer, text)
+            .unwrap();
+        tree.insert(b"a", IVec::from(b"one two")).unwrap();
+
+        // Another handle starts rebuilding and claims every value, then a write lands before it
+        // commits
+        let data = db.open_tree("docs").unwrap();
+        let index: TextIndex<IVec> =
+            TextIndex::new(&db, "docs", "body", SimpleTokenizer, text).unwrap();
+        index.reset().unwrap();
+        let claimed = index
+            .progress
+            .claim(&index.trees, &data, 10)
+            .unwrap()
+            .unwrap();
+
+        // The write dirtied the claim, so the chunk is indexed again with it
+        tree.insert(b"b", IVec::from(b"three")).unwrap();
+        assert!(!index
+            .index_claimed::<PlainEncoding>(&data, &claimed)
+            .unwrap());
+        assert_eq!(stats(&db), (0, 0));
+
+        tree.backfill_text_index("body", 10).unwrap();
+        assert_eq!(stats(&db), (2, 3));
+        assert_eq!(
+            keys(tree.search("body", &TextQuery::term("three")).unwrap()),
+            vec![IVec::from(b"b")]
+        );
+    }
+}
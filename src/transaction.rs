@@ -0,0 +1,83 @@
+use sled::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionResult, Transactional, TransactionalTree,
+};
+use std::cell::RefCell;
+
+use crate::error::{Error, Result};
+
+macro_rules! dispatch {
+    ($trees:ident, $f:ident, $($len:literal => ($($index:tt),+)),+) => {
+        match $trees.len() {
+            0 => Err(TransactionError::Storage(sled::Error::Unsupported(
+                "a transaction requires at least one tree".to_owned(),
+            ))),
+            1 => $trees[0].transaction(|view| ($f)(std::slice::from_ref(view))),
+            $(
+                $len => ($(&$trees[$index]),+).transaction(|views| {
+                    ($f)(&[$(views.$index.clone()),+])
+                }),
+            )+
+            _ => Err(TransactionError::Storage(sled::Error::Unsupported(format!(
+                "transactions may span at most {} trees",
+                MAX_TREES
+            )))),
+        }
+    };
+}
+
+/// The largest number of trees a single transaction may span
+pub(crate) const MAX_TREES: usize = 12;
+
+/// Run a transaction over a dynamic number of trees
+///
+/// Sled only implements `Transactional` for tuples of trees, so the slice is dispatched to the
+/// tuple of the matching size.
+pub(crate) fn transaction<A, F>(trees: &[sled::Tree], f: F) -> TransactionResult<A>
+where
+    F: Fn(&[TransactionalTree]) -> ConflictableTransactionResult<A>,
+{
+    dispatch!(
+        trees, f,
+        2 => (0, 1),
+        3 => (0, 1, 2),
+        4 => (0, 1, 2, 3),
+        5 => (0, 1, 2, 3, 4),
+        6 => (0, 1, 2, 3, 4, 5),
+        7 => (0, 1, 2, 3, 4, 5, 6),
+        8 => (0, 1, 2, 3, 4, 5, 6, 7),
+        9 => (0, 1, 2, 3, 4, 5, 6, 7, 8),
+        10 => (0, 1, 2, 3, 4, 5, 6, 7, 8, 9),
+        11 => (0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10),
+        12 => (0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11)
+    )
+}
+
+/// Run a transaction over a dynamic number of trees, aborting if the closure produces an error
+///
+/// Unlike the transactions exposed to users of this crate, nothing is committed when the inner
+/// result is an error.
+pub(crate) fn atomically<A, F>(trees: &[sled::Tree], f: F) -> Result<A>
+where
+    F: Fn(&[TransactionalTree]) -> ConflictableTransactionResult<Result<A>>,
+{
+    let error = RefCell::new(None);
+
+    let res = transaction(trees, |views| match (f)(views)? {
+        Ok(a) => Ok(a),
+        Err(e) => {
+            *error.borrow_mut() = Some(e);
+            Err(ConflictableTransactionError::Abort(()))
+        }
+    });
+
+    match res {
+        Ok(a) => Ok(a),
+        Err(TransactionError::Abort(())) => Err(error.into_inner().unwrap_or_else(|| {
+            Error::Sled(sled::Error::Unsupported(
+                "the transaction was aborted".to_owned(),
+            ))
+        })),
+        Err(TransactionError::Storage(e)) => Err(Error::Sled(e)),
+    }
+}
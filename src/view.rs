@@ -0,0 +1,402 @@
+use sled::{ConflictableTransactionResult, IVec, TransactionalTree};
+use std::marker::PhantomData;
+
+use crate::{
+    encoding::Encoding,
+    error::Result,
+    hook::{Hook, Write},
+    progress::{Claimed, Deferral, Progress, CHUNK},
+    transaction::atomically,
+};
+
+/// A map/reduce over the values in a structured tree, kept up to date in a materialized view
+///
+/// Each value is mapped to any number of contributions, keyed by where they land in the view.
+/// Contributions are folded into the output under their key as values are written, and folded
+/// back out as values are overwritten or removed, so `unreduce` must undo `reduce`.
+pub trait MapReduce<V>: Send + Sync {
+    /// What each value contributes to the view
+    type Mapped;
+
+    /// What is stored under each key of the view
+    type Output;
+
+    /// The contributions a value makes to the view, along with the view keys they land in
+    fn map(&self, key: &[u8], value: &V) -> Vec<(IVec, Self::Mapped)>;
+
+    /// Fold a contribution into the output stored under its view key, if any
+    fn reduce(&self, output: Option<Self::Output>, mapped: Self::Mapped) -> Self::Output;
+
+    /// Fold a contribution back out of the output stored under its view key
+    ///
+    /// Returning `None` removes the key from the view.
+    fn unreduce(&self, output: Self::Output, mapped: Self::Mapped) -> Option<Self::Output>;
+}
+
+/// A materialized view kept up to date with a structured tree
+///
+/// The outputs are stored in a companion tree, encoded the same way as the values in the source
+/// tree, and are updated in the same transaction as every write to the source tree.
+///
+/// A view is rebuilt in chunks, the same way counters are recounted. The progress of each rebuild
+/// is kept in a metadata tree shared by every view on the same tree, and while a view is
+/// rebuilding, writes are only applied to keys it has already folded in.
+pub(crate) struct View<V, M, E> {
+    source: String,
+    outputs: String,
+    progress: Progress,
+    trees: [sled::Tree; 2],
+    map_reduce: M,
+    value: PhantomData<fn(&V)>,
+    encoding: PhantomData<E>,
+}
+
+/// A view that can be rebuilt from the tree it is derived from
+pub(crate) trait Rebuild: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn rebuild(&self, data: &sled::Tree) -> Result<()>;
+}
+
+/// The name of the companion tree holding a view's outputs
+pub(crate) fn view_tree(tree: &str, name: &str) -> String {
+    format!("{}-view-{}", tree, name)
+}
+
+impl<V, M, E> View<V, M, E>
+where
+    M: MapReduce<V>,
+    E: Encoding<V> + Encoding<M::Output>,
+{
+    /// Open a view over the data tree
+    ///
+    /// An interrupted rebuild resumes where it left off the next time the view is registered.
+    pub(crate) fn new(
+        db: &sled::Db,
+        data: &sled::Tree,
+        tree: &str,
+        name: &str,
+        map_reduce: M,
+    ) -> Result<Self> {
+        let view = View {
+            source: tree.to_owned(),
+            outputs: view_tree(tree, name),
+            progress: Progress::new("view", name, &[]),
+            trees: [
+                db.open_tree(view_tree(tree, name))?,
+                db.open_tree(format!("{}-view-meta", tree))?,
+            ],
+            map_reduce,
+            value: PhantomData,
+            encoding: PhantomData,
+        };
+
+        view.resume(data)?;
+        Ok(view)
+    }
+
+    /// Fold in the rest of the values, if the view is rebuilding
+    fn resume(&self, data: &sled::Tree) -> Result<()> {
+        while let Some(claimed) = self.progress.claim(&self.trees, data, CHUNK)? {
+            self.fold_claimed(data, &claimed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fold the values of a claimed chunk into the view, unless a write marked the claim dirty
+    /// meanwhile
+    fn fold_claimed(&self, data: &sled::Tree, claimed: &Claimed) -> Result<()> {
+        let values = data
+            .range::<IVec, _>((claimed.after.clone(), claimed.upper.clone()))
+            .map(|res| {
+                let (key, v) = res?;
+                let value = <E as Encoding<V>>::decode_at(&self.source, &key, &v)?;
+                Ok((key, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        atomically(&self.trees, |views| {
+            if !self.progress.commit(&views[1], claimed)? {
+                return Ok(Ok(()));
+            }
+
+            for (key, value) in &values {
+                if let Err(e) = self.update(&views[0], key, value, true)? {
+                    return Ok(Err(e));
+                }
+            }
+
+            Ok(Ok(()))
+        })
+    }
+
+    /// Fold a value's contributions into or out of the view
+    fn update(
+        &self,
+        tree: &TransactionalTree,
+        key: &[u8],
+        value: &V,
+        added: bool,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        for (view_key, mapped) in self.map_reduce.map(key, value) {
+            let output = match tree.get(&view_key)? {
+                Some(v) => match E::decode_at(&self.outputs, &view_key, &v) {
+                    Ok(output) => Some(output),
+                    Err(e) => return Ok(Err(e)),
+                },
+                None => None,
+            };
+
+            let output = match (added, output) {
+                (true, output) => Some(self.map_reduce.reduce(output, mapped)),
+                (false, Some(output)) => self.map_reduce.unreduce(output, mapped),
+                (false, None) => None,
+            };
+
+            match output.map(|output| E::encode_at(&self.outputs, &view_key, &output)) {
+                Some(Ok(v)) => {
+                    tree.insert(view_key, v)?;
+                }
+                Some(Err(e)) => return Ok(Err(e)),
+                None => {
+                    tree.remove(view_key)?;
+                }
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+impl<V, M, E> Hook<V> for View<V, M, E>
+where
+    M: MapReduce<V>,
+    E: Encoding<V> + Encoding<M::Output> + Send + Sync,
+{
+    fn trees(&self) -> &[sled::Tree] {
+        &self.trees
+    }
+
+    fn apply(
+        &self,
+        trees: &[TransactionalTree],
+        write: &Write<V>,
+    ) -> ConflictableTransactionResult<Result<()>> {
+        let state = match self.progress.state_in(&trees[1])? {
+            Ok(state) => state,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        match state.map(|state| state.defers(write.key)) {
+            Some(Deferral::Apply) | None => (),
+            Some(Deferral::Defer) => return Ok(Ok(())),
+            Some(Deferral::Dirty(state)) => {
+                self.progress.store(&trees[1], &state)?;
+                return Ok(Ok(()));
+            }
+        }
+
+        if let Some(old) = write.old {
+            if let Err(e) = self.update(&trees[0], write.key, old, false)? {
+                return Ok(Err(e));
+            }
+        }
+
+        if let Some((new, _)) = write.new {
+            if let Err(e) = self.update(&trees[0], write.key, new, true)? {
+                return Ok(Err(e));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+impl<V, M, E> Rebuild for View<V, M, E>
+where
+    M: MapReduce<V>,
+    E: Encoding<V> + Encoding<M::Output> + Send + Sync,
+{
+    fn name(&self) -> &str {
+        self.progress.name()
+    }
+
+    /// Clear the view and fold every value back in, in chunks
+    fn rebuild(&self, data: &sled::Tree) -> Result<()> {
+        // Writes stop updating the outputs before they are cleared
+        self.progress.restart(&self.trees)?;
+        self.trees[0].clear()?;
+        self.resume(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encoding::PlainEncoding, error::Error, structured_tree::StructuredTree, Config};
+    use std::collections::BTreeMap;
+
+    type Tree = StructuredTree<IVec, PlainEncoding>;
+
+    /// Counts the values by their first byte
+    struct ByFirstByte;
+
+    impl MapReduce<IVec> for ByFirstByte {
+        type Mapped = u64;
+        type Output = IVec;
+
+        fn map(&self, _: &[u8], value: &IVec) -> Vec<(IVec, u64)> {
+            value
+                .first()
+                .map(|b| (IVec::from(&[*b][..]), 1))
+                .into_iter()
+                .collect()
+        }
+
+        fn reduce(&self, output: Option<IVec>, mapped: u64) -> IVec {
+            let count = output.map_or(0, |output| count(&output));
+            IVec::from(&(count + mapped).to_be_bytes()[..])
+        }
+
+        fn unreduce(&self, output: IVec, mapped: u64) -> Option<IVec> {
+            Some(count(&output) - mapped)
+                .filter(|count| *count > 0)
+                .map(|count| IVec::from(&count.to_be_bytes()[..]))
+        }
+    }
+
+    fn count(output: &[u8]) -> u64 {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(output);
+        u64::from_be_bytes(buf)
+    }
+
+    fn counts(tree: &Tree) -> Vec<(u8, u64)> {
+        tree.view::<IVec>("counts")
+            .unwrap()
+            .iter()
+            .map(|res| res.map(|(key, output)| (key[0], count(&output))))
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn outputs_follow_writes() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "source")
+            .unwrap()
+            .with_view("counts", ByFirstByte)
+            .unwrap();
+
+        tree.insert(b"1", IVec::from(b"apple")).unwrap();
+        tree.insert(b"2", IVec::from(b"avocado")).unwrap();
+        tree.insert(b"3", IVec::from(b"banana")).unwrap();
+        assert_eq!(counts(&tree), vec![(b'a', 2), (b'b', 1)]);
+
+        tree.insert(b"3", IVec::from(b"apricot")).unwrap();
+        tree.insert(b"4", IVec::from(b"")).unwrap();
+        assert_eq!(counts(&tree), vec![(b'a', 3)]);
+
+        tree.remove(b"1").unwrap();
+        tree.remove(b"2").unwrap();
+        tree.remove(b"3").unwrap();
+        assert!(counts(&tree).is_empty());
+    }
+
+    #[test]
+    fn rebuilds_replace_every_output() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "source").unwrap();
+        tree.insert(b"1", IVec::from(b"cherry")).unwrap();
+
+        let tree = tree.with_view("counts", ByFirstByte).unwrap();
+        tree.insert(b"2", IVec::from(b"cranberry")).unwrap();
+        tree.view::<IVec>("counts")
+            .unwrap()
+            .insert(b"z", IVec::from(&5u64.to_be_bytes()[..]))
+            .unwrap();
+        assert_eq!(counts(&tree), vec![(b'c', 1), (b'z', 5)]);
+
+        tree.rebuild_view("counts").unwrap();
+        assert_eq!(counts(&tree), vec![(b'c', 2)]);
+
+        match tree.rebuild_view("missing") {
+            Err(Error::UnknownView(name)) => assert_eq!(name, "missing"),
+            _ => panic!("Expected an unknown view"),
+        }
+    }
+
+    #[test]
+    fn writes_to_a_claimed_chunk_are_folded_in_once() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "source")
+            .unwrap()
+            .with_view("counts", ByFirstByte)
+            .unwrap();
+        tree.insert(b"1", IVec::from(b"apple")).unwrap();
+        tree.insert(b"2", IVec::from(b"banana")).unwrap();
+
+        // Another handle is rebuilding the view and has claimed every value
+        let view: View<IVec, _, PlainEncoding> =
+            View::new(&db, tree.sled_tree(), "source", "counts", ByFirstByte).unwrap();
+        view.progress.restart(&view.trees).unwrap();
+        view.trees[0].clear().unwrap();
+        let claimed = view
+            .progress
+            .claim(&view.trees, tree.sled_tree(), CHUNK)
+            .unwrap()
+            .unwrap();
+
+        tree.insert(b"3", IVec::from(b"avocado")).unwrap();
+        tree.remove(b"2").unwrap();
+        assert!(counts(&tree).is_empty());
+
+        // The write dirtied the claim, so the chunk is folded in again with it
+        view.fold_claimed(tree.sled_tree(), &claimed).unwrap();
+        assert!(counts(&tree).is_empty());
+
+        view.resume(tree.sled_tree()).unwrap();
+        assert_eq!(counts(&tree), vec![(b'a', 2)]);
+
+        tree.insert(b"4", IVec::from(b"blueberry")).unwrap();
+        assert_eq!(counts(&tree), vec![(b'a', 2), (b'b', 1)]);
+    }
+
+    #[test]
+    fn writes_during_a_rebuild_are_kept() {
+        let db = Config::default().temporary(true).open().unwrap();
+        let tree = Tree::new(&db, "source")
+            .unwrap()
+            .with_view("counts", ByFirstByte)
+            .unwrap();
+
+        for i in 0..3000u16 {
+            tree.insert(&i.to_be_bytes(), IVec::from(&[b'a' + (i % 3) as u8][..]))
+                .unwrap();
+        }
+
+        let writer = {
+            let tree = tree.clone();
+            std::thread::spawn(move || {
+                for i in 0..3000u16 {
+                    // Overwrites some values and adds others
+                    let key = (i * 7 % 6000).to_be_bytes();
+                    tree.insert(&key[..], IVec::from(&[b'd'][..])).unwrap();
+                }
+            })
+        };
+
+        tree.rebuild_view("counts").unwrap();
+        writer.join().unwrap();
+
+        let expected = tree
+            .aggregate::<&[u8], _>(..)
+            .fold(BTreeMap::new(), |mut counts, _, value| {
+                *counts.entry(value[0]).or_insert(0) += 1;
+                counts
+            })
+            .unwrap();
+        assert_eq!(counts(&tree), expected.into_iter().collect::<Vec<_>>());
+    }
+}